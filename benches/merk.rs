@@ -5,6 +5,7 @@ extern crate test;
 use std::thread;
 use test::Bencher;
 use merk::test_utils::*;
+use merk::SortedBatch;
 
 #[bench]
 fn get_1m_rocksdb(b: &mut Bencher) {
@@ -18,7 +19,7 @@ fn get_1m_rocksdb(b: &mut Bencher) {
     let mut batches = vec![];
     for i in 0..num_batches {
         let batch = make_batch_rand(batch_size, i);
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch.clone()).unwrap()).expect("apply failed");
         batches.push(batch);
     }
 
@@ -44,13 +45,13 @@ fn insert_1m_2k_seq_rocksdb_noprune(b: &mut Bencher) {
 
     for i in 0..(initial_size / batch_size) {
         let batch = make_batch_seq((i * batch_size)..((i+1) * batch_size));
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
     }
 
     let mut i = initial_size / batch_size;
     b.iter(|| {
         let batch = make_batch_seq((i * batch_size)..((i+1) * batch_size));
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
         i += 1;
     });
 }
@@ -65,13 +66,13 @@ fn insert_1m_2k_rand_rocksdb_noprune(b: &mut Bencher) {
 
     for i in 0..(initial_size / batch_size) {
         let batch = make_batch_rand(batch_size, i);
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
     }
 
     let mut i = initial_size / batch_size;
     b.iter(|| {
         let batch = make_batch_rand(batch_size, i);
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
         i += 1;
     });
 }
@@ -86,13 +87,13 @@ fn update_1m_2k_seq_rocksdb_noprune(b: &mut Bencher) {
 
     for i in 0..(initial_size / batch_size) {
         let batch = make_batch_seq((i * batch_size)..((i+1) * batch_size));
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
     }
 
     let mut i = 0;
     b.iter(|| {
         let batch = make_batch_seq((i * batch_size)..((i+1) * batch_size));
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
         i = (i + 1) % (initial_size / batch_size);
     });
 }
@@ -107,13 +108,13 @@ fn update_1m_2k_rand_rocksdb_noprune(b: &mut Bencher) {
 
     for i in 0..(initial_size / batch_size) {
         let batch = make_batch_rand(batch_size, i);
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
     }
 
     let mut i = 0;
     b.iter(|| {
         let batch = make_batch_rand(batch_size, i);
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
         i = (i + 1) % (initial_size / batch_size);
     });
 }
@@ -128,7 +129,7 @@ fn delete_1m_2k_rand_rocksdb_noprune(b: &mut Bencher) {
 
     for i in 0..(initial_size / batch_size) {
         let batch = make_batch_rand(batch_size, i);
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
     }
 
     let mut i = 0;
@@ -138,7 +139,7 @@ fn delete_1m_2k_rand_rocksdb_noprune(b: &mut Bencher) {
             return;
         }
         let batch = make_del_batch_rand(batch_size, i);
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
         i = (i + 1) % (initial_size / batch_size);
     });
 }
@@ -154,7 +155,7 @@ fn prove_1m_1_rand_rocksdb_noprune(b: &mut Bencher) {
 
     for i in 0..(initial_size / batch_size) {
         let batch = make_batch_rand(batch_size, i);
-        unsafe { merk.apply_unchecked(&batch).expect("apply failed") };
+        merk.apply_sorted(SortedBatch::new(batch).unwrap()).expect("apply failed");
     }
 
     let mut i = 0;
@@ -2,4 +2,16 @@ error_chain! {
     foreign_links {
         RocksDB(rocksdb::Error);
     }
+
+    errors {
+        Corruption(message: String) {
+            description("store is corrupted")
+            display("Store is corrupted: {}", message)
+        }
+
+        UnsupportedVersion(version: u32) {
+            description("store has an unsupported format version")
+            display("Store has unsupported format version {}", version)
+        }
+    }
 }
@@ -8,6 +8,8 @@ extern crate colored;
 extern crate byteorder;
 extern crate rand;
 extern crate jemallocator;
+#[cfg(feature = "async")]
+extern crate tokio;
 
 #[global_allocator] 
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
@@ -26,15 +28,47 @@ pub mod test_utils;
 // TODO: move this into its own crate
 pub mod owner;
 
-pub use error::{Error, Result};
-pub use self::merk::Merk;
+pub use error::{Error, ErrorKind, Result};
+pub use self::merk::{
+  Merk,
+  MerkOptions,
+  Compression,
+  SumFn,
+  CacheStats,
+  IntegrityReport,
+  Prefixed,
+  MultiMerk,
+  Transaction,
+  SharedMerk,
+  Snapshot
+};
+#[cfg(feature = "async")]
+pub use self::merk::AsyncMerk;
 pub use tree::{
+  Tree,
+  Link,
+  Fetch,
   Batch,
   BatchEntry,
   Op,
+  OpFailure,
+  MergeFn,
+  DuplicateKeys,
+  SortedBatch,
   PanicSource,
   Hash,
   HASH_LENGTH
 };
 pub use proofs::verify as verify_proof;
+pub use proofs::verify_sum as verify_sum_proof;
+pub use proofs::verify_len as verify_len_proof;
+pub use proofs::verify_rank as verify_rank_proof;
+pub use proofs::verify_index as verify_index_proof;
+pub use proofs::verify_page as verify_page_proof;
+pub use proofs::verify_query as verify_query_proof;
+pub use proofs::verify_prefixed_query as verify_prefixed_query_proof;
+pub use proofs::verify_multi as verify_multi_proof;
+pub use proofs::verify_diff as verify_diff_proof;
+pub use proofs::apply_to_proof;
+pub use proofs::{Change, Direction, Query, QueryItem};
 
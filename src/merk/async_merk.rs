@@ -0,0 +1,220 @@
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
+use crate::error::Result;
+use crate::proofs::Query;
+use crate::tree::{BatchEntry, OpFailure, Hash};
+use super::{Merk, SharedMerk};
+
+/// An asynchronous handle to a store, for use with the tokio runtime. Requires
+/// the `async` feature.
+///
+/// Operations are run on the blocking thread pool of a runtime (which may be
+/// a runtime dedicated to the store), so that blocking RocksDB calls do not
+/// stall the executor. At most `max_pending` operations run at once, and
+/// further calls wait for one of them to finish before starting. Reads and
+/// proofs are made as of the last commit while a batch is being applied (see
+/// `SharedMerk`).
+///
+/// # Example
+/// ```
+/// # let path = std::env::temp_dir().join("merk-async-doctest");
+/// use merk::{AsyncMerk, Merk, Op, verify_proof};
+///
+/// let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+/// runtime.block_on(async {
+///     let merk = Merk::open(&path).unwrap();
+///     let store = AsyncMerk::new(merk, tokio::runtime::Handle::current(), 16).unwrap();
+///
+///     store.apply(vec![(vec![1], Op::Put(vec![2]))]).await.unwrap();
+///     assert_eq!(store.get(vec![1]).await.unwrap(), vec![2]);
+///
+///     let (root_hash, proof) = store.prove(vec![vec![1]]).await.unwrap();
+///     verify_proof(&proof, &[vec![1]], root_hash).unwrap();
+///     # store.close().await.unwrap().destroy().unwrap();
+/// });
+/// ```
+#[derive(Clone)]
+pub struct AsyncMerk {
+    shared: Arc<SharedMerk>,
+    handle: Handle,
+    permits: Arc<Semaphore>,
+    max_pending: u32
+}
+
+impl AsyncMerk {
+    /// Creates an asynchronous handle to the store, which runs operations on
+    /// the blocking thread pool of the runtime with the given handle. At most
+    /// `max_pending` operations run at once.
+    pub fn new(merk: Merk, handle: Handle, max_pending: u32) -> Result<Self> {
+        if max_pending == 0 || max_pending as usize > Semaphore::MAX_PERMITS {
+            bail!("max_pending must be between 1 and {}", Semaphore::MAX_PERMITS);
+        }
+
+        Ok(AsyncMerk {
+            shared: Arc::new(SharedMerk::new(merk)),
+            handle,
+            permits: Arc::new(Semaphore::new(max_pending as usize)),
+            max_pending
+        })
+    }
+
+    /// Returns the root hash of the tree as of the last commit.
+    pub fn root_hash(&self) -> Hash {
+        self.shared.root_hash()
+    }
+
+    /// Gets a value for the given key as of the last commit. Returns an `Err`
+    /// if the key is not found or something else goes wrong.
+    pub async fn get(&self, key: Vec<u8>) -> Result<Vec<u8>> {
+        self.run(move |shared| shared.get(&key)).await
+    }
+
+    /// Creates a Merkle proof for the list of queried keys as of the last
+    /// commit (see `Merk::prove`). Returns the root hash of that commit, which
+    /// the proof can be verified against, along with the proof.
+    pub async fn prove(&self, keys: Vec<Vec<u8>>) -> Result<(Hash, Vec<u8>)> {
+        self.run(move |shared| {
+            let snapshot = shared.snapshot();
+            Ok((snapshot.root_hash(), snapshot.prove(&keys)?))
+        }).await
+    }
+
+    /// Creates a Merkle proof for a query made up of keys and ranges of keys
+    /// as of the last commit (see `Merk::prove_query`). Returns the root hash
+    /// of that commit, which the proof can be verified against, along with the
+    /// proof.
+    pub async fn prove_query(&self, query: Query) -> Result<(Hash, Vec<u8>)> {
+        self.run(move |shared| {
+            let snapshot = shared.snapshot();
+            Ok((snapshot.root_hash(), snapshot.prove_query(&query)?))
+        }).await
+    }
+
+    /// Applies a batch of operations (puts and deletes) to the tree, then
+    /// commits it (see `Merk::apply`). Batches are applied one at a time.
+    ///
+    /// Once the batch has started being applied, dropping the returned future
+    /// does not cancel it - the commit is still written in full, or not at all
+    /// if applying the batch fails. If the future is dropped while waiting for
+    /// other operations to finish, the batch is not applied. Either way, the
+    /// store is never left partially updated.
+    pub async fn apply(&self, batch: Vec<BatchEntry>) -> Result<Vec<(Vec<u8>, OpFailure)>> {
+        self.run(move |shared| shared.apply(&batch)).await
+    }
+
+    /// Waits for all pending operations to finish, then returns the store.
+    /// Fails if other clones of this handle still exist.
+    pub async fn close(self) -> Result<Merk> {
+        let permits = match self.permits.acquire_many(self.max_pending).await {
+            Err(_) => bail!("Store is closed"),
+            Ok(permits) => permits
+        };
+        drop(permits);
+
+        match Arc::try_unwrap(self.shared) {
+            Err(_) => bail!("Store is still shared by other handles"),
+            Ok(shared) => Ok(shared.into_inner())
+        }
+    }
+
+    /// Runs `f` on the blocking thread pool once fewer than `max_pending`
+    /// operations are running. The operation counts as running until `f`
+    /// returns, even if the returned future is dropped first.
+    async fn run<T, F>(&self, f: F) -> Result<T>
+        where
+            F: FnOnce(&SharedMerk) -> Result<T> + Send + 'static,
+            T: Send + 'static
+    {
+        let permit = match self.permits.clone().acquire_owned().await {
+            Err(_) => bail!("Store is closed"),
+            Ok(permit) => permit
+        };
+
+        let shared = self.shared.clone();
+        let task = self.handle.spawn_blocking(move || {
+            let result = f(&shared);
+            // release the store before the permit, so that `close` can take it
+            drop(shared);
+            drop(permit);
+            result
+        });
+
+        match task.await {
+            Err(err) => bail!("Store operation failed: {}", err),
+            Ok(result) => result
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use tokio::runtime::Builder;
+    use crate::test_utils::*;
+    use crate::{Op, verify_proof, verify_query_proof};
+    use super::*;
+
+    #[test]
+    fn async_merk() {
+        let path = thread::current().name().unwrap().to_owned();
+        let runtime = Builder::new_current_thread().build().expect("failed to build runtime");
+
+        runtime.block_on(async {
+            let merk = Merk::open(&path).expect("failed to open merk");
+            assert!(AsyncMerk::new(merk, Handle::current(), 0).is_err());
+
+            let merk = Merk::open(&path).expect("failed to open merk");
+            let store = AsyncMerk::new(merk, Handle::current(), 2).expect("failed to create handle");
+            store.apply(make_batch_seq(0..100)).await.expect("apply failed");
+            assert_eq!(store.get(seq_key(5)).await.unwrap(), vec![123; 60]);
+            assert!(store.get(seq_key(500)).await.is_err());
+
+            let keys = vec![seq_key(5), seq_key(500)];
+            let (root_hash, proof) = store.prove(keys.clone()).await.expect("prove failed");
+            assert_eq!(root_hash, store.root_hash());
+            let values = verify_proof(&proof, &keys, root_hash).expect("verify failed");
+            assert_eq!(values, vec![Some(vec![123; 60]), None]);
+
+            let mut query = Query::new();
+            query.insert_range(seq_key(10)..seq_key(20));
+            let (root_hash, proof) = store.prove_query(query.clone()).await.expect("prove failed");
+            assert_eq!(verify_query_proof(&proof, &query, root_hash).unwrap().len(), 10);
+
+            // more operations than permits, from several tasks at once
+            let tasks: Vec<_> = (0..8)
+                .map(|i| {
+                    let store = store.clone();
+                    tokio::spawn(async move {
+                        let key = seq_key(100 + i);
+                        store.apply(vec![(key.clone(), Op::Put(vec![1]))]).await?;
+                        let (root_hash, proof) = store.prove(vec![key.clone()]).await?;
+                        verify_proof(&proof, &[key], root_hash)
+                    })
+                })
+                .collect();
+            for task in tasks {
+                let values = task.await.unwrap().expect("task failed");
+                assert_eq!(values, vec![Some(vec![1])]);
+            }
+
+            // a cancelled apply is either written in full or not at all
+            let prev_hash = store.root_hash();
+            let task = {
+                let store = store.clone();
+                tokio::spawn(async move { store.apply(make_batch_seq(200..300)).await })
+            };
+            task.abort();
+            let _ = task.await;
+
+            let merk = store.close().await.expect("close failed");
+            let hash = merk.root_hash();
+            let applied = merk.get(&seq_key(200)).is_ok();
+            assert_eq!(applied, hash != prev_hash);
+            if applied {
+                assert!(merk.get(&seq_key(299)).is_ok());
+            }
+            merk.destroy().expect("failed to destroy merk");
+        });
+    }
+}
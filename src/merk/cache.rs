@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use crate::tree::Tree;
+
+/// The default number of decoded nodes to keep in a `Merk`'s node cache.
+pub const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+
+/// Counters describing how effective a `NodeCache` has been.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// The number of lookups which were served from the cache.
+    pub hits: u64,
+
+    /// The number of lookups which had to go to the backing store.
+    pub misses: u64
+}
+
+/// A bounded cache of decoded tree nodes, keyed by node key. When full, the
+/// least recently used node is evicted.
+///
+/// The cache is shared by reference with `MerkSource` (which must be `Send`),
+/// so its state is kept behind a `Mutex`.
+pub struct NodeCache {
+    inner: Mutex<Lru>
+}
+
+struct Lru {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<Vec<u8>, (Tree, u64)>,
+    order: BTreeMap<u64, Vec<u8>>,
+    stats: CacheStats
+}
+
+impl NodeCache {
+    /// Creates an empty cache which holds at most `capacity` nodes. A capacity
+    /// of 0 disables caching (lookups will still be counted as misses).
+    pub fn new(capacity: usize) -> Self {
+        NodeCache {
+            inner: Mutex::new(Lru {
+                capacity,
+                tick: 0,
+                entries: HashMap::with_capacity(capacity),
+                order: BTreeMap::new(),
+                stats: CacheStats::default()
+            })
+        }
+    }
+
+    /// Returns a copy of the node with the given key if it is in the cache,
+    /// marking it as the most recently used node.
+    pub fn get(&self, key: &[u8]) -> Option<Tree> {
+        let mut lru = self.inner.lock().unwrap();
+        let tick = lru.next_tick();
+
+        let (tree, prev_tick) = match lru.entries.get_mut(key) {
+            None => {
+                lru.stats.misses += 1;
+                return None;
+            },
+            Some((tree, last_used)) => {
+                let prev_tick = *last_used;
+                *last_used = tick;
+                (tree.clone(), prev_tick)
+            }
+        };
+
+        let key = lru.order.remove(&prev_tick)
+            .expect("cache entry missing from recency index");
+        lru.order.insert(tick, key);
+        lru.stats.hits += 1;
+        Some(tree)
+    }
+
+    /// Adds a decoded node to the cache, evicting the least recently used node
+    /// if the cache is full.
+    pub fn insert(&self, tree: Tree) {
+        let mut lru = self.inner.lock().unwrap();
+        if lru.capacity == 0 {
+            return;
+        }
+
+        let key = tree.key().to_vec();
+        lru.remove(&key);
+
+        if lru.entries.len() >= lru.capacity {
+            let oldest = lru.order.keys().next().cloned();
+            if let Some(tick) = oldest {
+                let evicted = lru.order.remove(&tick).unwrap();
+                lru.entries.remove(&evicted);
+            }
+        }
+
+        let tick = lru.next_tick();
+        lru.order.insert(tick, key.clone());
+        lru.entries.insert(key, (tree, tick));
+    }
+
+    /// Removes the node with the given key from the cache, if present. Called
+    /// when the node's record is overwritten or deleted in the backing store.
+    pub fn invalidate(&self, key: &[u8]) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    /// Returns the hit and miss counters accumulated since the cache was
+    /// created.
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().unwrap().stats
+    }
+}
+
+impl Lru {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((_, tick)) = self.entries.remove(key) {
+            self.order.remove(&tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn get_and_insert() {
+        let cache = NodeCache::new(2);
+        assert!(cache.get(&[1]).is_none());
+
+        cache.insert(Tree::new(vec![1], vec![101]));
+        let tree = cache.get(&[1]).expect("expected cached node");
+        assert_eq!(tree.key(), &[1]);
+        assert_eq!(tree.value(), &[101]);
+
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1 });
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = NodeCache::new(2);
+        cache.insert(Tree::new(vec![1], vec![101]));
+        cache.insert(Tree::new(vec![2], vec![102]));
+
+        // touch 1 so that 2 becomes the least recently used
+        cache.get(&[1]).unwrap();
+        cache.insert(Tree::new(vec![3], vec![103]));
+
+        assert!(cache.get(&[1]).is_some());
+        assert!(cache.get(&[2]).is_none());
+        assert!(cache.get(&[3]).is_some());
+    }
+
+    #[test]
+    fn reinsert_replaces() {
+        let cache = NodeCache::new(2);
+        cache.insert(Tree::new(vec![1], vec![101]));
+        cache.insert(Tree::new(vec![1], vec![111]));
+
+        assert_eq!(cache.get(&[1]).unwrap().value(), &[111]);
+        assert_eq!(cache.inner.lock().unwrap().entries.len(), 1);
+    }
+
+    #[test]
+    fn invalidate() {
+        let cache = NodeCache::new(2);
+        cache.insert(Tree::new(vec![1], vec![101]));
+        cache.invalidate(&[1]);
+
+        assert!(cache.get(&[1]).is_none());
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let cache = NodeCache::new(0);
+        cache.insert(Tree::new(vec![1], vec![101]));
+        assert!(cache.get(&[1]).is_none());
+    }
+}
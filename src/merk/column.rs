@@ -0,0 +1,143 @@
+use std::sync::Arc;
+use crate::error::Result;
+
+/// A handle to the column family of a RocksDB database which a `Merk` keeps
+/// its records in. A standalone store uses the default column family of its own
+/// database, while the subtrees of a `MultiMerk` share one database and each
+/// use their own column family.
+#[derive(Clone)]
+pub(crate) struct Column {
+    db: Arc<rocksdb::DB>,
+    name: Option<String>
+}
+
+impl Column {
+    /// Creates a handle to the default column family of the database.
+    pub(crate) fn default(db: Arc<rocksdb::DB>) -> Self {
+        Column { db, name: None }
+    }
+
+    /// Creates a handle to the named column family of the database, which must
+    /// have been opened along with it.
+    pub(crate) fn named(db: Arc<rocksdb::DB>, name: &str) -> Result<Self> {
+        if db.cf_handle(name).is_none() {
+            bail!("Column family {} was not opened", name);
+        }
+        Ok(Column { db, name: Some(name.to_string()) })
+    }
+
+    /// Returns the column family handle, or `None` for the default column
+    /// family.
+    fn cf(&self) -> Option<rocksdb::ColumnFamily> {
+        self.name.as_ref().map(|name| {
+            // checked when the handle was created
+            self.db.cf_handle(name).unwrap()
+        })
+    }
+
+    /// Gets the value for the given key in the column family.
+    pub(crate) fn get_pinned<K: AsRef<[u8]>>(
+        &self,
+        key: K
+    ) -> Result<Option<rocksdb::DBPinnableSlice>> {
+        Ok(match self.cf() {
+            None => self.db.get_pinned(key)?,
+            Some(cf) => self.db.get_pinned_cf(cf, key)?
+        })
+    }
+
+    /// Creates a snapshot of the database, through which the column family is
+    /// read as it was when the snapshot was created.
+    pub(crate) fn snapshot(&self) -> ColumnSnapshot<'_> {
+        ColumnSnapshot { column: self, snapshot: self.db.snapshot() }
+    }
+
+    /// Iterates over the entries in the column family.
+    pub(crate) fn iterator(&self, mode: rocksdb::IteratorMode) -> Result<rocksdb::DBIterator> {
+        Ok(match self.cf() {
+            None => self.db.iterator(mode),
+            Some(cf) => self.db.iterator_cf(cf, mode)?
+        })
+    }
+
+    /// Writes a value for the given key in the column family, outside of any
+    /// commit.
+    #[cfg(test)]
+    pub(crate) fn put<K, V>(&self, key: K, value: V) -> Result<()>
+        where K: AsRef<[u8]>, V: AsRef<[u8]>
+    {
+        match self.cf() {
+            None => self.db.put(key, value)?,
+            Some(cf) => self.db.put_cf(cf, key, value)?
+        };
+        Ok(())
+    }
+
+    /// Deletes the given key in the column family, outside of any commit.
+    #[cfg(test)]
+    pub(crate) fn delete<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        match self.cf() {
+            None => self.db.delete(key)?,
+            Some(cf) => self.db.delete_cf(cf, key)?
+        };
+        Ok(())
+    }
+
+    /// Adds a write of a value for the given key in the column family to the
+    /// batch.
+    pub(crate) fn batch_put<K, V>(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        key: K,
+        value: V
+    ) -> Result<()>
+        where K: AsRef<[u8]>, V: AsRef<[u8]>
+    {
+        match self.cf() {
+            None => batch.put(key, value)?,
+            Some(cf) => batch.put_cf(cf, key, value)?
+        };
+        Ok(())
+    }
+
+    /// Adds a deletion of the given key in the column family to the batch.
+    pub(crate) fn batch_delete<K: AsRef<[u8]>>(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        key: K
+    ) -> Result<()> {
+        match self.cf() {
+            None => batch.delete(key)?,
+            Some(cf) => batch.delete_cf(cf, key)?
+        };
+        Ok(())
+    }
+
+    /// Atomically writes the batch to the database. The batch may contain
+    /// writes to any column family of the database.
+    pub(crate) fn write(
+        &self,
+        batch: rocksdb::WriteBatch,
+        opts: &rocksdb::WriteOptions
+    ) -> Result<()> {
+        self.db.write_opt(batch, opts)?;
+        Ok(())
+    }
+}
+
+/// A consistent view of a column family as of when it was created, which is
+/// unaffected by later writes to the database.
+pub(crate) struct ColumnSnapshot<'a> {
+    column: &'a Column,
+    snapshot: rocksdb::Snapshot<'a>
+}
+
+impl<'a> ColumnSnapshot<'a> {
+    /// Gets the value for the given key in the column family.
+    pub(crate) fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<rocksdb::DBVector>> {
+        Ok(match self.column.cf() {
+            None => self.snapshot.get(key)?,
+            Some(cf) => self.snapshot.get_cf(cf, key)?
+        })
+    }
+}
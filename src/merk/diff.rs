@@ -0,0 +1,308 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use crate::error::Result;
+use crate::proofs::{Change, push_change, encode_partial_tree, encode_pair};
+use crate::tree::{Tree, Link, Hash};
+use super::Merk;
+
+/// A part of one version of a tree in key order, either a key/value pair or a
+/// subtree which has not been fetched yet.
+enum Item {
+    KV(Vec<u8>, Vec<u8>),
+    Subtree {
+        key: Vec<u8>,
+        hash: Hash,
+        height: u8
+    }
+}
+
+impl Item {
+    fn from_link(link: &Link) -> Self {
+        Item::Subtree {
+            key: link.key().to_vec(),
+            hash: *link.hash(),
+            height: link.height()
+        }
+    }
+}
+
+/// The remaining parts of one version of a tree, in key order, which are
+/// fetched as needed while walking the tree.
+struct Side<'a> {
+    merk: &'a Merk,
+    // the next item is at the end
+    stack: Vec<Item>,
+    // fetched nodes, if recording them for a proof
+    fetched: Option<BTreeMap<Vec<u8>, Tree>>
+}
+
+impl<'a> Side<'a> {
+    fn new(merk: &'a Merk, record: bool) -> Self {
+        let stack = match merk.tree() {
+            None => vec![],
+            Some(tree) => vec![Item::Subtree {
+                key: tree.key().to_vec(),
+                hash: tree.hash(),
+                height: tree.height()
+            }]
+        };
+        let fetched = if record { Some(BTreeMap::new()) } else { None };
+        Side { merk, stack, fetched }
+    }
+
+    fn peek(&self) -> Option<&Item> {
+        self.stack.last()
+    }
+
+    /// Replaces the next item, which must be a subtree, with its left subtree,
+    /// its root node's key/value pair and its right subtree.
+    fn expand(&mut self) -> Result<()> {
+        let key = match self.stack.pop() {
+            Some(Item::Subtree { key, .. }) => key,
+            _ => unreachable!("Expected subtree")
+        };
+        let tree = self.merk.source().fetch_key(&key)?;
+
+        if let Some(link) = tree.link(false) {
+            self.stack.push(Item::from_link(link));
+        }
+        self.stack.push(Item::KV(tree.key().to_vec(), tree.value().to_vec()));
+        if let Some(link) = tree.link(true) {
+            self.stack.push(Item::from_link(link));
+        }
+
+        if let Some(fetched) = &mut self.fetched {
+            fetched.insert(key, tree);
+        }
+        Ok(())
+    }
+
+    /// Encodes a proof of the fetched part of the tree.
+    fn to_proof(&self) -> Vec<u8> {
+        let fetched = self.fetched.as_ref().expect("Expected fetched nodes");
+        let root = self.merk.tree().map(|tree| (tree.key(), tree.hash()));
+        encode_partial_tree(root, fetched)
+    }
+}
+
+/// What to do with the next items of the two sides.
+enum Step {
+    Skip,
+    ExpandOld,
+    ExpandNew,
+    Removed,
+    Added,
+    Compare
+}
+
+/// Walks both versions of the tree in key order, fetching nodes as needed and
+/// skipping subtrees which are identical in both, and returns the changes.
+fn walk(old: &mut Side, new: &mut Side) -> Result<Vec<(Vec<u8>, Change)>> {
+    let mut changes = vec![];
+    loop {
+        let step = match (old.peek(), new.peek()) {
+            (None, None) => break,
+            (
+                Some(Item::Subtree { hash: old_hash, height: old_height, .. }),
+                Some(Item::Subtree { hash: new_hash, height: new_height, .. })
+            ) => {
+                if old_hash == new_hash {
+                    Step::Skip
+                } else if old_height >= new_height {
+                    Step::ExpandOld
+                } else {
+                    Step::ExpandNew
+                }
+            },
+            (Some(Item::Subtree { .. }), _) => Step::ExpandOld,
+            (_, Some(Item::Subtree { .. })) => Step::ExpandNew,
+            (Some(Item::KV(..)), None) => Step::Removed,
+            (None, Some(Item::KV(..))) => Step::Added,
+            (Some(Item::KV(old_key, _)), Some(Item::KV(new_key, _))) => {
+                match old_key.cmp(new_key) {
+                    Ordering::Less => Step::Removed,
+                    Ordering::Greater => Step::Added,
+                    Ordering::Equal => Step::Compare
+                }
+            }
+        };
+
+        match step {
+            Step::Skip => {
+                old.stack.pop();
+                new.stack.pop();
+            },
+            Step::ExpandOld => old.expand()?,
+            Step::ExpandNew => new.expand()?,
+            Step::Removed => {
+                if let Some(Item::KV(key, value)) = old.stack.pop() {
+                    push_change(&mut changes, &key, Some(&value), None);
+                }
+            },
+            Step::Added => {
+                if let Some(Item::KV(key, value)) = new.stack.pop() {
+                    push_change(&mut changes, &key, None, Some(&value));
+                }
+            },
+            Step::Compare => {
+                if let (Some(Item::KV(key, old_value)), Some(Item::KV(_, new_value)))
+                    = (old.stack.pop(), new.stack.pop())
+                {
+                    push_change(&mut changes, &key, Some(&old_value), Some(&new_value));
+                }
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// Returns the changes between two versions of a store, in key order.
+pub(super) fn diff(old: &Merk, new: &Merk) -> Result<Vec<(Vec<u8>, Change)>> {
+    walk(&mut Side::new(old, false), &mut Side::new(new, false))
+}
+
+/// Creates a proof of the changes between two versions of a store.
+pub(super) fn prove_diff(old: &Merk, new: &Merk) -> Result<Vec<u8>> {
+    let mut old = Side::new(old, true);
+    let mut new = Side::new(new, true);
+    walk(&mut old, &mut new)?;
+    Ok(encode_pair(&old.to_proof(), &new.to_proof()))
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use crate::test_utils::*;
+    use crate::{Op, MerkOptions, verify_diff_proof};
+    use super::super::is_metadata_key;
+    use super::*;
+
+    fn entries(merk: &Merk) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        merk.db.iterator(rocksdb::IteratorMode::Start)
+            .expect("iter failed")
+            .filter(|(key, _)| !is_metadata_key(key))
+            .map(|(key, value)| {
+                let node = Tree::decode(&key, &value).expect("decode failed");
+                (key.to_vec(), node.value().to_vec())
+            })
+            .collect()
+    }
+
+    fn expected_diff(old: &Merk, new: &Merk) -> Vec<(Vec<u8>, Change)> {
+        let (old, new) = (entries(old), entries(new));
+        let mut keys: Vec<_> = old.keys().chain(new.keys()).collect();
+        keys.sort();
+        keys.dedup();
+
+        let mut changes = vec![];
+        for key in keys {
+            let old_value = old.get(key).map(|value| value.as_slice());
+            let new_value = new.get(key).map(|value| value.as_slice());
+            push_change(&mut changes, key, old_value, new_value);
+        }
+        changes
+    }
+
+    #[test]
+    fn diff() {
+        let options = MerkOptions { counts: true, ..MerkOptions::default() };
+        let name = thread::current().name().unwrap().to_owned();
+        let mut old = Merk::open_with_options(format!("{}-old", name), options.clone())
+            .expect("failed to open merk");
+        let mut new = Merk::open_with_options(format!("{}-new", name), options)
+            .expect("failed to open merk");
+
+        // identical stores have no changes, and the proof only proves the roots
+        let base = make_batch_seq(0..1000);
+        old.apply(&base).expect("apply failed");
+        new.apply(&base).expect("apply failed");
+        assert_eq!(Merk::diff(&old, &new).unwrap(), vec![]);
+        let proof = Merk::prove_diff(&old, &new).expect("prove_diff failed");
+        assert!(proof.len() < 200);
+        assert_eq!(verify_diff_proof(&proof, old.root_hash(), new.root_hash()).unwrap(), vec![]);
+
+        new.apply(&[
+            (seq_key(5), Op::Put(vec![1])),
+            (seq_key(500), Op::Delete),
+            (seq_key(2000), Op::Put(vec![2]))
+        ]).expect("apply failed");
+        let changes = Merk::diff(&old, &new).expect("diff failed");
+        assert_eq!(changes, vec![
+            (seq_key(5), Change::Changed(vec![123; 60], vec![1])),
+            (seq_key(500), Change::Removed(vec![123; 60])),
+            (seq_key(2000), Change::Added(vec![2]))
+        ]);
+
+        let proof = Merk::prove_diff(&old, &new).expect("prove_diff failed");
+        let proven = verify_diff_proof(&proof, old.root_hash(), new.root_hash())
+            .expect("verify failed");
+        assert_eq!(proven, changes);
+
+        // the proof is checked against both root hashes
+        assert!(verify_diff_proof(&proof, new.root_hash(), old.root_hash()).is_err());
+        assert!(verify_diff_proof(&proof, old.root_hash(), old.root_hash()).is_err());
+
+        // larger changes, which also rebalance the trees differently
+        for i in 0..3 {
+            new.apply(&make_batch_rand(200, i)).expect("apply failed");
+            new.apply(&make_del_batch_seq(i * 100..i * 100 + 50)).expect("apply failed");
+            old.apply(&make_batch_seq(900 + i * 10..900 + i * 10 + 5)).expect("apply failed");
+
+            let changes = Merk::diff(&old, &new).expect("diff failed");
+            assert_eq!(changes, expected_diff(&old, &new));
+
+            let proof = Merk::prove_diff(&old, &new).expect("prove_diff failed");
+            let proven = verify_diff_proof(&proof, old.root_hash(), new.root_hash())
+                .expect("verify failed");
+            assert_eq!(proven, changes);
+
+            // the reverse diff swaps additions and removals
+            let reverse = Merk::diff(&new, &old).expect("diff failed");
+            assert_eq!(reverse, expected_diff(&new, &old));
+        }
+
+        old.destroy().expect("failed to destroy merk");
+        new.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn diff_empty() {
+        let empty = TempMerk::new().expect("failed to open merk");
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..10)).expect("apply failed");
+
+        let changes = Merk::diff(&empty, &merk).expect("diff failed");
+        assert_eq!(changes.len(), 10);
+        assert!(changes.iter().all(|(_, change)| *change == Change::Added(vec![123; 60])));
+
+        let proof = Merk::prove_diff(&merk, &empty).expect("prove_diff failed");
+        let proven = verify_diff_proof(&proof, merk.root_hash(), empty.root_hash())
+            .expect("verify failed");
+        assert_eq!(proven, expected_diff(&merk, &empty));
+
+        let proof = Merk::prove_diff(&empty, &empty).expect("prove_diff failed");
+        assert_eq!(verify_diff_proof(&proof, empty.root_hash(), empty.root_hash()).unwrap(), vec![]);
+        assert!(verify_diff_proof(&proof, merk.root_hash(), empty.root_hash()).is_err());
+    }
+
+    #[test]
+    fn diff_proof_missing_nodes() {
+        let mut old = TempMerk::new().expect("failed to open merk");
+        let mut new = TempMerk::new().expect("failed to open merk");
+        old.apply(&make_batch_seq(0..100)).expect("apply failed");
+        new.apply(&make_batch_seq(0..100)).expect("apply failed");
+        new.apply(&[
+            (seq_key(0), Op::Put(vec![1])),
+            (seq_key(99), Op::Put(vec![1]))
+        ]).expect("apply failed");
+
+        // proofs which hide differing subtrees are rejected, even if each side
+        // matches its root hash
+        let mut old_side = Side::new(&old, true);
+        let mut new_side = Side::new(&new, true);
+        old_side.expand().expect("expand failed");
+        new_side.expand().expect("expand failed");
+        let proof = encode_pair(&old_side.to_proof(), &new_side.to_proof());
+        assert!(verify_diff_proof(&proof, old.root_hash(), new.root_hash()).is_err());
+    }
+}
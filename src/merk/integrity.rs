@@ -0,0 +1,378 @@
+use std::cmp::max;
+use std::iter::Peekable;
+use crate::error::{ErrorKind, Result};
+use crate::tree::{Tree, Link, Hash, kv_hash, node_hash, aggregate_hash, NULL_HASH};
+use super::{ROOT_KEY_KEY, ROOT_HASH_KEY, MerkOptions, SumFn, is_metadata_key};
+use super::column::Column;
+
+/// The result of a full integrity check of a store, returned by
+/// `Merk::verify_integrity`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IntegrityReport {
+    /// The number of nodes reachable from the root.
+    pub node_count: usize,
+
+    /// Keys of node records in the store which are not reachable from the
+    /// root.
+    pub orphaned_keys: Vec<Vec<u8>>
+}
+
+/// The hash, aggregates and child heights of a subtree, as recomputed from the
+/// nodes in the store rather than taken from its parent's link.
+struct Subtree {
+    hash: Hash,
+    sum: Option<u64>,
+    count: Option<u64>,
+    child_heights: (u8, u8)
+}
+
+/// The aggregates a store maintains, which are recomputed and checked against
+/// the ones recorded in links.
+#[derive(Clone, Copy)]
+struct Aggregates {
+    sum_fn: Option<SumFn>,
+    counts: bool
+}
+
+impl Subtree {
+    fn height(&self) -> u8 {
+        1 + max(self.child_heights.0, self.child_heights.1)
+    }
+}
+
+/// Loads the root node of the store (returning `None` if the store is empty),
+/// checking that the root node and the nodes it links to exist and that their
+/// hashes agree with the root hash which was stored by the last commit.
+///
+/// This is cheap enough to run every time a store is opened, and catches the
+/// common symptoms of a commit which was lost or only partially written.
+pub(super) fn load_root(db: &Column) -> Result<Option<Tree>> {
+    let root_key = match db.get_pinned(ROOT_KEY_KEY)? {
+        Some(root_key) => root_key.to_vec(),
+        None => {
+            if db.get_pinned(ROOT_HASH_KEY)?.is_some() {
+                bail!(ErrorKind::Corruption(
+                    "Root hash is stored but root key is missing".to_string()
+                ));
+            }
+            return Ok(None);
+        }
+    };
+
+    let tree = read_node(db, &root_key)?;
+    check_kv_hash(&tree)?;
+
+    for left in [true, false].iter() {
+        if let Some(link) = tree.link(*left) {
+            let child = read_node(db, link.key())?;
+            check_kv_hash(&child)?;
+            check_link(link, &child)?;
+        }
+    }
+
+    // stores written before root hashes were recorded will not have one
+    if let Some(expected_hash) = db.get_pinned(ROOT_HASH_KEY)? {
+        if expected_hash[..] != tree.hash()[..] {
+            bail!(ErrorKind::Corruption(format!(
+                "Root hash does not match stored root hash\n\tExpected: {:?}\n\tActual: {:?}",
+                &expected_hash[..], tree.hash()
+            )));
+        }
+    }
+
+    Ok(Some(tree))
+}
+
+/// Reads and decodes the node with the given key, returning a `Corruption`
+/// error if it does not exist.
+pub(super) fn read_node(db: &Column, key: &[u8]) -> Result<Tree> {
+    match db.get_pinned(key)? {
+        Some(bytes) => Tree::decode(key, &bytes),
+        None => bail!(ErrorKind::Corruption(
+            format!("Node {:?} is missing", key)
+        ))
+    }
+}
+
+/// Checks that the node's stored `kv_hash` matches its key and value.
+fn check_kv_hash(tree: &Tree) -> Result<()> {
+    if kv_hash(tree.key(), tree.value()) != *tree.kv_hash() {
+        bail!(ErrorKind::Corruption(
+            format!("kv_hash of node {:?} does not match its key/value", tree.key())
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the hash and height recorded in `link` agree with the node it
+/// points to.
+fn check_link(link: &Link, child: &Tree) -> Result<()> {
+    if child.hash() != *link.hash() {
+        bail!(ErrorKind::Corruption(
+            format!("Hash of node {:?} does not match its parent's link", child.key())
+        ));
+    }
+    if child.height() != link.height() {
+        bail!(ErrorKind::Corruption(
+            format!("Height of node {:?} does not match its parent's link", child.key())
+        ));
+    }
+    Ok(())
+}
+
+/// Walks every node reachable from the root, reading each from the store and
+/// recomputing hashes bottom-up. Fails with a `Corruption` error if a node is
+/// missing, a hash or height recorded in a link does not match the node it
+/// points to, a node is unbalanced, or keys are out of order.
+///
+/// Since nodes are visited in key order, orphaned records are found by
+/// iterating through the store alongside the traversal.
+///
+/// The sums and counts which `options` enable are recomputed for each subtree
+/// and checked against the ones recorded in links.
+pub(super) fn verify_store(
+    db: &Column,
+    options: &MerkOptions
+) -> Result<IntegrityReport> {
+    let aggregates = Aggregates {
+        sum_fn: options.sum_fn,
+        counts: options.counts
+    };
+    let mut report = IntegrityReport::default();
+    let mut records = db.iterator(rocksdb::IteratorMode::Start)?.peekable();
+
+    let maybe_root_key = db.get_pinned(ROOT_KEY_KEY)?
+        .map(|root_key| root_key.to_vec());
+    if let Some(root_key) = maybe_root_key {
+        let root = verify_subtree(
+            db,
+            aggregates,
+            &root_key,
+            (None, None),
+            &mut records,
+            &mut report
+        )?;
+
+        if let Some(expected_hash) = db.get_pinned(ROOT_HASH_KEY)? {
+            if expected_hash[..] != root.hash[..] {
+                bail!(ErrorKind::Corruption(
+                    "Root hash does not match stored root hash".to_string()
+                ));
+            }
+        }
+    }
+
+    // any remaining records sort after the last node in the tree
+    for (key, _) in records {
+        if !is_metadata_key(&key) {
+            report.orphaned_keys.push(key.to_vec());
+        }
+    }
+
+    Ok(report)
+}
+
+/// Verifies the subtree rooted at the node with the given key, whose key must
+/// be within the exclusive `bounds` implied by its ancestors.
+fn verify_subtree<I>(
+    db: &Column,
+    aggregates: Aggregates,
+    key: &[u8],
+    bounds: (Option<&[u8]>, Option<&[u8]>),
+    records: &mut Peekable<I>,
+    report: &mut IntegrityReport
+) -> Result<Subtree>
+    where I: Iterator<Item=(Box<[u8]>, Box<[u8]>)>
+{
+    let tree = read_node(db, key)?;
+    check_kv_hash(&tree)?;
+
+    let out_of_order =
+        bounds.0.map_or(false, |lower| key <= lower)
+        || bounds.1.map_or(false, |upper| key >= upper);
+    if out_of_order {
+        bail!(ErrorKind::Corruption(
+            format!("Node {:?} is out of order", key)
+        ));
+    }
+
+    let left = verify_child(db, aggregates, &tree, true, (bounds.0, Some(key)), records, report)?;
+    visit(key, records, report);
+    let right = verify_child(db, aggregates, &tree, false, (Some(key), bounds.1), records, report)?;
+
+    let child_heights = (
+        left.as_ref().map_or(0, |child| child.height()),
+        right.as_ref().map_or(0, |child| child.height())
+    );
+    if (child_heights.1 as i8 - child_heights.0 as i8).abs() > 1 {
+        bail!(ErrorKind::Corruption(
+            format!("Node {:?} is unbalanced", key)
+        ));
+    }
+
+    let sum = match aggregates.sum_fn {
+        None => None,
+        Some(sum_fn) => {
+            let child_sum = |child: &Option<Subtree>| {
+                child.as_ref().map_or(0, |child| child.sum.unwrap_or(0))
+            };
+            let sum = sum_fn(tree.value())
+                .checked_add(child_sum(&left))
+                .and_then(|sum| sum.checked_add(child_sum(&right)));
+            match sum {
+                None => bail!(ErrorKind::Corruption(
+                    format!("Sum of node {:?} overflowed", key)
+                )),
+                sum => sum
+            }
+        }
+    };
+
+    let count = if aggregates.counts {
+        let child_count = |child: &Option<Subtree>| {
+            child.as_ref().map_or(0, |child| child.count.unwrap_or(0))
+        };
+        Some(1 + child_count(&left) + child_count(&right))
+    } else {
+        None
+    };
+
+    let hash = aggregate_hash(
+        node_hash(
+            tree.kv_hash(),
+            &left.map_or(NULL_HASH, |child| child.hash),
+            &right.map_or(NULL_HASH, |child| child.hash)
+        ),
+        count,
+        sum
+    );
+
+    Ok(Subtree { hash, sum, count, child_heights })
+}
+
+/// Verifies the child subtree on the given side (if any), and checks it against
+/// the parent's link.
+fn verify_child<I>(
+    db: &Column,
+    aggregates: Aggregates,
+    tree: &Tree,
+    left: bool,
+    bounds: (Option<&[u8]>, Option<&[u8]>),
+    records: &mut Peekable<I>,
+    report: &mut IntegrityReport
+) -> Result<Option<Subtree>>
+    where I: Iterator<Item=(Box<[u8]>, Box<[u8]>)>
+{
+    let link = match tree.link(left) {
+        None => return Ok(None),
+        Some(link) => link
+    };
+
+    let child = verify_subtree(db, aggregates, link.key(), bounds, records, report)?;
+    if child.hash != *link.hash() {
+        bail!(ErrorKind::Corruption(
+            format!("Hash of node {:?} does not match its parent's link", link.key())
+        ));
+    }
+    if child.child_heights != link.child_heights() {
+        bail!(ErrorKind::Corruption(
+            format!("Child heights of node {:?} do not match its parent's link", link.key())
+        ));
+    }
+    if child.sum != link.sum() {
+        bail!(ErrorKind::Corruption(
+            format!("Sum of node {:?} does not match its parent's link", link.key())
+        ));
+    }
+    if child.count != link.count() {
+        bail!(ErrorKind::Corruption(
+            format!("Count of node {:?} does not match its parent's link", link.key())
+        ));
+    }
+
+    Ok(Some(child))
+}
+
+/// Counts a node reached by the traversal, and advances the record iterator
+/// past its record, reporting any records before it as orphaned.
+fn visit<I>(key: &[u8], records: &mut Peekable<I>, report: &mut IntegrityReport)
+    where I: Iterator<Item=(Box<[u8]>, Box<[u8]>)>
+{
+    report.node_count += 1;
+
+    while let Some((record_key, _)) = records.peek() {
+        if &record_key[..] > key {
+            break;
+        }
+
+        let (record_key, _) = records.next().unwrap();
+        if &record_key[..] != key && !is_metadata_key(&record_key) {
+            report.orphaned_keys.push(record_key.to_vec());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use crate::test_utils::*;
+    use crate::tree::Tree;
+    use crate::ErrorKind;
+    use super::IntegrityReport;
+
+    #[test]
+    fn verify_valid_store() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+
+        let report = merk.verify_integrity().expect("verify failed");
+        assert_eq!(report, IntegrityReport::default());
+
+        merk.apply(&make_batch_seq(0..100)).expect("apply failed");
+        let report = merk.verify_integrity().expect("verify failed");
+        assert_eq!(report.node_count, 100);
+        assert!(report.orphaned_keys.is_empty());
+    }
+
+    #[test]
+    fn verify_orphans() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(1..100)).expect("apply failed");
+
+        let mut bytes = vec![];
+        Tree::new(seq_key(0), vec![1]).encode_into(&mut bytes);
+        merk.db.put(seq_key(0), &bytes).unwrap();
+        merk.db.put(seq_key(1000), &bytes).unwrap();
+
+        let report = merk.verify_integrity().expect("verify failed");
+        assert_eq!(report.node_count, 99);
+        assert_eq!(report.orphaned_keys, vec![ seq_key(0), seq_key(1000) ]);
+    }
+
+    #[test]
+    fn verify_tampered_value() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..100)).expect("apply failed");
+
+        // replace a node's value while keeping its old kv_hash and links
+        let node = super::read_node(&merk.db, &seq_key(50)).unwrap();
+        let tampered = Tree::from_fields(
+            seq_key(50),
+            vec![1, 2, 3],
+            *node.kv_hash(),
+            node.link(true).cloned(),
+            node.link(false).cloned()
+        );
+        let mut bytes = vec![];
+        tampered.encode_into(&mut bytes);
+        merk.db.put(seq_key(50), &bytes).unwrap();
+
+        let err = merk.verify_integrity().unwrap_err();
+        match err.kind() {
+            ErrorKind::Corruption(_) => {},
+            _ => panic!("expected corruption error, got {}", err)
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::error::{ErrorKind, Result};
+use crate::tree::{Tree, Link, Hash, AggregateCommit, kv_hash, legacy_kv_hash};
+use super::{ROOT_KEY_KEY, ROOT_HASH_KEY, FORMAT_VERSION_KEY, FORMAT_VERSION, MerkOptions};
+use super::column::Column;
+use super::integrity::read_node;
+use super::undo::UndoLog;
+
+/// Computes the `kv_hash` of a key/value pair in some format version.
+pub(super) type KVHashFn = fn(&[u8], &[u8]) -> Hash;
+
+/// Upgrades a store written before format versions were recorded (version 0),
+/// in which `kv_hash` hashed the key and value directly, to the current
+/// format.
+///
+/// Every node reachable from the root is checked against the hashes it was
+/// written with, then rewritten with the current hashes (and the sums and
+/// counts which `options` enable). The undo log is cleared, since it would
+/// restore nodes in the old format. Everything is written in a single batch,
+/// so the store is left unchanged if this fails.
+pub(super) fn migrate(
+    db: &Column,
+    undo: &UndoLog,
+    options: &MerkOptions
+) -> Result<()> {
+    let mut batch = rocksdb::WriteBatch::default();
+    rehash(db, &mut batch, legacy_kv_hash, kv_hash, options)?;
+    undo.truncate(&mut batch, 0)?;
+    db.batch_put(&mut batch, FORMAT_VERSION_KEY, FORMAT_VERSION.to_be_bytes())?;
+    db.write(batch, &options.to_write_opts())
+}
+
+/// Adds writes to `batch` which rewrite every node reachable from the root
+/// with its `kv_hash` computed by `to`, and the hashes in links and the stored
+/// root hash updated to match.
+///
+/// Fails with a `Corruption` error if a node is missing, if a node's `kv_hash`
+/// does not match the one computed by `from`, or if a hash recorded in a link
+/// or the stored root hash does not match the node it points to.
+pub(super) fn rehash(
+    db: &Column,
+    batch: &mut rocksdb::WriteBatch,
+    from: KVHashFn,
+    to: KVHashFn,
+    options: &MerkOptions
+) -> Result<()> {
+    let root_key = match db.get_pinned(ROOT_KEY_KEY)? {
+        None => return Ok(()),
+        Some(root_key) => root_key.to_vec()
+    };
+
+    let mut committer = AggregateCommit {
+        sum_fn: options.sum_fn,
+        counts: options.counts
+    };
+    let (prev_hash, root) = rehash_subtree(db, batch, &root_key, from, to, &mut committer)?;
+
+    // stores written before root hashes were recorded will not have one
+    if let Some(expected_hash) = db.get_pinned(ROOT_HASH_KEY)? {
+        if expected_hash[..] != prev_hash[..] {
+            bail!(ErrorKind::Corruption(
+                "Root hash does not match stored root hash".to_string()
+            ));
+        }
+    }
+    db.batch_put(batch, ROOT_HASH_KEY, root.hash())
+}
+
+/// Rewrites the subtree rooted at the node with the given key, returning the
+/// hash of its root node as it was written, and the rewritten root node.
+fn rehash_subtree(
+    db: &Column,
+    batch: &mut rocksdb::WriteBatch,
+    key: &[u8],
+    from: KVHashFn,
+    to: KVHashFn,
+    committer: &mut AggregateCommit
+) -> Result<(Hash, Tree)> {
+    let tree = read_node(db, key)?;
+    if from(tree.key(), tree.value()) != *tree.kv_hash() {
+        bail!(ErrorKind::Corruption(
+            format!("kv_hash of node {:?} does not match its key/value", key)
+        ));
+    }
+
+    let left = rehash_child(db, batch, &tree, true, from, to, committer)?;
+    let right = rehash_child(db, batch, &tree, false, from, to, committer)?;
+
+    let mut rewritten = Tree::from_fields(
+        key.to_vec(),
+        tree.value().to_vec(),
+        to(key, tree.value()),
+        left,
+        right
+    );
+    // recomputes the aggregates from the rewritten links
+    rewritten.commit(committer)?;
+
+    let mut bytes = vec![];
+    rewritten.encode_into(&mut bytes);
+    db.batch_put(batch, key, bytes)?;
+
+    Ok((tree.hash(), rewritten))
+}
+
+/// Rewrites the child subtree on the given side (if any), checking it against
+/// the parent's link, and returns a link to the rewritten child.
+fn rehash_child(
+    db: &Column,
+    batch: &mut rocksdb::WriteBatch,
+    tree: &Tree,
+    left: bool,
+    from: KVHashFn,
+    to: KVHashFn,
+    committer: &mut AggregateCommit
+) -> Result<Option<Link>> {
+    let link = match tree.link(left) {
+        None => return Ok(None),
+        Some(link) => link
+    };
+
+    let (prev_hash, child) = rehash_subtree(db, batch, link.key(), from, to, committer)?;
+    if prev_hash != *link.hash() {
+        bail!(ErrorKind::Corruption(
+            format!("Hash of node {:?} does not match its parent's link", link.key())
+        ));
+    }
+
+    Ok(Some(Link::Pruned {
+        hash: child.hash(),
+        sum: child.sum(),
+        count: child.count(),
+        child_heights: child.child_heights(),
+        key: link.key().to_vec()
+    }))
+}
@@ -1,6 +1,23 @@
+mod cache;
+mod options;
+mod integrity;
+mod migrate;
+mod prefixed;
+mod column;
+mod multi;
+mod transaction;
+mod undo;
+mod diff;
+mod shared;
+#[cfg(feature = "async")]
+mod async_merk;
+
+use std::collections::LinkedList;
+use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
-use crate::error::Result;
+use crate::error::{ErrorKind, Result};
 use crate::tree::{
     Tree,
     Link,
@@ -8,38 +25,146 @@ use crate::tree::{
     Walker,
     RefWalker,
     Commit,
+    AggregateCommit,
     Batch,
+    BatchEntry,
+    SortedBatch,
+    OpFailure,
+    DuplicateKeys,
+    check_sorted,
     Hash,
     NULL_HASH
 };
-use crate::proofs::encode_into;
+use crate::proofs::{encode_into, encode_witness, check_keys, Aggregate, Change, Direction, Query};
+use cache::NodeCache;
+use column::Column;
+use undo::{UndoLog, UNDO_CF};
+
+pub use cache::CacheStats;
+pub use options::{MerkOptions, Compression, SumFn};
+pub use integrity::IntegrityReport;
+pub use prefixed::Prefixed;
+pub use multi::MultiMerk;
+pub use transaction::Transaction;
+pub use shared::{SharedMerk, Snapshot};
+#[cfg(feature = "async")]
+pub use async_merk::AsyncMerk;
 
 // TODO: use a column family or something to keep the root key separate
 const ROOT_KEY_KEY: [u8; 12] = *b"\00\00root\00\00";
+const ROOT_HASH_KEY: [u8; 12] = *b"\00\00hash\00\00";
+const FORMAT_VERSION_KEY: [u8; 12] = *b"\00\00vers\00\00";
+
+/// The version of the format in which nodes are hashed and stored, which is
+/// recorded in each store. Stores written before versions were recorded are
+/// treated as version 0.
+const FORMAT_VERSION: u32 = 1;
 
 /// A handle to a Merkle key/value store backed by RocksDB.
 pub struct Merk {
     tree: Option<Tree>,
-    db: rocksdb::DB,
-    path: PathBuf
+    db: Column,
+    path: PathBuf,
+    cache: NodeCache,
+    options: MerkOptions,
+    undo: Option<UndoLog>
 }
 
 impl Merk {
     /// Opens a store with the specified file path. If no store exists at that
     /// path, one will be created.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Merk> {
-        let db_opts = default_db_opts();
-        let mut path_buf = PathBuf::new();
-        path_buf.push(path);
-        let db = rocksdb::DB::open(&db_opts, &path_buf)?;
-
-        // try to load root node
-        let tree = match db.get_pinned(ROOT_KEY_KEY)? {
-            Some(root_key) => Some(get_node(&db, &root_key)?),
-            None => None
-        };
+        Merk::open_with_options(path, MerkOptions::default())
+    }
+
+    /// Opens a store with the specified file path, tuning RocksDB and the
+    /// durability of commits with the given options. If no store exists at
+    /// that path, one will be created.
+    ///
+    /// Returns an `ErrorKind::Corruption` error if the root of the tree is
+    /// inconsistent, e.g. because the last commit was only partially written
+    /// before a crash (see `MerkOptions::durable` to prevent this), or an
+    /// `ErrorKind::UnsupportedVersion` error if the store was written in a
+    /// different format (see `docs/algorithms.md`). Stores written before
+    /// format versions were recorded can be upgraded with `Merk::migrate`.
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        options: MerkOptions
+    ) -> Result<Merk> {
+        options.check()?;
+
+        let (db, path_buf) = open_db(path, &options)?;
+        let undo = UndoLog::new(Column::named(db.clone(), UNDO_CF)?);
+        Merk::open_column(Column::default(db), path_buf, options, Some(undo))
+    }
+
+    /// Opens a store like `open_with_options`, first upgrading it to the
+    /// current format if it was written before format versions were recorded
+    /// (which `open_with_options` refuses with
+    /// `ErrorKind::UnsupportedVersion(0)`). Stores which are already in the
+    /// current format are opened unchanged.
+    ///
+    /// Every node is checked against the hashes it was written with, then
+    /// rewritten with the current hashes, so the root hash of the store
+    /// changes (see `docs/algorithms.md`). Sums and counts are added to the
+    /// nodes if `options` enables them. Records of deleted nodes which older
+    /// versions left behind are then removed (see `Merk::gc`).
+    ///
+    /// The nodes are rewritten in a single write, so the store is left
+    /// unchanged if the upgrade fails, but this needs memory proportional to
+    /// the size of the store.
+    pub fn migrate<P: AsRef<Path>>(path: P, options: MerkOptions) -> Result<Merk> {
+        options.check()?;
+
+        let (db, path_buf) = open_db(path, &options)?;
+        let undo = UndoLog::new(Column::named(db.clone(), UNDO_CF)?);
+        let db = Column::default(db);
+
+        let legacy = db.get_pinned(FORMAT_VERSION_KEY)?.is_none();
+        if legacy {
+            migrate::migrate(&db, &undo, &options)?;
+        }
+
+        let mut merk = Merk::open_column(db, path_buf, options, Some(undo))?;
+        if legacy {
+            merk.gc()?;
+        }
+        Ok(merk)
+    }
+
+    /// Loads a store from a column family of an open database, keeping its
+    /// undo log in `undo` if given.
+    fn open_column(
+        db: Column,
+        path: PathBuf,
+        options: MerkOptions,
+        undo: Option<UndoLog>
+    ) -> Result<Merk> {
+        check_format_version(&db, undo.as_ref(), &options)?;
+
+        // try to load root node, failing if it is inconsistent
+        let tree = integrity::load_root(&db)?;
+
+        if let Some(tree) = &tree {
+            if tree.sum().is_some() != options.sum_fn.is_some() {
+                bail!("Store must be opened with a sum function if and only if it was created with one");
+            }
+            if tree.count().is_some() != options.counts {
+                bail!("Store must be opened with counts enabled if and only if it was created with them");
+            }
+        }
 
-        Ok(Merk { tree, db, path: path_buf })
+        // drop undo entries beyond the configured depth, which may have
+        // been lowered since the store was last opened
+        if let Some(undo) = &undo {
+            let mut batch = rocksdb::WriteBatch::default();
+            undo.truncate(&mut batch, options.undo_depth)?;
+            db.write(batch, &options.to_write_opts())?;
+        }
+
+        let cache = NodeCache::new(options.node_cache_capacity);
+
+        Ok(Merk { tree, db, path, cache, options, undo })
     }
 
     /// Gets a value for the given key. Returns an `Err` if the key is not found
@@ -54,6 +179,45 @@ impl Merk {
         Ok(node.value().to_vec())
     }
 
+    /// Returns a view of the keys which start with `prefix`, through which
+    /// keys are read, written and proven relative to the prefix (see
+    /// `Prefixed`).
+    ///
+    /// # Example
+    /// ```
+    /// # let mut store = merk::test_utils::TempMerk::new().unwrap();
+    /// use merk::Op;
+    ///
+    /// let mut bank = store.prefixed(b"bank/");
+    /// bank.apply(&[(b"alice".to_vec(), Op::Put(vec![1]))]).unwrap();
+    /// assert_eq!(bank.get(b"alice").unwrap(), vec![1]);
+    /// assert_eq!(store.get(b"bank/alice").unwrap(), vec![1]);
+    /// ```
+    pub fn prefixed(&mut self, prefix: &[u8]) -> Prefixed {
+        Prefixed::new(self, prefix)
+    }
+
+    /// Begins a transaction which buffers writes in memory, and whose reads
+    /// see its own writes merged with the committed store. The writes are
+    /// applied in a single batch when the transaction is committed (see
+    /// `Transaction`).
+    ///
+    /// # Example
+    /// ```
+    /// # let mut store = merk::test_utils::TempMerk::new().unwrap();
+    /// let mut tx = store.begin();
+    /// tx.put(vec![1, 2, 3], vec![4]);
+    /// tx.savepoint();
+    /// tx.put(vec![1, 2, 3], vec![5]);
+    /// tx.rollback_to_savepoint().unwrap();
+    /// assert_eq!(tx.get(&[1, 2, 3]).unwrap(), vec![4]);
+    /// tx.commit().unwrap();
+    /// assert_eq!(store.get(&[1, 2, 3]).unwrap(), vec![4]);
+    /// ```
+    pub fn begin(&mut self) -> Transaction {
+        Transaction::new(self)
+    }
+
     /// Returns the root hash of the tree (a digest for the entire store which
     /// proofs can be checked against). If the tree is empty, returns the null
     /// hash (zero-filled).
@@ -61,12 +225,70 @@ impl Merk {
         self.tree.as_ref().map_or(NULL_HASH, |tree| tree.hash())
     }
 
+    /// Returns the sum of all values in the store (see `MerkOptions::sum_fn`),
+    /// or `None` if the store does not maintain sums. If the tree is empty,
+    /// returns `Some(0)`.
+    pub fn sum(&self) -> Option<u64> {
+        match &self.tree {
+            None => self.options.sum_fn.map(|_| 0),
+            Some(tree) => tree.sum()
+        }
+    }
+
+    /// Returns `true` if the store contains no keys.
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_none()
+    }
+
+    /// Returns the number of keys in the store. Fails if the store does not
+    /// maintain counts (see `MerkOptions::counts`).
+    pub fn len(&self) -> Result<u64> {
+        if !self.options.counts {
+            bail!("Store does not maintain counts");
+        }
+        Ok(self.tree.as_ref().map_or(0, |tree| tree.count().unwrap_or(0)))
+    }
+
+    /// Returns the number of keys in the store which are less than `key`
+    /// (whether or not `key` is in the store). Fails if the store does not
+    /// maintain counts (see `MerkOptions::counts`).
+    pub fn rank(&self, key: &[u8]) -> Result<u64> {
+        if !self.options.counts {
+            bail!("Store does not maintain counts");
+        }
+        if self.tree.is_none() {
+            return Ok(0);
+        }
+        self.walk(|walker| walker.rank(key))
+    }
+
+    /// Returns the key/value pair at the given index in key order. Fails if
+    /// the index is out of bounds, or if the store does not maintain counts
+    /// (see `MerkOptions::counts`).
+    pub fn get_by_index(&self, index: u64) -> Result<(Vec<u8>, Vec<u8>)> {
+        if !self.options.counts {
+            bail!("Store does not maintain counts");
+        }
+        if index >= self.len()? {
+            bail!("Index out of bounds");
+        }
+        match self.walk(|walker| walker.get_by_index(index))? {
+            None => bail!("Index out of bounds"),
+            Some(kv) => Ok(kv)
+        }
+    }
+
     /// Applies a batch of operations (puts and deletes) to the tree.
     ///
-    /// This will fail if the keys in `batch` are not sorted and unique. This
-    /// check creates some overhead, so if you are sure your batch is sorted and
-    /// unique you can use the unsafe `apply_unchecked` for a small performance
-    /// gain.
+    /// Conditional operations (such as `Op::PutIfAbsent`) whose condition does
+    /// not hold are skipped without affecting the rest of the batch, and are
+    /// returned along with the reason, sorted by key. Any other failure, such
+    /// as an `Op::Delete` of a missing key, fails the whole batch and leaves
+    /// the store as of the last commit.
+    ///
+    /// This will fail if the keys in `batch` are not sorted and unique. Since
+    /// the batch is borrowed, its keys and values are copied into the tree - to
+    /// avoid this, pass ownership of the batch with `apply_sorted`.
     ///
     /// # Example
     /// ```
@@ -81,58 +303,260 @@ impl Merk {
     /// ];
     /// store.apply(batch).unwrap();
     /// ```
-    pub fn apply(&mut self, batch: &Batch) -> Result<()> {
+    pub fn apply(&mut self, batch: &Batch) -> Result<Vec<(Vec<u8>, OpFailure)>> {
         // ensure keys in batch are sorted and unique
-        let mut maybe_prev_key = None;
-        for (key, _) in batch.iter() {
-            if let Some(prev_key) = maybe_prev_key {
-                if prev_key > *key {
-                    bail!("Keys in batch must be sorted");
-                } else if prev_key == *key {
-                    bail!("Keys in batch must be unique");
-                }
-            }
-            maybe_prev_key = Some(key.to_vec());
-        }
+        check_sorted(batch)?;
 
-        unsafe { self.apply_unchecked(batch) }
+        self.apply_sorted(SortedBatch::new_unchecked(batch.to_vec()))
     }
 
+    /// Applies a batch of operations (puts and deletes) to the tree, taking
+    /// ownership of the batch so that its keys and values are moved into the
+    /// tree rather than copied. Otherwise the same as `apply`.
+    ///
+    /// # Example
+    /// ```
+    /// # let mut store = merk::test_utils::TempMerk::new().unwrap();
+    /// use merk::{Op, SortedBatch};
+    ///
+    /// let batch = SortedBatch::new(vec![
+    ///     (vec![1, 2, 3], Op::Put(vec![4, 5, 6])),
+    ///     (vec![4, 5, 6], Op::Put(vec![7, 8, 9]))
+    /// ]).unwrap();
+    /// store.apply_sorted(batch).unwrap();
+    /// ```
+    pub fn apply_sorted(
+        &mut self,
+        batch: SortedBatch
+    ) -> Result<Vec<(Vec<u8>, OpFailure)>> {
+        let (deleted_keys, failures) = self.apply_to_tree(batch)?;
 
-    /// Applies a batch of operations (puts and deletes) to the tree.
+        // commit changes to db
+        self.commit(deleted_keys)?;
+
+        Ok(failures)
+    }
+
+    /// Applies the batch to the tree in memory without committing. Returns
+    /// the keys of the nodes which were removed from the tree, and the
+    /// conditional operations which were skipped, sorted by key.
+    fn apply_to_tree(
+        &mut self,
+        mut batch: SortedBatch
+    ) -> Result<(LinkedList<Vec<u8>>, Vec<(Vec<u8>, OpFailure)>)> {
+        let maybe_walker = self.tree.take()
+            .map(|tree| Walker::new(tree, self.source()));
+
+        let (maybe_tree, outcome) =
+            match Walker::apply_to(maybe_walker, batch.as_mut_slice()) {
+                Ok(result) => result,
+                Err(err) => {
+                    // the walker consumed the tree
+                    self.reload_tree()?;
+                    return Err(err);
+                }
+            };
+        self.tree = maybe_tree;
+
+        let mut failures: Vec<_> = outcome.failures.into_iter().collect();
+        failures.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok((outcome.deleted_keys, failures))
+    }
+
+    /// Computes the root hash the tree would have after applying the batch,
+    /// without writing to the store or changing the tree. Conditional
+    /// operations which would be skipped are ignored.
     ///
-    /// This is unsafe because the keys in `batch` must be sorted and unique -
-    /// if they are not, there will be undefined behavior. For a safe version of
-    /// this method which checks to ensure the batch is sorted and unique, see
-    /// `apply`.
+    /// This will fail if the keys in `batch` are not sorted and unique.
     ///
     /// # Example
     /// ```
     /// # let mut store = merk::test_utils::TempMerk::new().unwrap();
-    /// # store.apply(&[(vec![4,5,6], Op::Put(vec![0]))]).unwrap();
-    /// 
     /// use merk::Op;
     ///
-    /// let batch = &[
-    ///     (vec![1, 2, 3], Op::Put(vec![4, 5, 6])), // puts value [4,5,6] to key [1,2,3]
-    ///     (vec![4, 5, 6], Op::Delete) // deletes key [4,5,6]
+    /// let batch = &[(vec![1, 2, 3], Op::Put(vec![4, 5, 6]))];
+    /// let hash = store.apply_dry_run(batch).unwrap();
+    /// assert!(store.get(&[1, 2, 3]).is_err());
+    ///
+    /// store.apply(batch).unwrap();
+    /// assert_eq!(store.root_hash(), hash);
+    /// ```
+    pub fn apply_dry_run(&self, batch: &Batch) -> Result<Hash> {
+        check_sorted(batch)?;
+        let mut batch = batch.to_vec();
+
+        // apply to a copy of the in-memory nodes, fetching the rest from the
+        // store as needed
+        let maybe_walker = self.tree.clone()
+            .map(|tree| Walker::new(tree, self.source()));
+        let (maybe_tree, _) = Walker::apply_to(maybe_walker, batch.as_mut_slice())?;
+
+        match maybe_tree {
+            None => Ok(NULL_HASH),
+            Some(mut tree) => {
+                let mut committer = AggregateCommit {
+                    sum_fn: self.options.sum_fn,
+                    counts: self.options.counts
+                };
+                tree.commit(&mut committer)?;
+                Ok(tree.hash())
+            }
+        }
+    }
+
+    /// Applies a batch of operations (puts and deletes) to the tree, like
+    /// `apply`, and also returns a witness for the batch: the nodes which were
+    /// read or modified while applying it, as of before the batch.
+    ///
+    /// The witness can be checked against the root hash from before the batch,
+    /// and used to compute the root hash after the batch without access to the
+    /// store, with `merk::apply_to_proof` (see its note on which parts of the
+    /// witness can not be checked). If the store maintains sums or counts, the
+    /// witness also includes the node hashes of the children of those nodes,
+    /// so that the aggregates of the children can be checked.
+    ///
+    /// # Example
+    /// ```
+    /// # let mut store = merk::test_utils::TempMerk::new().unwrap();
+    /// use merk::{Op, MerkOptions, apply_to_proof};
+    ///
+    /// let prev_hash = store.root_hash();
+    /// let batch = &[(vec![1, 2, 3], Op::Put(vec![4, 5, 6]))];
+    /// let (_, witness) = store.apply_with_witness(batch).unwrap();
+    ///
+    /// let options = MerkOptions::default();
+    /// let hash = apply_to_proof(&witness, batch, prev_hash, &options).unwrap();
+    /// assert_eq!(hash, store.root_hash());
+    /// ```
+    pub fn apply_with_witness(
+        &mut self,
+        batch: &Batch
+    ) -> Result<(Vec<(Vec<u8>, OpFailure)>, Vec<u8>)> {
+        check_sorted(batch)?;
+
+        // the batch is applied to a copy of the root node read from the store,
+        // so every node below it which is read or modified is fetched (and
+        // recorded) as of before the batch. The resulting tree is the one
+        // which gets committed, so each operation only runs once.
+        let fetched = Arc::new(Mutex::new(vec![]));
+        let maybe_walker = match &self.tree {
+            None => None,
+            Some(tree) => {
+                let root = get_node(&self.db, tree.key())?;
+                fetched.lock().unwrap().push(root.clone());
+                let source = RecordingSource {
+                    source: self.source(),
+                    fetched: fetched.clone()
+                };
+                Some(Walker::new(root, source))
+            }
+        };
+
+        let mut batch = batch.to_vec();
+        let (maybe_tree, outcome) = Walker::apply_to(maybe_walker, batch.as_mut_slice())?;
+        let source = self.source();
+        let witness = encode_witness(
+            &fetched.lock().unwrap(),
+            self.options.sum_fn.is_some() || self.options.counts,
+            |link| Ok(source.fetch(link)?.node_hash())
+        )?;
+
+        self.tree = maybe_tree;
+        self.commit(outcome.deleted_keys)?;
+
+        let mut failures: Vec<_> = outcome.failures.into_iter().collect();
+        failures.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok((failures, witness))
+    }
+
+    /// Applies a batch of operations which may be unsorted and may contain a
+    /// key more than once. The batch is sorted by key, and duplicate keys are
+    /// resolved according to `duplicates` before applying.
+    ///
+    /// # Example
+    /// ```
+    /// # let mut store = merk::test_utils::TempMerk::new().unwrap();
+    /// use merk::{Op, DuplicateKeys};
+    ///
+    /// let batch = vec![
+    ///     (vec![4, 5, 6], Op::Put(vec![1])),
+    ///     (vec![1, 2, 3], Op::Put(vec![2])),
+    ///     (vec![4, 5, 6], Op::Put(vec![3])) // overwrites the first put
     /// ];
-    /// unsafe { store.apply_unchecked(batch).unwrap() };
+    /// store.apply_unsorted(batch, DuplicateKeys::LastWriteWins).unwrap();
     /// ```
-    pub unsafe fn apply_unchecked(&mut self, batch: &Batch) -> Result<()> {
+    pub fn apply_unsorted(
+        &mut self,
+        batch: Vec<BatchEntry>,
+        duplicates: DuplicateKeys
+    ) -> Result<Vec<(Vec<u8>, OpFailure)>> {
+        self.apply_sorted(SortedBatch::from_unsorted(batch, duplicates)?)
+    }
+
+    /// Applies a batch of operations (puts and deletes) to the tree.
+    ///
+    /// This is unsafe because the keys in `batch` must be sorted and unique -
+    /// if they are not, there will be undefined behavior. Prefer `apply`, or
+    /// `apply_sorted` which checks the batch once when the `SortedBatch` is
+    /// created and avoids copying.
+    #[deprecated(note = "use `apply_sorted` with a `SortedBatch` instead")]
+    pub unsafe fn apply_unchecked(
+        &mut self,
+        batch: &Batch
+    ) -> Result<Vec<(Vec<u8>, OpFailure)>> {
+        self.apply_sorted(SortedBatch::new_unchecked(batch.to_vec()))
+    }
+
+    /// Deletes all keys in the range `[start, end)`.
+    ///
+    /// Subtrees which lie entirely within the range are removed from the tree
+    /// without being loaded, and the records in the range are found by
+    /// iterating over RocksDB, so this is much faster than deleting each key
+    /// with `Op::Delete`.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) -> Result<()> {
+        if start > end {
+            bail!("Range start must not be greater than range end");
+        }
+
+        // collect the records to delete before the tree is modified
+        let mut deleted_keys = vec![];
+        let iter = self.db.iterator(rocksdb::IteratorMode::From(
+            start,
+            rocksdb::Direction::Forward
+        ))?;
+        for (key, _) in iter {
+            if &key[..] >= end {
+                break;
+            }
+            if !is_metadata_key(&key) {
+                deleted_keys.push(key.to_vec());
+            }
+        }
+
         let maybe_walker = self.tree.take()
             .map(|tree| Walker::new(tree, self.source()));
+        self.tree = match Walker::delete_range(maybe_walker, start, end) {
+            Ok(maybe_tree) => maybe_tree,
+            Err(err) => {
+                // the walker consumed the tree
+                self.reload_tree()?;
+                return Err(err);
+            }
+        };
 
-        // TODO: will return set of deleted keys
-        self.tree = Walker::apply_to(maybe_walker, batch)?;
+        self.commit(deleted_keys)
+    }
 
-        // commit changes to db
-        self.commit()
+    /// Replaces the tree with its state as of the last commit, after applying
+    /// or committing a batch failed part way through.
+    fn reload_tree(&mut self) -> Result<()> {
+        self.tree = integrity::load_root(&self.db)?;
+        Ok(())
     }
 
     /// Closes the store and deletes all data from disk.
     pub fn destroy(self) -> Result<()> {
-        let opts = default_db_opts();
+        let opts = self.options.to_db_opts();
         drop(self.db);
         rocksdb::DB::destroy(&opts, &self.path)?;
         Ok(())
@@ -150,20 +574,8 @@ impl Merk {
     /// check adds some overhead, so if you are sure your batch is sorted and
     /// unique you can use the unsafe `prove_unchecked` for a small performance
     /// gain.
-    pub fn prove(&mut self, query: &[Vec<u8>]) -> Result<Vec<u8>> {
-        // ensure keys in query are sorted and unique
-        let mut maybe_prev_key = None;
-        for key in query.iter() {
-            if let Some(prev_key) = maybe_prev_key {
-                if prev_key > *key {
-                    bail!("Keys in query must be sorted");
-                } else if prev_key == *key {
-                    bail!("Keys in query must be unique");
-                }
-            }
-            maybe_prev_key = Some(key.to_vec());
-        }
-
+    pub fn prove(&self, query: &[Vec<u8>]) -> Result<Vec<u8>> {
+        check_keys(query)?;
         unsafe { self.prove_unchecked(query) }
     }
 
@@ -179,125 +591,611 @@ impl Merk {
     /// if they are not, there will be undefined behavior. For a safe version of
     /// this method which checks to ensure the batch is sorted and unique, see
     /// `prove`.
-    pub unsafe fn prove_unchecked(&mut self, query: &[Vec<u8>]) -> Result<Vec<u8>> {
-        let mut tree = match self.tree.take() {
+    pub unsafe fn prove_unchecked(&self, query: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let tree = match &self.tree {
             None => bail!("Cannot create proof for empty tree"),
             Some(tree) => tree
         };
 
-        let mut ref_walker = RefWalker::new(&mut tree, self.source());
+        let ref_walker = RefWalker::new(tree, self.source());
         let (proof, _) = ref_walker.create_proof(query)?;
 
-        self.tree = Some(tree);
-
         let mut bytes = Vec::with_capacity(128);
         encode_into(proof.iter(), &mut bytes);
         Ok(bytes)
     }
 
-    fn commit(&mut self) -> Result<()> {
-        // TODO: concurrent commit
+    /// Creates a Merkle proof for a query made up of keys and ranges of keys
+    /// (see `Query`). Every key in the store which is selected by the query
+    /// will be proven to be in the tree, and the absence of any other selected
+    /// keys will be proven by including boundary keys.
+    ///
+    /// The proof returned is in an encoded format which can be verified with
+    /// `merk::verify_query_proof`.
+    pub fn prove_query(&self, query: &Query) -> Result<Vec<u8>> {
+        if self.tree.is_none() {
+            bail!("Cannot create proof for empty tree");
+        }
 
-        let mut batch = rocksdb::WriteBatch::default();
+        let (proof, _) = self.walk(|walker| walker.create_query_proof(query.items()))?;
 
-        if let Some(tree) = &mut self.tree {
-            // TODO: configurable committer
-            let mut committer = MerkCommitter::new(tree.height(), 1);
-            tree.commit(&mut committer)?;
+        let mut bytes = Vec::with_capacity(128);
+        encode_into(proof.iter(), &mut bytes);
+        Ok(bytes)
+    }
 
-            committer.batch.sort_by(|a, b| a.0.cmp(&b.0));
-            for (key, value) in committer.batch {
-                batch.put(key, value)?;
+    /// Reads a page of up to `limit` entries, starting after the `start` key
+    /// (exclusive) in the given direction, or from the first key in that
+    /// direction if `start` is `None`. Returns the entries along with a Merkle
+    /// proof that no entries were skipped, which can be verified with
+    /// `merk::verify_page_proof`.
+    ///
+    /// To read the next page, pass the key of the last entry as `start`.
+    ///
+    /// # Example
+    /// ```
+    /// # let mut store = merk::test_utils::TempMerk::new().unwrap();
+    /// # store.apply(&merk::test_utils::make_batch_seq(0..10)).unwrap();
+    /// use merk::{Direction, verify_page_proof};
+    ///
+    /// let (entries, proof) = store.query_page(None, Direction::Ascending, 5).unwrap();
+    /// let last_key = entries.last().unwrap().0.as_slice();
+    /// let (next_entries, _) = store
+    ///     .query_page(Some(last_key), Direction::Ascending, 5)
+    ///     .unwrap();
+    /// assert_eq!(next_entries.len(), 5);
+    ///
+    /// let verified = verify_page_proof(
+    ///     &proof,
+    ///     None,
+    ///     Direction::Ascending,
+    ///     5,
+    ///     store.root_hash()
+    /// ).unwrap();
+    /// assert_eq!(verified, entries);
+    /// ```
+    pub fn query_page(
+        &self,
+        start: Option<&[u8]>,
+        direction: Direction,
+        limit: usize
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, Vec<u8>)> {
+        if self.tree.is_none() {
+            bail!("Cannot create proof for empty tree");
+        }
+
+        let mode = match (start, direction) {
+            (None, Direction::Ascending) => rocksdb::IteratorMode::Start,
+            (None, Direction::Descending) => rocksdb::IteratorMode::End,
+            (Some(start), Direction::Ascending) => {
+                rocksdb::IteratorMode::From(start, rocksdb::Direction::Forward)
+            },
+            (Some(start), Direction::Descending) => {
+                rocksdb::IteratorMode::From(start, rocksdb::Direction::Reverse)
             }
+        };
 
-            // update pointer to root node
-            batch.put(ROOT_KEY_KEY, tree.key())?;
-        } else {
-            // empty tree, delete pointer to root
-            batch.delete(ROOT_KEY_KEY)?;
+        let mut entries = Vec::with_capacity(limit);
+        for (key, value) in self.db.iterator(mode)? {
+            if entries.len() == limit {
+                break;
+            }
+            if is_metadata_key(&key) {
+                continue;
+            }
+            if let Some(start) = start {
+                if !direction.is_after(&key, start) {
+                    continue;
+                }
+            }
+
+            let node = Tree::decode(&key, &value)?;
+            entries.push((key.to_vec(), node.value().to_vec()));
         }
 
-        // write to db
-        let mut opts = rocksdb::WriteOptions::default();
-        opts.set_sync(false);
-        opts.disable_wal(true);
-        self.db.write_opt(batch, &opts)?;
+        // proving the start key includes the entry at or before it (or the
+        // edge of the tree) as a boundary, as in an absence proof, and proving
+        // the entries of a short page includes the edge of the tree after them
+        let mut keys = Vec::with_capacity(entries.len() + 1);
+        keys.extend(start.map(|start| start.to_vec()));
+        keys.extend(entries.iter().map(|(key, _)| key.clone()));
+        if direction == Direction::Descending {
+            keys.reverse();
+        }
 
-        Ok(())
+        let (proof, _) = self.walk(|walker| walker.create_proof(&keys))?;
+
+        let mut bytes = Vec::with_capacity(128);
+        encode_into(proof.iter(), &mut bytes);
+        Ok((entries, bytes))
     }
 
-    fn source(&self) -> MerkSource {
-        MerkSource { db: &self.db }
+    /// Creates a Merkle proof of the sum of the values with keys in the range
+    /// `[start, end)`, which can be verified with `merk::verify_sum_proof`.
+    ///
+    /// Fails if the store was not opened with a sum function (see
+    /// `MerkOptions::sum_fn`).
+    pub fn prove_sum(&self, start: &[u8], end: &[u8]) -> Result<Vec<u8>> {
+        if self.options.sum_fn.is_none() {
+            bail!("Store does not maintain sums");
+        }
+        if start > end {
+            bail!("Range start must not be greater than range end");
+        }
+
+        let tree = match &self.tree {
+            None => bail!("Cannot create proof for empty tree"),
+            Some(tree) => tree
+        };
+
+        let ref_walker = RefWalker::new(tree, self.source());
+        let proof = ref_walker.create_range_proof(
+            Aggregate::Sum,
+            start,
+            end,
+            (None, None)
+        )?;
+
+        let mut bytes = Vec::with_capacity(128);
+        encode_into(proof.iter(), &mut bytes);
+        Ok(bytes)
     }
 
-    fn tree(&self) -> Option<&Tree> {
-        self.tree.as_ref()
+    /// Creates a Merkle proof of the number of keys in the store, which can be
+    /// verified with `merk::verify_len_proof`.
+    ///
+    /// Fails if the store does not maintain counts (see
+    /// `MerkOptions::counts`).
+    pub fn prove_len(&self) -> Result<Vec<u8>> {
+        let proof = self.walk(|walker| walker.create_len_proof())?;
+
+        let mut bytes = Vec::with_capacity(32);
+        encode_into(proof.iter(), &mut bytes);
+        Ok(bytes)
     }
-}
 
-#[derive(Clone)]
-struct MerkSource<'a> {
-    db: &'a rocksdb::DB
-}
+    /// Creates a Merkle proof of the number of keys in the store which are
+    /// less than `key`, which can be verified with `merk::verify_rank_proof`.
+    ///
+    /// Fails if the store does not maintain counts (see
+    /// `MerkOptions::counts`).
+    pub fn prove_rank(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let proof = self.walk(|walker| {
+            walker.create_range_proof(Aggregate::Count, &[], key, (None, None))
+        })?;
 
-impl<'a> Fetch for MerkSource<'a> {
-    fn fetch(&self, link: &Link) -> Result<Tree> {
-        get_node(&self.db, link.key())
+        let mut bytes = Vec::with_capacity(128);
+        encode_into(proof.iter(), &mut bytes);
+        Ok(bytes)
     }
-}
 
-struct MerkCommitter {
-    batch: Vec<(Vec<u8>, Vec<u8>)>,
-    height: u8,
-    levels: u8
-}
+    /// Creates a Merkle proof of the key/value pair at the given index in key
+    /// order, which can be verified with `merk::verify_index_proof`.
+    ///
+    /// Fails if the index is out of bounds, or if the store does not maintain
+    /// counts (see `MerkOptions::counts`).
+    pub fn prove_index(&self, index: u64) -> Result<Vec<u8>> {
+        let proof = self.walk(|walker| walker.create_index_proof(index))?;
 
-impl MerkCommitter {
-    fn new(height: u8, levels: u8) -> Self {
-        MerkCommitter { batch: Vec::with_capacity(10000), height, levels }
+        let mut bytes = Vec::with_capacity(128);
+        encode_into(proof.iter(), &mut bytes);
+        Ok(bytes)
     }
-}
 
-impl Commit for MerkCommitter {
-    fn write(&mut self, tree: &Tree) -> Result<()> {
-        let mut buf = Vec::with_capacity(tree.encoding_length());
-        tree.encode_into(&mut buf);
-        self.batch.push((tree.key().to_vec(), buf));
-        Ok(())
+    /// Returns the keys which were added, removed or changed between two
+    /// versions of a store, such as a store and a copy of it made at an
+    /// earlier height, in key order.
+    ///
+    /// Both trees are walked together, and subtrees whose hashes are the same
+    /// in both versions are skipped without being fetched, so the cost is
+    /// proportional to the size of the changes rather than of the stores.
+    ///
+    /// # Example
+    /// ```
+    /// # let mut old = merk::test_utils::TempMerk::new().unwrap();
+    /// # let mut new = merk::test_utils::TempMerk::new().unwrap();
+    /// use merk::{Merk, Op, Change};
+    ///
+    /// old.apply(&[(vec![1], Op::Put(vec![1])), (vec![2], Op::Put(vec![2]))]).unwrap();
+    /// new.apply(&[(vec![1], Op::Put(vec![3])), (vec![4], Op::Put(vec![4]))]).unwrap();
+    ///
+    /// let changes = Merk::diff(&old, &new).unwrap();
+    /// assert_eq!(changes, vec![
+    ///     (vec![1], Change::Changed(vec![1], vec![3])),
+    ///     (vec![2], Change::Removed(vec![2])),
+    ///     (vec![4], Change::Added(vec![4]))
+    /// ]);
+    /// ```
+    pub fn diff(old: &Merk, new: &Merk) -> Result<Vec<(Vec<u8>, Change)>> {
+        diff::diff(old, new)
     }
 
-    fn prune(&self, tree: &Tree) -> (bool, bool) {
-        // keep N top levels of tree
-        let prune = (self.height - tree.height()) > self.levels;
-        (prune, prune)
+    /// Creates a proof of the changes between two versions of a store (see
+    /// `Merk::diff`), which can be verified against the root hashes of both
+    /// versions with `merk::verify_diff_proof`.
+    ///
+    /// The proof includes the nodes of each version which were walked to find
+    /// the changes, and the hashes of the subtrees which were skipped.
+    pub fn prove_diff(old: &Merk, new: &Merk) -> Result<Vec<u8>> {
+        diff::prove_diff(old, new)
     }
-}
 
-fn get_node(db: &rocksdb::DB, key: &[u8]) -> Result<Tree> {
-    // TODO: for bottom levels, iterate and return tree with descendants
-    let bytes = db.get_pinned(key)?;
-    if let Some(bytes) = bytes {
-        Tree::decode(key, &bytes)
-    } else {
-        bail!("key not found: '{:?}'", key)
+    /// Calls `f` with a `RefWalker` over the tree, fetching pruned nodes from
+    /// the store. Fails if the tree is empty.
+    fn walk<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce(&RefWalker<MerkSource>) -> Result<T>
+    {
+        let tree = match &self.tree {
+            None => bail!("Tree is empty"),
+            Some(tree) => tree
+        };
+
+        f(&RefWalker::new(tree, self.source()))
     }
-}
 
-fn default_db_opts() -> rocksdb::Options {
-    let mut opts = rocksdb::Options::default();
-    opts.create_if_missing(true);
-    opts.increase_parallelism(num_cpus::get() as i32);
-    // opts.set_advise_random_on_open(false);
-    opts.set_allow_mmap_writes(true);
-    opts.set_allow_mmap_reads(true);
-    // TODO: tune
-    opts
-}
+    fn commit<I>(&mut self, deleted_keys: I) -> Result<()>
+        where I: IntoIterator<Item=Vec<u8>>
+    {
+        let mut batch = rocksdb::WriteBatch::default();
+        if let Err(err) = self.commit_into(&mut batch, deleted_keys) {
+            // the tree may have been partially committed (for instance if a
+            // sum overflowed), but none of it was written
+            self.reload_tree()?;
+            return Err(err);
+        }
 
-#[cfg(test)]
+        // write to db
+        let opts = self.options.to_write_opts();
+        self.db.write(batch, &opts)
+    }
+
+    /// Commits the tree, adding the writes of the updated node records to
+    /// `batch` rather than writing them, so that they can be written atomically
+    /// along with other changes.
+    fn commit_into<I>(
+        &mut self,
+        batch: &mut rocksdb::WriteBatch,
+        deleted_keys: I
+    ) -> Result<()>
+        where I: IntoIterator<Item=Vec<u8>>
+    {
+        // TODO: concurrent commit
+
+        // records of nodes which were removed from the tree are deleted
+        let mut writes: Vec<(Vec<u8>, Option<Vec<u8>>)> = deleted_keys.into_iter()
+            .map(|key| (key, None))
+            .collect();
+
+        if let Some(tree) = &mut self.tree {
+            // TODO: configurable committer
+            let mut committer = MerkCommitter::new(
+                tree.height(),
+                1,
+                self.options.sum_fn,
+                self.options.counts
+            );
+            tree.commit(&mut committer)?;
+
+            committer.batch.sort_by(|a, b| a.0.cmp(&b.0));
+            writes.extend(committer.batch.into_iter()
+                .map(|(key, value)| (key, Some(value))));
+
+            // update pointer to root node, and root hash to check against
+            // when reopening
+            writes.push((ROOT_KEY_KEY.to_vec(), Some(tree.key().to_vec())));
+            writes.push((ROOT_HASH_KEY.to_vec(), Some(tree.hash().to_vec())));
+        } else {
+            // empty tree, delete pointer to root
+            writes.push((ROOT_KEY_KEY.to_vec(), None));
+            writes.push((ROOT_HASH_KEY.to_vec(), None));
+        }
+
+        // record the previous versions of the records so the commit can be
+        // rolled back
+        if let (Some(undo), true) = (&self.undo, self.options.undo_depth > 0) {
+            let mut records = Vec::with_capacity(writes.len());
+            for (key, _) in writes.iter() {
+                let prev = self.db.get_pinned(key)?.map(|value| value.to_vec());
+                records.push((key.clone(), prev));
+            }
+            undo.record(batch, &records, self.options.undo_depth)?;
+        }
+
+        for (key, value) in writes {
+            // cached copies of rewritten or deleted nodes are now stale
+            self.cache.invalidate(&key);
+            match value {
+                None => self.db.batch_delete(batch, key)?,
+                Some(value) => self.db.batch_put(batch, key, value)?
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Undoes the `n` most recent commits, restoring the tree and root hash
+    /// to their state before them.
+    ///
+    /// Only the commits recorded in the undo log can be rolled back (see
+    /// `MerkOptions::undo_depth`), so this fails if fewer than `n` commits
+    /// are recorded.
+    ///
+    /// # Example
+    /// ```
+    /// # let path = std::env::temp_dir().join("merk-rollback-doctest");
+    /// use merk::{Merk, MerkOptions, Op};
+    ///
+    /// let options = MerkOptions { undo_depth: 10, ..MerkOptions::default() };
+    /// let mut store = Merk::open_with_options(&path, options).unwrap();
+    /// store.apply(&[(vec![1, 2, 3], Op::Put(vec![4]))]).unwrap();
+    /// let root_hash = store.root_hash();
+    ///
+    /// store.apply(&[(vec![1, 2, 3], Op::Put(vec![5]))]).unwrap();
+    /// store.rollback(1).unwrap();
+    /// assert_eq!(store.root_hash(), root_hash);
+    /// assert_eq!(store.get(&[1, 2, 3]).unwrap(), vec![4]);
+    /// # store.destroy().unwrap();
+    /// ```
+    pub fn rollback(&mut self, n: usize) -> Result<()> {
+        let undo = match &self.undo {
+            None => bail!("Store does not keep an undo log"),
+            Some(undo) => undo
+        };
+
+        // restore records from newest commit to oldest, so the oldest
+        // version of each record is written last
+        let mut batch = rocksdb::WriteBatch::default();
+        for records in undo.pop(&mut batch, n)? {
+            for (key, value) in records {
+                self.cache.invalidate(&key);
+                match value {
+                    None => self.db.batch_delete(&mut batch, key)?,
+                    Some(value) => self.db.batch_put(&mut batch, key, value)?
+                }
+            }
+        }
+
+        let opts = self.options.to_write_opts();
+        self.db.write(batch, &opts)?;
+
+        self.tree = integrity::load_root(&self.db)?;
+        Ok(())
+    }
+
+    /// Returns the number of most recent commits which can be undone with
+    /// `Merk::rollback`.
+    pub fn undo_len(&self) -> Result<usize> {
+        match &self.undo {
+            None => Ok(0),
+            Some(undo) => undo.len()
+        }
+    }
+
+    /// Audits the entire store by reading every node reachable from the root
+    /// from RocksDB. Recomputes each node's `kv_hash` and hash bottom-up, and
+    /// checks them against the links which point to it, along with AVL
+    /// balance, the child heights recorded in links, and key ordering.
+    ///
+    /// Returns an `ErrorKind::Corruption` error describing the first problem
+    /// found, otherwise returns a report of the number of nodes and of any
+    /// orphaned node records (records not reachable from the root).
+    ///
+    /// This reads every record in the store, so may take a long time for large
+    /// stores.
+    pub fn verify_integrity(&self) -> Result<IntegrityReport> {
+        integrity::verify_store(&self.db, &self.options)
+    }
+
+    /// Deletes all node records which are not reachable from the root, returning
+    /// the number of records deleted.
+    ///
+    /// Deleted nodes are removed from the store when a batch is applied, so
+    /// this is only needed to clean up records left behind by older versions.
+    /// Stores written by those versions have to be upgraded with
+    /// `Merk::migrate` before they can be opened, which runs this once the
+    /// store is upgraded. The whole store is verified first (see
+    /// `verify_integrity`), and nothing is deleted if it is corrupted.
+    pub fn gc(&mut self) -> Result<usize> {
+        let report = self.verify_integrity()?;
+
+        let mut batch = rocksdb::WriteBatch::default();
+        for key in report.orphaned_keys.iter() {
+            self.cache.invalidate(key);
+            self.db.batch_delete(&mut batch, key)?;
+        }
+
+        let opts = self.options.to_write_opts();
+        self.db.write(batch, &opts)?;
+
+        Ok(report.orphaned_keys.len())
+    }
+
+    /// Returns the hit and miss counters of the cache of decoded nodes which
+    /// is consulted when walking to pruned nodes (during `apply` and `prove`).
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    fn source(&self) -> MerkSource {
+        MerkSource { db: &self.db, cache: &self.cache }
+    }
+
+    fn tree(&self) -> Option<&Tree> {
+        self.tree.as_ref()
+    }
+}
+
+#[derive(Clone)]
+struct MerkSource<'a> {
+    db: &'a Column,
+    cache: &'a NodeCache
+}
+
+impl<'a> MerkSource<'a> {
+    /// Fetches the node with the given key, from the cache if possible.
+    fn fetch_key(&self, key: &[u8]) -> Result<Tree> {
+        if let Some(tree) = self.cache.get(key) {
+            return Ok(tree);
+        }
+
+        let tree = get_node(&self.db, key)?;
+        self.cache.insert(tree.clone());
+        Ok(tree)
+    }
+}
+
+impl<'a> Fetch for MerkSource<'a> {
+    fn fetch(&self, link: &Link) -> Result<Tree> {
+        self.fetch_key(link.key())
+    }
+}
+
+/// A source which records a copy of every node it fetches.
+#[derive(Clone)]
+struct RecordingSource<'a> {
+    source: MerkSource<'a>,
+    fetched: Arc<Mutex<Vec<Tree>>>
+}
+
+impl<'a> Fetch for RecordingSource<'a> {
+    fn fetch(&self, link: &Link) -> Result<Tree> {
+        let tree = self.source.fetch(link)?;
+        self.fetched.lock().unwrap().push(tree.clone());
+        Ok(tree)
+    }
+}
+
+struct MerkCommitter {
+    batch: Vec<(Vec<u8>, Vec<u8>)>,
+    height: u8,
+    levels: u8,
+    sum_fn: Option<SumFn>,
+    counts: bool
+}
+
+impl MerkCommitter {
+    fn new(
+        height: u8,
+        levels: u8,
+        sum_fn: Option<SumFn>,
+        counts: bool
+    ) -> Self {
+        MerkCommitter {
+            batch: Vec::with_capacity(10000),
+            height,
+            levels,
+            sum_fn,
+            counts
+        }
+    }
+}
+
+impl Commit for MerkCommitter {
+    fn write(&mut self, tree: &Tree) -> Result<()> {
+        let mut buf = Vec::with_capacity(tree.encoding_length());
+        tree.encode_into(&mut buf);
+        self.batch.push((tree.key().to_vec(), buf));
+        Ok(())
+    }
+
+    fn prune(&self, tree: &Tree) -> (bool, bool) {
+        // keep N top levels of tree
+        let prune = (self.height - tree.height()) > self.levels;
+        (prune, prune)
+    }
+
+    fn value_sum(&self, value: &[u8]) -> Option<u64> {
+        self.sum_fn.map(|sum_fn| sum_fn(value))
+    }
+
+    fn counts(&self) -> bool {
+        self.counts
+    }
+}
+
+/// Returns `true` if the key is used to store metadata rather than a node.
+fn is_metadata_key(key: &[u8]) -> bool {
+    key == ROOT_KEY_KEY || key == ROOT_HASH_KEY || key == FORMAT_VERSION_KEY
+}
+
+/// Opens the RocksDB database at `path` with the column families a store uses,
+/// creating it if it does not exist.
+fn open_db<P: AsRef<Path>>(
+    path: P,
+    options: &MerkOptions
+) -> Result<(Arc<rocksdb::DB>, PathBuf)> {
+    let mut db_opts = options.to_db_opts();
+    db_opts.create_missing_column_families(true);
+    let mut path_buf = PathBuf::new();
+    path_buf.push(path);
+    let db = Arc::new(rocksdb::DB::open_cf(&db_opts, &path_buf, &[UNDO_CF])?);
+    Ok((db, path_buf))
+}
+
+/// Checks that the store was written in the current format version, and
+/// records the version in stores which have no tree yet.
+fn check_format_version(
+    db: &Column,
+    undo: Option<&UndoLog>,
+    options: &MerkOptions
+) -> Result<()> {
+    if let Some(bytes) = db.get_pinned(FORMAT_VERSION_KEY)? {
+        let version = match <[u8; 4]>::try_from(&bytes[..]) {
+            Err(_) => bail!(ErrorKind::Corruption("Invalid format version".to_string())),
+            Ok(bytes) => u32::from_be_bytes(bytes)
+        };
+        if version != FORMAT_VERSION {
+            bail!(ErrorKind::UnsupportedVersion(version));
+        }
+        return Ok(());
+    }
+
+    // a tree without a recorded version was written before versions were
+    // recorded, when nodes were hashed differently, and has to be upgraded
+    // with `Merk::migrate`
+    if db.get_pinned(ROOT_KEY_KEY)?.is_some() {
+        bail!(ErrorKind::UnsupportedVersion(0));
+    }
+
+    // an empty store has no nodes to convert, but undo entries from an
+    // earlier version would restore nodes in the old format, so they are
+    // dropped
+    let mut batch = rocksdb::WriteBatch::default();
+    if let Some(undo) = undo {
+        undo.truncate(&mut batch, 0)?;
+    }
+    db.batch_put(&mut batch, FORMAT_VERSION_KEY, FORMAT_VERSION.to_be_bytes())?;
+    db.write(batch, &options.to_write_opts())
+}
+
+fn get_node(db: &Column, key: &[u8]) -> Result<Tree> {
+    // TODO: for bottom levels, iterate and return tree with descendants
+    let bytes = db.get_pinned(key)?;
+    if let Some(bytes) = bytes {
+        Tree::decode(key, &bytes)
+    } else {
+        bail!("key not found: '{:?}'", key)
+    }
+}
+
+#[cfg(test)]
 mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::convert::TryInto;
     use std::thread;
+    use rand::prelude::*;
     use crate::test_utils::*;
+    use crate::tree::{Tree, Fetch, kv_hash, legacy_kv_hash};
+    use crate::{Op, OpFailure, MergeFn, DuplicateKeys, SortedBatch, ErrorKind};
+    use crate::{verify_proof, verify_sum_proof};
+    use crate::{verify_len_proof, verify_rank_proof, verify_index_proof};
+    use crate::{verify_page_proof, Direction};
+    use crate::{verify_query_proof, Query};
+    use crate::apply_to_proof;
+    use super::{CacheStats, Merk, MerkOptions, Compression, ROOT_HASH_KEY, NULL_HASH};
+    use super::{FORMAT_VERSION_KEY, FORMAT_VERSION, UNDO_CF};
+    use super::migrate::rehash;
 
     #[test]
     fn simple_insert_apply() {
@@ -310,7 +1208,8 @@ mod test {
         merk.apply(&batch).expect("apply failed");
 
         assert_tree_invariants(merk.tree().expect("expected tree"));
-        assert_eq!(merk.root_hash(), [217, 218, 163, 74, 119, 133, 165, 247, 140, 194, 85, 70, 28, 33, 61, 148, 118, 231, 134, 111]);
+        // the root hash as of format version 1
+        assert_eq!(merk.root_hash(), [77, 234, 38, 192, 101, 57, 134, 88, 8, 29, 124, 14, 45, 58, 89, 113, 143, 157, 13, 91]);
     }
 
     #[test]
@@ -344,4 +1243,1015 @@ mod test {
 
         }
     }
+
+    #[test]
+    fn node_cache() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..100)).expect("apply failed");
+
+        let link = merk.tree().unwrap().link(true).unwrap().clone();
+        let key = link.key().to_vec();
+
+        merk.source().fetch(&link).expect("fetch failed");
+        assert_eq!(merk.cache_stats(), CacheStats { hits: 0, misses: 1 });
+        merk.source().fetch(&link).expect("fetch failed");
+        assert_eq!(merk.cache_stats(), CacheStats { hits: 1, misses: 1 });
+
+        // rewriting the node invalidates the cached copy
+        merk.apply(&[(key, Op::Put(vec![1, 2, 3]))]).expect("apply failed");
+        let tree = merk.source().fetch(&link).expect("fetch failed");
+        assert_eq!(tree.value(), &[1, 2, 3]);
+        assert_eq!(merk.cache_stats(), CacheStats { hits: 1, misses: 2 });
+    }
+
+    #[test]
+    fn open_with_options() {
+        let path = thread::current().name().unwrap().to_owned();
+        let options = MerkOptions {
+            block_cache_size: Some(1 << 20),
+            compression: Some(Compression::None),
+            wal: true,
+            sync_writes: true,
+            bloom_filter_bits: Some(10),
+            mmap: false,
+            node_cache_capacity: 16,
+            sum_fn: None,
+            counts: false,
+            undo_depth: 0
+        };
+
+        let mut merk = Merk::open_with_options(&path, options.clone())
+            .expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..20)).expect("apply failed");
+        let root_hash = merk.root_hash();
+        drop(merk);
+
+        let merk = Merk::open_with_options(&path, options)
+            .expect("failed to reopen merk");
+        assert_eq!(merk.root_hash(), root_hash);
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn sync_without_wal() {
+        let path = thread::current().name().unwrap().to_owned();
+        let options = MerkOptions {
+            wal: false,
+            sync_writes: true,
+            ..MerkOptions::default()
+        };
+        assert!(Merk::open_with_options(&path, options).is_err());
+    }
+
+    #[test]
+    fn reopen_durable() {
+        let path = thread::current().name().unwrap().to_owned();
+
+        let mut merk = Merk::open_with_options(&path, MerkOptions::durable())
+            .expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..20)).expect("apply failed");
+        let root_hash = merk.root_hash();
+        drop(merk);
+
+        let merk = Merk::open_with_options(&path, MerkOptions::durable())
+            .expect("failed to reopen merk");
+        assert_eq!(merk.root_hash(), root_hash);
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn delete_persisted() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..20)).expect("apply failed");
+
+        merk.apply(&[ del_entry(5), del_entry(10) ]).expect("apply failed");
+        assert!(merk.get(&seq_key(5)).is_err());
+        assert!(merk.get(&seq_key(10)).is_err());
+        assert!(merk.get(&seq_key(6)).is_ok());
+
+        let report = merk.verify_integrity().expect("verify failed");
+        assert_eq!(report.node_count, 18);
+        assert!(report.orphaned_keys.is_empty());
+
+        // deleting every key leaves no records behind
+        let batch: Vec<_> = (0..20)
+            .filter(|i| *i != 5 && *i != 10)
+            .map(del_entry)
+            .collect();
+        merk.apply(&batch).expect("apply failed");
+        // only the format version is left
+        assert!(merk.db.iterator(rocksdb::IteratorMode::Start).unwrap()
+            .all(|(key, _)| key[..] == FORMAT_VERSION_KEY[..]));
+    }
+
+    #[test]
+    fn conditional_ops() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..10)).expect("apply failed");
+
+        let failures = merk.apply(&[
+            (seq_key(1), Op::CompareAndSwap(vec![0], vec![1])),
+            (seq_key(2), Op::CompareAndSwap(vec![123; 60], vec![2])),
+            (seq_key(3), Op::DeleteIfExists),
+            (seq_key(4), Op::PutIfAbsent(vec![4])),
+            (seq_key(20), Op::DeleteIfExists),
+            (seq_key(21), Op::PutIfAbsent(vec![21])),
+            (seq_key(22), Op::CompareAndSwap(vec![], vec![22]))
+        ]).expect("apply failed");
+
+        assert_eq!(failures, vec![
+            (seq_key(1), OpFailure::ValueMismatch),
+            (seq_key(4), OpFailure::KeyExists),
+            (seq_key(22), OpFailure::KeyNotFound)
+        ]);
+        assert_eq!(merk.get(&seq_key(1)).unwrap(), vec![123; 60]);
+        assert_eq!(merk.get(&seq_key(2)).unwrap(), vec![2]);
+        assert!(merk.get(&seq_key(3)).is_err());
+        assert_eq!(merk.get(&seq_key(21)).unwrap(), vec![21]);
+        assert!(merk.get(&seq_key(22)).is_err());
+        assert_tree_invariants(merk.tree().expect("expected tree"));
+    }
+
+    #[test]
+    fn failed_apply() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..10)).expect("apply failed");
+        let root_hash = merk.root_hash();
+
+        // deleting a missing key fails the whole batch
+        let batch = vec![
+            (seq_key(1), Op::Put(vec![1])),
+            (seq_key(20), Op::Delete)
+        ];
+        assert!(merk.apply(&batch).is_err());
+
+        // the tree is left as of the last commit
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get(&seq_key(1)).unwrap(), vec![123; 60]);
+        let proof = merk.prove(&[seq_key(2)]).expect("prove failed");
+        verify_proof(&proof, &[seq_key(2)], root_hash).expect("verify failed");
+
+        merk.apply(&make_batch_seq(10..20)).expect("apply failed");
+        let report = merk.verify_integrity().expect("verify failed");
+        assert_eq!(report.node_count, 20);
+    }
+
+    #[test]
+    fn delete_range() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..1000)).expect("apply failed");
+
+        merk.delete_range(&seq_key(100), &seq_key(900)).expect("delete_range failed");
+        assert!(merk.get(&seq_key(99)).is_ok());
+        assert!(merk.get(&seq_key(100)).is_err());
+        assert!(merk.get(&seq_key(899)).is_err());
+        assert!(merk.get(&seq_key(900)).is_ok());
+        assert_tree_invariants(merk.tree().expect("expected tree"));
+
+        let report = merk.verify_integrity().expect("verify failed");
+        assert_eq!(report.node_count, 200);
+        assert!(report.orphaned_keys.is_empty());
+
+        merk.delete_range(&[], &[255; 9]).expect("delete_range failed");
+        assert!(merk.tree().is_none());
+        // only the format version is left
+        assert!(merk.db.iterator(rocksdb::IteratorMode::Start).unwrap()
+            .all(|(key, _)| key[..] == FORMAT_VERSION_KEY[..]));
+
+        assert!(merk.delete_range(&[2], &[1]).is_err());
+    }
+
+    #[test]
+    fn apply_unsorted() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+
+        let mut batch = make_batch_rand(100, 1);
+        batch.push((batch[0].0.clone(), Op::Put(vec![1, 2, 3])));
+        merk.apply_unsorted(batch, DuplicateKeys::LastWriteWins)
+            .expect("apply failed");
+        assert_tree_invariants(merk.tree().expect("expected tree"));
+
+        let batch = vec![
+            (seq_key(1), Op::Put(vec![1])),
+            (seq_key(0), Op::Put(vec![2])),
+            (seq_key(1), Op::Delete)
+        ];
+        let root_hash = merk.root_hash();
+        assert!(merk.apply_unsorted(batch, DuplicateKeys::Error).is_err());
+        assert_eq!(merk.root_hash(), root_hash);
+    }
+
+    #[test]
+    fn apply_sorted() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+
+        let batch = SortedBatch::new(make_batch_seq(0..20)).expect("batch not sorted");
+        merk.apply_sorted(batch).expect("apply failed");
+        assert_tree_invariants(merk.tree().expect("expected tree"));
+        // the root hash as of format version 1
+        assert_eq!(merk.root_hash(), [77, 234, 38, 192, 101, 57, 134, 88, 8, 29, 124, 14, 45, 58, 89, 113, 143, 157, 13, 91]);
+
+        let mut batch = make_batch_seq(0..3);
+        batch.swap(0, 1);
+        assert!(SortedBatch::new(batch).is_err());
+        assert!(SortedBatch::new(vec![ put_entry(1), put_entry(1) ]).is_err());
+    }
+
+    #[test]
+    fn apply_dry_run() {
+        let path = thread::current().name().unwrap().to_owned();
+        let options = MerkOptions {
+            counts: true,
+            ..MerkOptions::default()
+        };
+        let mut merk = Merk::open_with_options(&path, options)
+            .expect("failed to open merk");
+        assert_eq!(merk.apply_dry_run(&[]).unwrap(), NULL_HASH);
+
+        for i in 0..4 {
+            let batch = make_batch_rand(500, i);
+            let hash = merk.apply_dry_run(&batch).expect("dry run failed");
+            assert_ne!(hash, merk.root_hash());
+            merk.apply(&batch).expect("apply failed");
+            assert_eq!(merk.root_hash(), hash);
+        }
+
+        // the tree and store are left unchanged
+        let root_hash = merk.root_hash();
+        let batch = make_del_batch_rand(500, 0);
+        let hash = merk.apply_dry_run(&batch).expect("dry run failed");
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.verify_integrity().unwrap().node_count, 2000);
+        merk.apply(&batch).expect("apply failed");
+        assert_eq!(merk.root_hash(), hash);
+
+        // deleting every key gives the null hash
+        let mut batch: Vec<_> = (1..4).flat_map(|i| make_del_batch_rand(500, i)).collect();
+        batch.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(merk.apply_dry_run(&batch).unwrap(), NULL_HASH);
+        assert_ne!(merk.root_hash(), NULL_HASH);
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn apply_with_witness() {
+        let option_sets = vec![
+            MerkOptions::default(),
+            MerkOptions { counts: true, ..MerkOptions::default() },
+            MerkOptions { sum_fn: Some(first_byte), ..MerkOptions::default() }
+        ];
+        for (i, options) in option_sets.into_iter().enumerate() {
+            let path = format!("{}-{}", thread::current().name().unwrap(), i);
+            let mut merk = Merk::open_with_options(&path, options.clone())
+                .expect("failed to open merk");
+
+            let batches = vec![
+                make_batch_seq(0..100),
+                make_batch_rand(50, 0),
+                make_del_batch_seq(10..60),
+                make_batch_seq(40..45),
+                vec![(seq_key(99), Op::PutIfAbsent(vec![1]))],
+                make_del_batch_rand(50, 0)
+            ];
+            for batch in batches.iter() {
+                let prev_hash = merk.root_hash();
+                let (failures, witness) = merk.apply_with_witness(batch)
+                    .expect("apply failed");
+                let hash = apply_to_proof(&witness, batch, prev_hash, &options)
+                    .expect("apply_to_proof failed");
+                assert_eq!(hash, merk.root_hash());
+                assert_eq!(failures.is_empty(), batch.len() > 1);
+
+                // the witness only proves the state it was created for
+                if hash != prev_hash {
+                    assert!(apply_to_proof(&witness, batch, hash, &options).is_err());
+                }
+            }
+            merk.destroy().expect("failed to destroy merk");
+        }
+    }
+
+    #[test]
+    fn apply_with_witness_merge() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..100)).expect("apply failed");
+        let prev_hash = merk.root_hash();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let merge: MergeFn = {
+            let calls = calls.clone();
+            Arc::new(move |value| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                vec![value.map_or(0, |value| value[0] + 1)]
+            })
+        };
+        let batch = vec![(seq_key(5), Op::Merge(merge))];
+
+        // the merge function is only run once, by the same pass which builds
+        // the witness
+        let (_, witness) = merk.apply_with_witness(&batch).expect("apply failed");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(merk.get(&seq_key(5)).unwrap(), vec![124]);
+
+        let hash = apply_to_proof(&witness, &batch, prev_hash, &MerkOptions::default())
+            .expect("apply_to_proof failed");
+        assert_eq!(hash, merk.root_hash());
+
+        // a failed batch leaves the tree as it was
+        assert!(merk.apply_with_witness(&[(seq_key(1000), Op::Delete)]).is_err());
+        assert_eq!(merk.root_hash(), hash);
+        assert_eq!(merk.get(&seq_key(5)).unwrap(), vec![124]);
+    }
+
+    #[test]
+    fn apply_to_proof_missing_nodes() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..1000)).expect("apply failed");
+        let prev_hash = merk.root_hash();
+        let options = MerkOptions::default();
+
+        let batch = vec![(seq_key(5), Op::Put(vec![1]))];
+        let (_, witness) = merk.apply_with_witness(&batch).expect("apply failed");
+
+        // a batch touching other nodes needs a different witness
+        let other = vec![(seq_key(995), Op::Put(vec![1]))];
+        assert!(apply_to_proof(&witness, &other, prev_hash, &options).is_err());
+
+        // a batch which fails to apply also fails against the witness
+        let delete = vec![(seq_key(5000), Op::Delete)];
+        assert!(apply_to_proof(&witness, &delete, prev_hash, &options).is_err());
+
+        // the witness of an empty tree is empty
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        let (_, witness) = merk.apply_with_witness(&batch).expect("apply failed");
+        assert!(witness.is_empty());
+        let hash = apply_to_proof(&witness, &batch, NULL_HASH, &options)
+            .expect("apply_to_proof failed");
+        assert_eq!(hash, merk.root_hash());
+    }
+
+    /// Returns the range of the root node's record within a witness.
+    fn witness_root_record(witness: &[u8]) -> std::ops::Range<usize> {
+        let key_length = witness[0] as usize;
+        let start = 1 + key_length + 4;
+        let length = u32::from_be_bytes([
+            witness[start - 4],
+            witness[start - 3],
+            witness[start - 2],
+            witness[start - 1]
+        ]);
+        start..start + length as usize
+    }
+
+    #[test]
+    fn apply_to_proof_tampered_aggregates() {
+        let options = MerkOptions { counts: true, ..MerkOptions::default() };
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open_with_options(&path, options.clone())
+            .expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..100)).expect("apply failed");
+        let prev_hash = merk.root_hash();
+
+        // only the root is in the witness
+        let root_key = merk.tree().expect("expected tree").key().to_vec();
+        let batch = vec![(root_key, Op::Put(vec![1]))];
+        let (_, witness) = merk.apply_with_witness(&batch).expect("apply failed");
+        let hash = apply_to_proof(&witness, &batch, prev_hash, &options)
+            .expect("apply_to_proof failed");
+        assert_eq!(hash, merk.root_hash());
+
+        // move a node from the count of the root's left child to its right
+        // child, which keeps the root's count (and so its hash) the same
+        let record = witness_root_record(&witness);
+        let mut tampered = witness.clone();
+        let left = record.end - 16..record.end - 8;
+        let right = record.end - 8..record.end;
+        let count = u64::from_be_bytes(tampered[left.clone()].try_into().unwrap());
+        tampered[left].copy_from_slice(&(count - 1).to_be_bytes());
+        let count = u64::from_be_bytes(tampered[right.clone()].try_into().unwrap());
+        tampered[right].copy_from_slice(&(count + 1).to_be_bytes());
+        assert!(apply_to_proof(&tampered, &batch, prev_hash, &options).is_err());
+
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn apply_to_proof_tampered_heights() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..3)).expect("apply failed");
+        let prev_hash = merk.root_hash();
+        let options = MerkOptions::default();
+
+        // the batch makes the right side of the root 2 levels taller than its
+        // left side, so the root is rotated
+        let batch = make_batch_seq(3..6);
+        let (_, witness) = merk.apply_with_witness(&batch).expect("apply failed");
+        let hash = apply_to_proof(&witness, &batch, prev_hash, &options)
+            .expect("apply_to_proof failed");
+        assert_eq!(hash, merk.root_hash());
+
+        // claim the root's left child (which is not in the witness) is taller
+        // than it is. Heights are not committed to, so the witness still
+        // matches, but the root is not rotated and the resulting root hash is
+        // wrong (see the note on `apply_to_proof`)
+        let record = witness_root_record(&witness);
+        let value_length = witness[record.start] as usize
+            + ((witness[record.start + 1] as usize) << 8);
+        let link = record.start + 2 + value_length + 20;
+        let heights = link + 1 + witness[link] as usize + 20;
+        let mut tampered = witness.clone();
+        tampered[heights] += 1;
+        let hash = apply_to_proof(&tampered, &batch, prev_hash, &options)
+            .expect("apply_to_proof failed");
+        assert_ne!(hash, merk.root_hash());
+    }
+
+    #[test]
+    fn rollback() {
+        let path = thread::current().name().unwrap().to_owned();
+        let options = MerkOptions {
+            undo_depth: 3,
+            ..MerkOptions::default()
+        };
+        let mut merk = Merk::open_with_options(&path, options.clone())
+            .expect("failed to open merk");
+
+        let batches = vec![
+            make_batch_seq(0..100),
+            make_batch_rand(100, 0),
+            make_del_batch_seq(20..60),
+            make_batch_seq(50..70),
+            make_del_batch_rand(100, 0)
+        ];
+        let mut root_hashes = vec![merk.root_hash()];
+        for batch in batches.iter() {
+            merk.apply(batch).expect("apply failed");
+            root_hashes.push(merk.root_hash());
+        }
+        assert_eq!(merk.undo_len().unwrap(), 3);
+
+        merk.rollback(1).expect("rollback failed");
+        assert_eq!(merk.root_hash(), root_hashes[4]);
+        assert_eq!(merk.get(&make_batch_rand(100, 0)[0].0).unwrap(), vec![123; 60]);
+        assert_eq!(merk.verify_integrity().unwrap().node_count, 170);
+
+        merk.rollback(2).expect("rollback failed");
+        assert_eq!(merk.root_hash(), root_hashes[2]);
+        assert!(merk.get(&seq_key(30)).is_ok());
+        assert_eq!(merk.verify_integrity().unwrap().orphaned_keys.len(), 0);
+        assert!(merk.rollback(1).is_err());
+        assert_eq!(merk.undo_len().unwrap(), 0);
+
+        // commits after a rollback can also be rolled back
+        merk.apply(&make_del_batch_seq(0..100)).expect("apply failed");
+        assert_eq!(merk.undo_len().unwrap(), 1);
+        drop(merk);
+
+        let mut merk = Merk::open_with_options(&path, options)
+            .expect("failed to reopen merk");
+        merk.rollback(1).expect("rollback failed");
+        assert_eq!(merk.root_hash(), root_hashes[2]);
+        merk.apply(&make_del_batch_seq(0..50)).expect("apply failed");
+        drop(merk);
+
+        // lowering the depth drops the recorded commits
+        let mut merk = Merk::open(&path).expect("failed to reopen merk");
+        assert_eq!(merk.undo_len().unwrap(), 0);
+        assert!(merk.rollback(1).is_err());
+        merk.rollback(0).expect("rollback failed");
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn rollback_to_empty() {
+        let path = thread::current().name().unwrap().to_owned();
+        let options = MerkOptions {
+            undo_depth: 10,
+            ..MerkOptions::default()
+        };
+        let mut merk = Merk::open_with_options(&path, options)
+            .expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..10)).expect("apply failed");
+        merk.apply(&make_batch_seq(5..15)).expect("apply failed");
+
+        merk.rollback(2).expect("rollback failed");
+        assert_eq!(merk.root_hash(), NULL_HASH);
+        assert!(merk.get(&seq_key(0)).is_err());
+        assert_eq!(merk.verify_integrity().unwrap().node_count, 0);
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn gc() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = TempMerk::open(path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(1..20)).expect("apply failed");
+
+        // simulate records left behind by an old version
+        let mut bytes = vec![];
+        Tree::new(seq_key(0), vec![1]).encode_into(&mut bytes);
+        merk.db.put(seq_key(0), &bytes).unwrap();
+        merk.db.put(seq_key(100), &bytes).unwrap();
+
+        assert_eq!(merk.gc().expect("gc failed"), 2);
+        assert!(merk.get(&seq_key(0)).is_err());
+        assert!(merk.get(&seq_key(1)).is_ok());
+        assert_eq!(merk.gc().expect("gc failed"), 0);
+    }
+
+    fn first_byte(value: &[u8]) -> u64 {
+        value[0] as u64
+    }
+
+    #[test]
+    fn sums() {
+        let path = thread::current().name().unwrap().to_owned();
+        let options = MerkOptions {
+            sum_fn: Some(first_byte),
+            ..MerkOptions::default()
+        };
+        let mut merk = Merk::open_with_options(&path, options.clone())
+            .expect("failed to open merk");
+        assert_eq!(merk.sum(), Some(0));
+
+        let batch: Vec<_> = (0..100)
+            .map(|n| (seq_key(n), Op::Put(vec![n as u8])))
+            .collect();
+        merk.apply(&batch).expect("apply failed");
+        assert_eq!(merk.sum(), Some(4950));
+
+        merk.apply(&[
+            (seq_key(10), Op::Delete),
+            (seq_key(20), Op::Put(vec![0]))
+        ]).expect("apply failed");
+        merk.delete_range(&seq_key(90), &seq_key(100))
+            .expect("delete_range failed");
+        // 4950 - 10 - 20 - (90 + ... + 99)
+        assert_eq!(merk.sum(), Some(3975));
+
+        let root_hash = merk.root_hash();
+        let proof = merk.prove_sum(&seq_key(5), &seq_key(25))
+            .expect("prove_sum failed");
+        let sum = verify_sum_proof(&proof, &seq_key(5), &seq_key(25), root_hash)
+            .expect("verify_sum failed");
+        // 5 + ... + 24, without 10 and 20
+        assert_eq!(sum, 260);
+
+        // key proofs still verify against trees with sums
+        let proof = merk.prove(&[seq_key(5)]).expect("prove failed");
+        let values = verify_proof(&proof, &[seq_key(5)], root_hash)
+            .expect("verify failed");
+        assert_eq!(values, vec![Some(vec![5])]);
+
+        assert_eq!(merk.verify_integrity().expect("verify failed").node_count, 89);
+        drop(merk);
+
+        let merk = Merk::open_with_options(&path, options.clone())
+            .expect("failed to reopen merk");
+        assert_eq!(merk.sum(), Some(3975));
+        assert_eq!(merk.root_hash(), root_hash);
+        drop(merk);
+
+        // opening without the sum function fails
+        assert!(Merk::open(&path).is_err());
+
+        let merk = Merk::open_with_options(&path, options)
+            .expect("failed to reopen merk");
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    fn max_if_255(value: &[u8]) -> u64 {
+        match value[0] {
+            255 => u64::MAX,
+            n => n as u64
+        }
+    }
+
+    #[test]
+    fn sum_overflow() {
+        let path = thread::current().name().unwrap().to_owned();
+        let options = MerkOptions {
+            sum_fn: Some(max_if_255),
+            ..MerkOptions::default()
+        };
+        let mut merk = Merk::open_with_options(&path, options.clone())
+            .expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..10)).expect("apply failed");
+        let root_hash = merk.root_hash();
+
+        // the sum only overflows when the tree is committed
+        assert!(merk.apply(&[
+            (seq_key(1), Op::Put(vec![1])),
+            (seq_key(20), Op::Put(vec![255]))
+        ]).is_err());
+
+        // the tree is left as of the last commit
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.sum(), Some(1230));
+        assert_eq!(merk.get(&seq_key(1)).unwrap(), vec![123; 60]);
+        assert!(merk.get(&seq_key(20)).is_err());
+
+        merk.apply(&[(seq_key(1), Op::Put(vec![1]))]).expect("apply failed");
+        assert_eq!(merk.sum(), Some(1108));
+        assert_eq!(merk.verify_integrity().expect("verify failed").node_count, 10);
+        let root_hash = merk.root_hash();
+        drop(merk);
+
+        let merk = Merk::open_with_options(&path, options)
+            .expect("failed to reopen merk");
+        assert_eq!(merk.root_hash(), root_hash);
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn counts() {
+        let path = thread::current().name().unwrap().to_owned();
+        let options = MerkOptions {
+            counts: true,
+            ..MerkOptions::default()
+        };
+        let mut merk = Merk::open_with_options(&path, options.clone())
+            .expect("failed to open merk");
+        assert_eq!(merk.len().unwrap(), 0);
+        assert!(merk.is_empty());
+        assert_eq!(merk.rank(&seq_key(5)).unwrap(), 0);
+        assert!(merk.get_by_index(0).is_err());
+
+        merk.apply(&make_batch_seq(0..100)).expect("apply failed");
+        merk.apply(&make_del_batch_seq(10..20)).expect("apply failed");
+        merk.delete_range(&seq_key(50), &seq_key(60))
+            .expect("delete_range failed");
+        assert_eq!(merk.len().unwrap(), 80);
+        assert!(!merk.is_empty());
+
+        // keys are 0..10, 20..50 and 60..100
+        assert_eq!(merk.rank(&seq_key(5)).unwrap(), 5);
+        assert_eq!(merk.rank(&seq_key(15)).unwrap(), 10);
+        assert_eq!(merk.rank(&seq_key(60)).unwrap(), 40);
+        assert_eq!(merk.get_by_index(10).unwrap().0, seq_key(20));
+        assert_eq!(merk.get_by_index(79).unwrap().0, seq_key(99));
+        assert!(merk.get_by_index(80).is_err());
+
+        let root_hash = merk.root_hash();
+        let proof = merk.prove_len().expect("prove_len failed");
+        assert_eq!(verify_len_proof(&proof, root_hash).unwrap(), 80);
+        let proof = merk.prove_rank(&seq_key(60)).expect("prove_rank failed");
+        assert_eq!(verify_rank_proof(&proof, &seq_key(60), root_hash).unwrap(), 40);
+        let proof = merk.prove_index(40).expect("prove_index failed");
+        let (key, value) = verify_index_proof(&proof, 40, root_hash).unwrap();
+        assert_eq!(key, seq_key(60));
+        assert_eq!(value, vec![123; 60]);
+
+        assert_eq!(merk.verify_integrity().expect("verify failed").node_count, 80);
+        drop(merk);
+
+        let merk = Merk::open_with_options(&path, options.clone())
+            .expect("failed to reopen merk");
+        assert_eq!(merk.len().unwrap(), 80);
+        assert_eq!(merk.get_by_index(40).unwrap().0, seq_key(60));
+        assert_eq!(merk.root_hash(), root_hash);
+        drop(merk);
+
+        // opening with counts disabled fails
+        assert!(Merk::open(&path).is_err());
+
+        let merk = Merk::open_with_options(&path, options)
+            .expect("failed to reopen merk");
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn counts_and_sums() {
+        let options = MerkOptions {
+            counts: true,
+            sum_fn: Some(first_byte),
+            ..MerkOptions::default()
+        };
+        let path = thread::current().name().unwrap().to_owned();
+        assert!(Merk::open_with_options(path, options).is_err());
+    }
+
+    #[test]
+    fn prove_sum_without_sums() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..10)).expect("apply failed");
+        assert_eq!(merk.sum(), None);
+        assert!(merk.prove_sum(&seq_key(0), &seq_key(5)).is_err());
+        assert!(merk.len().is_err());
+        assert!(merk.rank(&seq_key(0)).is_err());
+        assert!(merk.prove_len().is_err());
+    }
+
+    #[test]
+    fn query_page() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        assert!(merk.query_page(None, Direction::Ascending, 10).is_err());
+
+        merk.apply(&make_batch_seq(0..100)).expect("apply failed");
+        merk.apply(&make_del_batch_seq(40..50)).expect("apply failed");
+        let root_hash = merk.root_hash();
+
+        let page_keys = |entries: &Vec<(Vec<u8>, Vec<u8>)>| -> Vec<Vec<u8>> {
+            entries.iter().map(|(key, _)| key.clone()).collect()
+        };
+        let cases: &[(Option<u64>, Direction, usize, Vec<u64>)] = &[
+            (None, Direction::Ascending, 3, vec![0, 1, 2]),
+            (Some(2), Direction::Ascending, 3, vec![3, 4, 5]),
+            (Some(38), Direction::Ascending, 3, vec![39, 50, 51]),
+            (Some(45), Direction::Ascending, 2, vec![50, 51]),
+            (Some(97), Direction::Ascending, 10, vec![98, 99]),
+            (Some(99), Direction::Ascending, 10, vec![]),
+            (None, Direction::Descending, 3, vec![99, 98, 97]),
+            (Some(51), Direction::Descending, 3, vec![50, 39, 38]),
+            (Some(2), Direction::Descending, 10, vec![1, 0]),
+            (Some(10), Direction::Ascending, 0, vec![])
+        ];
+        for (start, direction, limit, expected) in cases {
+            let start = start.map(seq_key);
+            let start = start.as_ref().map(|start| start.as_slice());
+            let (entries, proof) = merk.query_page(start, *direction, *limit)
+                .expect("query_page failed");
+            let expected: Vec<_> = expected.iter().map(|n| seq_key(*n)).collect();
+            assert_eq!(page_keys(&entries), expected);
+
+            let verified = verify_page_proof(&proof, start, *direction, *limit, root_hash)
+                .expect("verify failed");
+            assert_eq!(verified, entries);
+        }
+
+        // a proof of a page does not prove a longer page
+        let (_, proof) = merk.query_page(None, Direction::Ascending, 3)
+            .expect("query_page failed");
+        assert!(verify_page_proof(&proof, None, Direction::Ascending, 4, root_hash).is_err());
+    }
+
+    #[test]
+    fn prove_query() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        assert!(merk.prove_query(&Query::new()).is_err());
+
+        merk.apply(&make_batch_rand(200, 1)).expect("apply failed");
+        let root_hash = merk.root_hash();
+        let mut keys: Vec<Vec<u8>> = make_batch_rand(200, 1).into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        keys.sort();
+
+        let mut rng: SmallRng = SeedableRng::seed_from_u64(2);
+        for _ in 0..50 {
+            let mut query = Query::new();
+            for _ in 0..rng.gen_range(0, 5) {
+                let mut a = keys[rng.gen_range(0, keys.len())].clone();
+                let mut b = keys[rng.gen_range(0, keys.len())].clone();
+                if rng.gen() {
+                    // a key which is not in the store
+                    a.push(0);
+                }
+                if a > b {
+                    std::mem::swap(&mut a, &mut b);
+                }
+                match rng.gen_range(0, 6) {
+                    0 => query.insert_key(a),
+                    1 => query.insert_range(a..b),
+                    2 => query.insert_range_inclusive(a..=b),
+                    3 => query.insert_range_from(b..),
+                    4 => query.insert_range_to(..a),
+                    _ => query.insert_prefix(a[..1].to_vec())
+                }
+            }
+
+            let proof = merk.prove_query(&query).expect("prove_query failed");
+            let entries = verify_query_proof(&proof, &query, root_hash)
+                .expect("verify failed");
+            let expected: Vec<_> = keys.iter()
+                .filter(|key| query.contains(key))
+                .cloned()
+                .collect();
+            let proven: Vec<_> = entries.into_iter()
+                .map(|(key, _)| key)
+                .collect();
+            assert_eq!(proven, expected);
+        }
+    }
+
+    fn assert_corrupted<F: FnOnce(&Merk)>(path: String, corrupt: F) {
+        let mut merk = Merk::open(&path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..20)).expect("apply failed");
+        corrupt(&merk);
+        drop(merk);
+
+        match Merk::open(&path) {
+            Err(err) => match err.kind() {
+                ErrorKind::Corruption(_) => {},
+                _ => panic!("expected corruption error, got {}", err)
+            },
+            Ok(_) => panic!("expected corruption error")
+        };
+
+        let opts = MerkOptions::default().to_db_opts();
+        rocksdb::DB::destroy(&opts, &path).expect("failed to destroy db");
+    }
+
+    #[test]
+    fn missing_child_node() {
+        let path = thread::current().name().unwrap().to_owned();
+        assert_corrupted(path, |merk| {
+            let child_key = merk.tree().unwrap().link(true).unwrap().key();
+            merk.db.delete(child_key).unwrap();
+        });
+    }
+
+    fn assert_unsupported_version(path: &str, expected: u32) {
+        match Merk::open(path) {
+            Err(err) => match err.kind() {
+                ErrorKind::UnsupportedVersion(version) => assert_eq!(*version, expected),
+                _ => panic!("expected unsupported version error, got {}", err)
+            },
+            Ok(_) => panic!("expected unsupported version error")
+        };
+    }
+
+    fn put_format_version(path: &str, version: u32) {
+        let opts = MerkOptions::default().to_db_opts();
+        let db = rocksdb::DB::open_cf(&opts, path, &[UNDO_CF]).unwrap();
+        db.put(FORMAT_VERSION_KEY, version.to_be_bytes()).unwrap();
+    }
+
+    #[test]
+    fn format_version() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open(&path).expect("failed to open merk");
+        let version = merk.db.get_pinned(FORMAT_VERSION_KEY).unwrap().unwrap().to_vec();
+        assert_eq!(version, FORMAT_VERSION.to_be_bytes().to_vec());
+        merk.apply(&make_batch_seq(0..20)).expect("apply failed");
+        let root_hash = merk.root_hash();
+
+        // a store written before versions were recorded is not read as
+        // corrupted
+        merk.db.delete(FORMAT_VERSION_KEY).unwrap();
+        drop(merk);
+        assert_unsupported_version(&path, 0);
+
+        // neither is a store written in a later version
+        put_format_version(&path, 2);
+        assert_unsupported_version(&path, 2);
+
+        put_format_version(&path, FORMAT_VERSION);
+        let merk = Merk::open(&path).expect("failed to reopen merk");
+        assert_eq!(merk.root_hash(), root_hash);
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    /// Rewrites a store in the format used before format versions were
+    /// recorded.
+    fn downgrade(merk: &Merk) {
+        let mut batch = rocksdb::WriteBatch::default();
+        rehash(&merk.db, &mut batch, kv_hash, legacy_kv_hash, &merk.options)
+            .expect("rehash failed");
+        merk.db.batch_delete(&mut batch, FORMAT_VERSION_KEY).unwrap();
+        merk.db.write(batch, &merk.options.to_write_opts()).unwrap();
+    }
+
+    #[test]
+    fn migrate() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open(&path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..20)).expect("apply failed");
+        downgrade(&merk);
+
+        // the root hash of the same store before format version 1
+        let legacy_hash = merk.db.get_pinned(ROOT_HASH_KEY).unwrap().unwrap().to_vec();
+        assert_eq!(legacy_hash, vec![217, 218, 163, 74, 119, 133, 165, 247, 140, 194, 85, 70, 28, 33, 61, 148, 118, 231, 134, 111]);
+
+        // older versions left the records of some deleted nodes behind
+        let mut bytes = vec![];
+        Tree::new(seq_key(100), vec![1]).encode_into(&mut bytes);
+        merk.db.put(seq_key(100), &bytes).unwrap();
+        drop(merk);
+        assert_unsupported_version(&path, 0);
+
+        let merk = Merk::migrate(&path, MerkOptions::default())
+            .expect("migrate failed");
+        assert_eq!(merk.root_hash(), [77, 234, 38, 192, 101, 57, 134, 88, 8, 29, 124, 14, 45, 58, 89, 113, 143, 157, 13, 91]);
+        assert_eq!(merk.get(&seq_key(5)).unwrap(), vec![123; 60]);
+        assert!(merk.get(&seq_key(100)).is_err());
+        let report = merk.verify_integrity().expect("verify failed");
+        assert_eq!(report.node_count, 20);
+        assert!(report.orphaned_keys.is_empty());
+        drop(merk);
+
+        // the upgraded store opens normally, and is not migrated again
+        let merk = Merk::open(&path).expect("failed to reopen merk");
+        let root_hash = merk.root_hash();
+        drop(merk);
+        let merk = Merk::migrate(&path, MerkOptions::default())
+            .expect("migrate failed");
+        assert_eq!(merk.root_hash(), root_hash);
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn migrate_with_aggregates() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open(&path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..20)).expect("apply failed");
+        downgrade(&merk);
+        drop(merk);
+
+        // sums or counts can be added while upgrading
+        let options = MerkOptions { counts: true, ..MerkOptions::default() };
+        let merk = Merk::migrate(&path, options.clone())
+            .expect("migrate failed");
+        assert_eq!(merk.len().unwrap(), 20);
+        assert_eq!(merk.verify_integrity().expect("verify failed").node_count, 20);
+
+        // the same as a store created with counts
+        let mut expected = Merk::open_with_options(format!("{}-expected", path), options)
+            .expect("failed to open merk");
+        expected.apply(&make_batch_seq(0..20)).expect("apply failed");
+        assert_eq!(merk.root_hash(), expected.root_hash());
+        expected.destroy().expect("failed to destroy merk");
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn migrate_corrupted() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open(&path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..20)).expect("apply failed");
+        downgrade(&merk);
+
+        // a value which does not match its kv_hash in the old format
+        let node = merk.tree().expect("expected tree").clone().with_value(vec![1]);
+        let mut bytes = vec![];
+        node.encode_into(&mut bytes);
+        merk.db.put(node.key(), &bytes).unwrap();
+        drop(merk);
+
+        match Merk::migrate(&path, MerkOptions::default()) {
+            Err(err) => match err.kind() {
+                ErrorKind::Corruption(_) => {},
+                _ => panic!("expected corruption error, got {}", err)
+            },
+            Ok(_) => panic!("expected corruption error")
+        };
+
+        // nothing was written
+        assert_unsupported_version(&path, 0);
+        let opts = MerkOptions::default().to_db_opts();
+        rocksdb::DB::destroy(&opts, &path).expect("failed to destroy db");
+    }
+
+    #[test]
+    fn concurrent_proofs() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open(&path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..1000)).expect("apply failed");
+        drop(merk);
+
+        // after reopening, only the root node is in memory
+        let merk = Arc::new(Merk::open(&path).expect("failed to reopen merk"));
+        let root_hash = merk.root_hash();
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let merk = merk.clone();
+                thread::spawn(move || {
+                    for j in 0..10 {
+                        let keys = vec![seq_key(i * 250 + j), seq_key(i * 250 + j + 100)];
+                        let proof = merk.prove(&keys).expect("prove failed");
+                        let values = verify_proof(&proof, &keys, root_hash)
+                            .expect("verify failed");
+                        assert!(values.iter().all(|value| value.is_some()));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().expect("thread panicked");
+        }
+
+        // proving does not load fetched nodes into the tree
+        let tree = merk.tree().unwrap();
+        assert!(tree.child(true).is_none());
+        assert!(tree.child(false).is_none());
+
+        let merk = Arc::try_unwrap(merk).ok().expect("merk is still shared");
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn wrong_root_hash() {
+        let path = thread::current().name().unwrap().to_owned();
+        assert_corrupted(path, |merk| {
+            merk.db.put(ROOT_HASH_KEY, [0; 20]).unwrap();
+        });
+    }
 }
@@ -0,0 +1,442 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::error::{ErrorKind, Result};
+use crate::proofs::encode_pair;
+use crate::tree::{Tree, Batch, Op, OpFailure, SortedBatch, Hash, check_sorted};
+use super::{Merk, MerkOptions, is_metadata_key};
+use super::column::Column;
+
+/// The skipped conditional operations of each batch applied to a `MultiMerk`.
+type Failures = Vec<Vec<(Vec<u8>, OpFailure)>>;
+
+/// A set of independent, named `Merk` trees (subtrees) kept in a single RocksDB
+/// database with a column family each, which are committed atomically and
+/// summarized by a single root hash.
+///
+/// The combined root hash is the root hash of a small root tree, kept in the
+/// default column family, which maps the name of each subtree to the
+/// subtree's root hash. Keys in a subtree are proven against the combined root
+/// hash with a two-layer proof (see `MultiMerk::prove`).
+pub struct MultiMerk {
+    root: Merk,
+    subtrees: BTreeMap<String, Merk>,
+    path: PathBuf
+}
+
+impl MultiMerk {
+    /// Opens a store with the given subtrees at the specified file path. If no
+    /// store exists at that path, one will be created. A store must always be
+    /// opened with all of its subtrees.
+    pub fn open<P: AsRef<Path>>(path: P, names: &[&str]) -> Result<MultiMerk> {
+        MultiMerk::open_with_options(path, names, MerkOptions::default())
+    }
+
+    /// Opens a store with the given subtrees at the specified file path, with
+    /// the given options (see `Merk::open_with_options`). The options apply to
+    /// every subtree, while the root tree never maintains sums or counts.
+    /// Commits to a `MultiMerk` cannot be rolled back, so `undo_depth` is
+    /// ignored.
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        names: &[&str],
+        options: MerkOptions
+    ) -> Result<MultiMerk> {
+        options.check()?;
+
+        for (i, name) in names.iter().enumerate() {
+            if name.is_empty() || *name == "default" {
+                bail!("Invalid subtree name: {:?}", name);
+            }
+            if names[..i].contains(name) {
+                bail!("Subtree {} is given more than once", name);
+            }
+        }
+
+        let mut db_opts = options.to_db_opts();
+        db_opts.create_missing_column_families(true);
+        let mut path_buf = PathBuf::new();
+        path_buf.push(path);
+        let db = Arc::new(rocksdb::DB::open_cf(&db_opts, &path_buf, names)?);
+
+        let root_options = MerkOptions {
+            sum_fn: None,
+            counts: false,
+            ..options.clone()
+        };
+        let root = Merk::open_column(
+            Column::default(db.clone()),
+            path_buf.clone(),
+            root_options,
+            None
+        )?;
+
+        let mut subtrees = BTreeMap::new();
+        for name in names {
+            let subtree = Merk::open_column(
+                Column::named(db.clone(), name)?,
+                path_buf.clone(),
+                options.clone(),
+                None
+            )?;
+            subtrees.insert(name.to_string(), subtree);
+        }
+
+        let mut multi = MultiMerk { root, subtrees, path: path_buf };
+        multi.load_roots()?;
+        Ok(multi)
+    }
+
+    /// Checks the subtree root hashes recorded in the root tree against the
+    /// subtrees, and records the root hashes of any subtrees which were added
+    /// since the store was last opened.
+    fn load_roots(&mut self) -> Result<()> {
+        let mut recorded = BTreeMap::new();
+        for (key, value) in self.root.db.iterator(rocksdb::IteratorMode::Start)? {
+            if is_metadata_key(&key) {
+                continue;
+            }
+            let node = Tree::decode(&key, &value)?;
+            recorded.insert(key.to_vec(), node.value().to_vec());
+        }
+
+        let mut added = vec![];
+        for (name, subtree) in self.subtrees.iter() {
+            match recorded.remove(name.as_bytes()) {
+                None => added.push((
+                    name.as_bytes().to_vec(),
+                    Op::Put(subtree.root_hash().to_vec())
+                )),
+                Some(hash) => if hash[..] != subtree.root_hash()[..] {
+                    bail!(ErrorKind::Corruption(format!(
+                        "Root hash of subtree {} does not match the root tree",
+                        name
+                    )));
+                }
+            }
+        }
+
+        if let Some(name) = recorded.keys().next() {
+            bail!(
+                "Subtree {} was not opened",
+                String::from_utf8_lossy(name)
+            );
+        }
+
+        if !added.is_empty() {
+            self.root.apply_sorted(SortedBatch::new_unchecked(added))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the combined root hash of the store, which commits to the root
+    /// hash of every subtree.
+    pub fn root_hash(&self) -> Hash {
+        self.root.root_hash()
+    }
+
+    /// Returns the names of the subtrees, in sorted order.
+    pub fn names(&self) -> impl Iterator<Item=&str> {
+        self.subtrees.keys().map(String::as_str)
+    }
+
+    /// Returns the subtree with the given name, for reading. Subtrees can only
+    /// be written through `MultiMerk::apply`, which keeps the root tree up to
+    /// date.
+    pub fn subtree(&self, name: &str) -> Result<&Merk> {
+        match self.subtrees.get(name) {
+            None => bail!("Unknown subtree: {}", name),
+            Some(subtree) => Ok(subtree)
+        }
+    }
+
+    /// Gets a value for the given key in the named subtree. Returns an `Err`
+    /// if the key is not found or something else goes wrong.
+    pub fn get(&self, name: &str, key: &[u8]) -> Result<Vec<u8>> {
+        self.subtree(name)?.get(key)
+    }
+
+    /// Applies batches of operations to any number of subtrees, given as pairs
+    /// of subtree name and batch, and updates the root tree. All of the changes
+    /// are written to the database atomically.
+    ///
+    /// Returns the skipped conditional operations of each batch (see
+    /// `Merk::apply`), in the same order as the batches. This will fail if a
+    /// subtree does not exist or is given more than once, or if the keys of a
+    /// batch are not sorted and unique.
+    pub fn apply(
+        &mut self,
+        batches: &[(&str, &Batch)]
+    ) -> Result<Failures> {
+        for (i, (name, batch)) in batches.iter().enumerate() {
+            if !self.subtrees.contains_key(*name) {
+                bail!("Unknown subtree: {}", name);
+            }
+            if batches[..i].iter().any(|(other, _)| other == name) {
+                bail!("Subtree {} is given more than once", name);
+            }
+            check_sorted(batch)?;
+        }
+
+        // the trees are updated in memory before anything is written, so if
+        // a batch fails, every tree is restored to the last commit
+        let prev_root = self.root.tree.clone();
+        let prev_subtrees: Vec<_> = batches.iter()
+            .map(|(name, _)| (*name, self.subtrees[*name].tree.clone()))
+            .collect();
+
+        match self.apply_batches(batches) {
+            Ok(failures) => Ok(failures),
+            Err(err) => {
+                self.root.tree = prev_root;
+                for (name, tree) in prev_subtrees {
+                    self.subtrees.get_mut(name).unwrap().tree = tree;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Applies checked batches to the subtrees and the root tree, and writes
+    /// them to the database at once. If this fails, the trees may have been
+    /// partially updated.
+    fn apply_batches(
+        &mut self,
+        batches: &[(&str, &Batch)]
+    ) -> Result<Failures> {
+        let mut write_batch = rocksdb::WriteBatch::default();
+        let mut failures = Vec::with_capacity(batches.len());
+        let mut root_batch = Vec::with_capacity(batches.len());
+        for (name, batch) in batches.iter() {
+            let subtree = self.subtrees.get_mut(*name).unwrap();
+            let batch = SortedBatch::new_unchecked(batch.to_vec());
+            let (deleted_keys, subtree_failures) = subtree.apply_to_tree(batch)?;
+            subtree.commit_into(&mut write_batch, deleted_keys)?;
+
+            failures.push(subtree_failures);
+            root_batch.push((
+                name.as_bytes().to_vec(),
+                Op::Put(subtree.root_hash().to_vec())
+            ));
+        }
+
+        root_batch.sort_by(|a, b| a.0.cmp(&b.0));
+        let root_batch = SortedBatch::new_unchecked(root_batch);
+        let (deleted_keys, _) = self.root.apply_to_tree(root_batch)?;
+        self.root.commit_into(&mut write_batch, deleted_keys)?;
+
+        // write the subtrees and the root tree at once
+        let opts = self.root.options.to_write_opts();
+        self.root.db.write(write_batch, &opts)?;
+
+        Ok(failures)
+    }
+
+    /// Creates a Merkle proof for the list of queried keys in the named
+    /// subtree, which can be verified against the combined root hash with
+    /// `merk::verify_multi_proof`.
+    ///
+    /// The proof contains a proof of the subtree's root hash in the root tree,
+    /// followed by a proof of the keys in the subtree (see `Merk::prove`). This
+    /// will fail if the subtree is empty, or if the keys in `keys` are not
+    /// sorted and unique.
+    pub fn prove(&self, name: &str, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+        let subtree_proof = match self.subtrees.get(name) {
+            None => bail!("Unknown subtree: {}", name),
+            Some(subtree) => subtree.prove(keys)?
+        };
+        let root_proof = self.root.prove(&[name.as_bytes().to_vec()])?;
+
+        Ok(encode_pair(&root_proof, &subtree_proof))
+    }
+
+    /// Closes the store and deletes all data from disk.
+    pub fn destroy(self) -> Result<()> {
+        let opts = self.root.options.to_db_opts();
+        let path = self.path.clone();
+        drop(self);
+        rocksdb::DB::destroy(&opts, &path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use crate::test_utils::*;
+    use crate::{Op, OpFailure, ErrorKind, verify_multi_proof};
+    use super::*;
+
+    #[test]
+    fn multi_merk() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut multi = MultiMerk::open(&path, &["bank", "gov"])
+            .expect("failed to open multi merk");
+        assert_eq!(multi.names().collect::<Vec<_>>(), vec!["bank", "gov"]);
+        let empty_hash = multi.root_hash();
+
+        let failures = multi.apply(&[
+            ("gov", &make_batch_seq(0..10)),
+            ("bank", &[
+                (b"alice".to_vec(), Op::Put(vec![1])),
+                (b"bob".to_vec(), Op::PutIfAbsent(vec![2]))
+            ])
+        ]).expect("apply failed");
+        assert_eq!(failures, vec![vec![], vec![]]);
+        assert_ne!(multi.root_hash(), empty_hash);
+
+        let failures = multi.apply(&[
+            ("bank", &[(b"bob".to_vec(), Op::PutIfAbsent(vec![3]))])
+        ]).expect("apply failed");
+        assert_eq!(failures, vec![vec![(b"bob".to_vec(), OpFailure::KeyExists)]]);
+
+        // subtrees are independent
+        assert_eq!(multi.get("bank", b"bob").unwrap(), vec![2]);
+        assert!(multi.get("gov", b"bob").is_err());
+        assert_eq!(multi.get("gov", &seq_key(5)).unwrap(), vec![123; 60]);
+        assert_eq!(multi.subtree("gov").unwrap().verify_integrity().unwrap().node_count, 10);
+
+        // the combined root is the root of a tree of subtree root hashes
+        let mut expected = TempMerk::new().expect("failed to open merk");
+        expected.apply(&[
+            (b"bank".to_vec(), Op::Put(multi.subtree("bank").unwrap().root_hash().to_vec())),
+            (b"gov".to_vec(), Op::Put(multi.subtree("gov").unwrap().root_hash().to_vec()))
+        ]).expect("apply failed");
+        assert_eq!(multi.root_hash(), expected.root_hash());
+
+        let root_hash = multi.root_hash();
+        drop(multi);
+
+        let multi = MultiMerk::open(&path, &["gov", "bank"])
+            .expect("failed to reopen multi merk");
+        assert_eq!(multi.root_hash(), root_hash);
+        assert_eq!(multi.get("bank", b"alice").unwrap(), vec![1]);
+        drop(multi);
+
+        // every subtree must be opened
+        assert!(MultiMerk::open(&path, &["bank"]).is_err());
+
+        // new subtrees are added to the root tree
+        let multi = MultiMerk::open(&path, &["bank", "gov", "staking"])
+            .expect("failed to reopen multi merk");
+        assert_ne!(multi.root_hash(), root_hash);
+        multi.destroy().expect("failed to destroy multi merk");
+    }
+
+    #[test]
+    fn multi_merk_invalid() {
+        let path = thread::current().name().unwrap().to_owned();
+        assert!(MultiMerk::open(&path, &["bank", "bank"]).is_err());
+        assert!(MultiMerk::open(&path, &["default"]).is_err());
+        assert!(MultiMerk::open(&path, &[""]).is_err());
+
+        let mut multi = MultiMerk::open(&path, &["bank", "gov"])
+            .expect("failed to open multi merk");
+        let batch = make_batch_seq(0..10);
+        assert!(multi.apply(&[("staking", &batch)]).is_err());
+        assert!(multi.apply(&[("bank", &batch), ("bank", &batch)]).is_err());
+
+        let mut unsorted = make_batch_seq(0..10);
+        unsorted.reverse();
+        assert!(multi.apply(&[("bank", &batch), ("gov", &unsorted)]).is_err());
+
+        // nothing was applied since the batches were invalid
+        assert!(multi.get("bank", &seq_key(0)).is_err());
+        multi.destroy().expect("failed to destroy multi merk");
+    }
+
+    #[test]
+    fn multi_merk_failed_apply() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut multi = MultiMerk::open(&path, &["bank", "gov"])
+            .expect("failed to open multi merk");
+        multi.apply(&[
+            ("bank", &make_batch_seq(0..10)),
+            ("gov", &make_batch_seq(0..10))
+        ]).expect("apply failed");
+        let root_hash = multi.root_hash();
+        let gov_hash = multi.subtree("gov").unwrap().root_hash();
+
+        // the first batch is valid, but the second fails while being applied
+        assert!(multi.apply(&[
+            ("bank", &make_batch_seq(10..20)),
+            ("gov", &[(seq_key(100), Op::Delete)])
+        ]).is_err());
+
+        // neither subtree was changed
+        assert_eq!(multi.root_hash(), root_hash);
+        assert_eq!(multi.subtree("gov").unwrap().root_hash(), gov_hash);
+        assert!(multi.get("bank", &seq_key(10)).is_err());
+        assert_eq!(multi.get("gov", &seq_key(5)).unwrap(), vec![123; 60]);
+        let keys = vec![seq_key(5)];
+        let proof = multi.prove("gov", &keys).expect("prove failed");
+        verify_multi_proof(&proof, "gov", &keys, root_hash).expect("verify failed");
+
+        // a later commit does not include the failed batch
+        multi.apply(&[("gov", &make_batch_seq(20..30))]).expect("apply failed");
+        assert!(multi.get("bank", &seq_key(10)).is_err());
+        let root_hash = multi.root_hash();
+        drop(multi);
+
+        let multi = MultiMerk::open(&path, &["bank", "gov"])
+            .expect("failed to reopen multi merk");
+        assert_eq!(multi.root_hash(), root_hash);
+        assert_eq!(multi.get("gov", &seq_key(5)).unwrap(), vec![123; 60]);
+        assert_eq!(multi.subtree("gov").unwrap().verify_integrity().unwrap().node_count, 20);
+        multi.destroy().expect("failed to destroy multi merk");
+    }
+
+    #[test]
+    fn multi_merk_corrupted_root() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut multi = MultiMerk::open(&path, &["bank", "gov"])
+            .expect("failed to open multi merk");
+        multi.apply(&[("bank", &make_batch_seq(0..10))]).expect("apply failed");
+
+        // write to a subtree without updating the root tree
+        let subtree = multi.subtrees.get_mut("bank").unwrap();
+        subtree.apply(&make_batch_seq(10..20)).expect("apply failed");
+        drop(multi);
+
+        match MultiMerk::open(&path, &["bank", "gov"]) {
+            Err(err) => match err.kind() {
+                ErrorKind::Corruption(_) => {},
+                _ => panic!("expected corruption error, got {}", err)
+            },
+            Ok(_) => panic!("expected corruption error")
+        };
+
+        let opts = MerkOptions::default().to_db_opts();
+        rocksdb::DB::destroy(&opts, &path).expect("failed to destroy db");
+    }
+
+    #[test]
+    fn multi_proofs() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut multi = MultiMerk::open(&path, &["bank", "gov"])
+            .expect("failed to open multi merk");
+        assert!(multi.prove("bank", &[seq_key(0)]).is_err());
+
+        multi.apply(&[
+            ("bank", &make_batch_seq(0..100)),
+            ("gov", &make_batch_seq(50..60))
+        ]).expect("apply failed");
+        let root_hash = multi.root_hash();
+
+        let keys = vec![seq_key(5), seq_key(150)];
+        let proof = multi.prove("bank", &keys).expect("prove failed");
+        let values = verify_multi_proof(&proof, "bank", &keys, root_hash)
+            .expect("verify failed");
+        assert_eq!(values, vec![Some(vec![123; 60]), None]);
+
+        // the proof does not prove keys in another subtree
+        assert!(verify_multi_proof(&proof, "gov", &keys, root_hash).is_err());
+
+        // a truncated proof is invalid
+        assert!(verify_multi_proof(&proof[..proof.len() - 1], "bank", &keys, root_hash).is_err());
+        assert!(verify_multi_proof(&proof[..3], "bank", &keys, root_hash).is_err());
+
+        assert!(multi.prove("staking", &keys).is_err());
+        multi.destroy().expect("failed to destroy multi merk");
+    }
+}
@@ -0,0 +1,178 @@
+use crate::error::Result;
+use super::cache::DEFAULT_CACHE_CAPACITY;
+
+/// Returns the amount a value contributes to the sums maintained by a store,
+/// see `MerkOptions::sum_fn`.
+pub type SumFn = fn(&[u8]) -> u64;
+
+/// Compression algorithms which RocksDB can apply to data blocks. Note that an
+/// algorithm must have been compiled into RocksDB to be usable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Snappy,
+    Zlib,
+    Bz2,
+    Lz4,
+    Lz4hc,
+    Zstd
+}
+
+/// Tuning and durability options for a `Merk` store, passed to
+/// `Merk::open_with_options`.
+///
+/// The defaults favor speed over durability: commits are written without the
+/// RocksDB write-ahead log and without syncing, so the last commits may be
+/// lost on a crash or power loss. See `MerkOptions::durable` for crash-safe
+/// commits.
+///
+/// # Example
+/// ```
+/// # use merk::MerkOptions;
+/// let options = MerkOptions {
+///     wal: true,
+///     sync_writes: true,
+///     ..MerkOptions::default()
+/// };
+/// ```
+#[derive(Clone, Debug)]
+pub struct MerkOptions {
+    /// Size in bytes of RocksDB's LRU block cache. `None` uses the RocksDB
+    /// default.
+    pub block_cache_size: Option<usize>,
+
+    /// Compression applied to data blocks. `None` uses the RocksDB default.
+    pub compression: Option<Compression>,
+
+    /// Whether commits are written to the RocksDB write-ahead log.
+    pub wal: bool,
+
+    /// Whether commits are synced to disk before `apply` returns. Requires
+    /// `wal` to be enabled.
+    pub sync_writes: bool,
+
+    /// Bits per key of the bloom filter used for point lookups. `None`
+    /// disables bloom filters.
+    pub bloom_filter_bits: Option<i32>,
+
+    /// Whether RocksDB reads and writes files through mmap.
+    pub mmap: bool,
+
+    /// The maximum number of decoded nodes kept in the node cache.
+    pub node_cache_capacity: usize,
+
+    /// If set, every node keeps the sum of this function over the values in
+    /// its subtree, which is included in its hash so that sums over key
+    /// ranges can be proven (see `Merk::prove_sum`). A store must always be
+    /// opened with the same function it was created with. A batch which would
+    /// make the sum of the tree overflow a `u64` fails.
+    ///
+    /// Cannot be combined with `counts`. Node hashes and records can commit to
+    /// both, but every proof node carries at most one aggregate (such as
+    /// `HashWithSum` or `HashWithCount`), so proofs of a store maintaining
+    /// both could not be verified until proof nodes carrying both are added.
+    pub sum_fn: Option<SumFn>,
+
+    /// Whether every node keeps the number of nodes in its subtree, which is
+    /// included in its hash so that `Merk::len`, `Merk::rank` and
+    /// `Merk::get_by_index` can be answered in O(log n) and proven. A store
+    /// must always be opened with the same setting it was created with, and
+    /// cannot maintain both counts and sums (see `sum_fn`).
+    pub counts: bool,
+
+    /// The number of most recent commits which can be undone with
+    /// `Merk::rollback`. Each recorded commit keeps the previous versions of
+    /// the records it overwrote or deleted. `0` disables rollback.
+    pub undo_depth: usize
+}
+
+impl Default for MerkOptions {
+    fn default() -> Self {
+        MerkOptions {
+            block_cache_size: None,
+            compression: None,
+            wal: false,
+            sync_writes: false,
+            bloom_filter_bits: None,
+            mmap: true,
+            node_cache_capacity: DEFAULT_CACHE_CAPACITY,
+            sum_fn: None,
+            counts: false,
+            undo_depth: 0
+        }
+    }
+}
+
+impl MerkOptions {
+    /// Returns the default options, but with crash-safe commits: each commit
+    /// is written to the write-ahead log and synced to disk before `apply`
+    /// returns, so a committed batch survives a crash or power loss.
+    pub fn durable() -> Self {
+        MerkOptions {
+            wal: true,
+            sync_writes: true,
+            ..MerkOptions::default()
+        }
+    }
+
+    /// Checks that the options are consistent with each other.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.sync_writes && !self.wal {
+            bail!("Sync writes require the write-ahead log to be enabled");
+        }
+        if self.sum_fn.is_some() && self.counts {
+            bail!("Store cannot maintain both sums and counts, since proofs cannot carry both");
+        }
+        Ok(())
+    }
+
+    /// Creates the RocksDB options used to open the store.
+    pub(crate) fn to_db_opts(&self) -> rocksdb::Options {
+        let mut opts = rocksdb::Options::default();
+        opts.create_if_missing(true);
+        opts.increase_parallelism(num_cpus::get() as i32);
+        // opts.set_advise_random_on_open(false);
+        opts.set_allow_mmap_writes(self.mmap);
+        opts.set_allow_mmap_reads(self.mmap);
+
+        if let Some(compression) = self.compression {
+            opts.set_compression_type(compression.into());
+        }
+
+        if self.block_cache_size.is_some() || self.bloom_filter_bits.is_some() {
+            let mut block_opts = rocksdb::BlockBasedOptions::default();
+            if let Some(size) = self.block_cache_size {
+                block_opts.set_lru_cache(size);
+            }
+            if let Some(bits) = self.bloom_filter_bits {
+                block_opts.set_bloom_filter(bits, false);
+            }
+            opts.set_block_based_table_factory(&block_opts);
+        }
+
+        opts
+    }
+
+    /// Creates the RocksDB write options used when committing.
+    pub(crate) fn to_write_opts(&self) -> rocksdb::WriteOptions {
+        let mut opts = rocksdb::WriteOptions::default();
+        opts.set_sync(self.sync_writes);
+        opts.disable_wal(!self.wal);
+        opts
+    }
+}
+
+impl From<Compression> for rocksdb::DBCompressionType {
+    fn from(compression: Compression) -> Self {
+        use rocksdb::DBCompressionType as C;
+        match compression {
+            Compression::None => C::None,
+            Compression::Snappy => C::Snappy,
+            Compression::Zlib => C::Zlib,
+            Compression::Bz2 => C::Bz2,
+            Compression::Lz4 => C::Lz4,
+            Compression::Lz4hc => C::Lz4hc,
+            Compression::Zstd => C::Zstd
+        }
+    }
+}
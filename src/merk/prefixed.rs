@@ -0,0 +1,183 @@
+use crate::error::Result;
+use crate::proofs::Query;
+use crate::tree::{Batch, SortedBatch, OpFailure, Tree, check_sorted};
+use super::{Merk, is_metadata_key};
+
+/// A view of the keys of a `Merk` which start with a given prefix, created
+/// with `Merk::prefixed`. Keys passed to and returned from the view are
+/// relative to the prefix, which is added and removed transparently, so that
+/// several independent namespaces can share one store (and one root hash).
+pub struct Prefixed<'a> {
+    merk: &'a mut Merk,
+    prefix: Vec<u8>
+}
+
+impl<'a> Prefixed<'a> {
+    pub(super) fn new(merk: &'a mut Merk, prefix: &[u8]) -> Self {
+        Prefixed { merk, prefix: prefix.to_vec() }
+    }
+
+    /// Returns the prefix of the keys in this view.
+    pub fn prefix(&self) -> &[u8] {
+        self.prefix.as_slice()
+    }
+
+    /// Gets a value for the given key, relative to the prefix. Returns an `Err`
+    /// if the key is not found or something else goes wrong.
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.merk.get(&self.prefixed_key(key)?)
+    }
+
+    /// Applies a batch of operations (puts and deletes) whose keys are relative
+    /// to the prefix. Otherwise the same as `Merk::apply`, with the keys of any
+    /// returned failures also relative to the prefix.
+    ///
+    /// This will fail if the keys in `batch` are not sorted and unique, or if
+    /// any key would be too long once prefixed.
+    pub fn apply(&mut self, batch: &Batch) -> Result<Vec<(Vec<u8>, OpFailure)>> {
+        check_sorted(batch)?;
+
+        let mut prefixed = Vec::with_capacity(batch.len());
+        for (key, op) in batch.iter() {
+            prefixed.push((self.prefixed_key(key)?, op.clone()));
+        }
+
+        let failures = self.merk.apply_sorted(SortedBatch::new_unchecked(prefixed))?;
+        Ok(failures.into_iter()
+            .map(|(key, failure)| (key[self.prefix.len()..].to_vec(), failure))
+            .collect())
+    }
+
+    /// Returns an iterator over the key/value pairs in this view, in key order,
+    /// with keys relative to the prefix. Entries are read directly from the
+    /// backing store.
+    pub fn iter(&self) -> Result<impl Iterator<Item=Result<(Vec<u8>, Vec<u8>)>> + '_> {
+        let mode = rocksdb::IteratorMode::From(
+            self.prefix.as_slice(),
+            rocksdb::Direction::Forward
+        );
+        Ok(self.merk.db.iterator(mode)?
+            .take_while(move |(key, _)| key.starts_with(&self.prefix))
+            .filter(|(key, _)| !is_metadata_key(key))
+            .map(move |(key, value)| {
+                let node = Tree::decode(&key, &value)?;
+                Ok((key[self.prefix.len()..].to_vec(), node.value().to_vec()))
+            }))
+    }
+
+    /// Creates a Merkle proof for the list of queried keys, relative to the
+    /// prefix, which can be verified with `merk::verify_prefixed_query_proof`
+    /// using a query of the same keys.
+    ///
+    /// This will fail if the keys in `keys` are not sorted and unique.
+    pub fn prove(&self, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+        for pair in keys.windows(2) {
+            if pair[0] >= pair[1] {
+                bail!("Keys in query must be sorted and unique");
+            }
+        }
+
+        let mut query = Query::new();
+        for key in keys {
+            query.insert_key(key.clone());
+        }
+        self.prove_query(&query)
+    }
+
+    /// Creates a Merkle proof for a query whose keys are relative to the
+    /// prefix. Ranges in the query which are unbounded only extend to the
+    /// edges of the view, so the proof does not cover keys outside of it.
+    ///
+    /// The proof can be verified with `merk::verify_prefixed_query_proof`,
+    /// which returns keys relative to the prefix.
+    pub fn prove_query(&self, query: &Query) -> Result<Vec<u8>> {
+        self.merk.prove_query(&query.with_prefix(&self.prefix))
+    }
+
+    /// Prepends the prefix to the key, failing if the result would be longer
+    /// than the maximum key length.
+    fn prefixed_key(&self, key: &[u8]) -> Result<Vec<u8>> {
+        if self.prefix.len() + key.len() > 255 {
+            bail!("Key is too long to be prefixed: {:?}", key);
+        }
+        Ok([self.prefix.as_slice(), key].concat())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::*;
+    use crate::{Op, OpFailure, Query, verify_prefixed_query_proof};
+
+    #[test]
+    fn prefixed() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+
+        let mut bank = merk.prefixed(b"bank/");
+        bank.apply(&[
+            (b"alice".to_vec(), Op::Put(vec![1])),
+            (b"bob".to_vec(), Op::Put(vec![2]))
+        ]).expect("apply failed");
+        let mut gov = merk.prefixed(b"gov/");
+        gov.apply(&[(b"alice".to_vec(), Op::Put(vec![3]))])
+            .expect("apply failed");
+        let failures = gov.apply(&[(b"alice".to_vec(), Op::PutIfAbsent(vec![4]))])
+            .expect("apply failed");
+        assert_eq!(failures, vec![(b"alice".to_vec(), OpFailure::KeyExists)]);
+
+        assert_eq!(merk.get(b"bank/alice").unwrap(), vec![1]);
+        assert_eq!(merk.get(b"gov/alice").unwrap(), vec![3]);
+
+        let bank = merk.prefixed(b"bank/");
+        assert_eq!(bank.get(b"bob").unwrap(), vec![2]);
+        assert!(bank.get(b"carol").is_err());
+        let entries: Vec<_> = bank.iter()
+            .expect("iter failed")
+            .collect::<crate::Result<_>>()
+            .expect("iter failed");
+        assert_eq!(entries, vec![
+            (b"alice".to_vec(), vec![1]),
+            (b"bob".to_vec(), vec![2])
+        ]);
+
+        // keys which would be too long once prefixed are rejected
+        let mut bank = merk.prefixed(b"bank/");
+        assert!(bank.apply(&[(vec![0; 255], Op::Put(vec![1]))]).is_err());
+    }
+
+    #[test]
+    fn prefixed_proofs() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        merk.apply(&[
+            (b"a".to_vec(), Op::Put(vec![0])),
+            (b"bank/alice".to_vec(), Op::Put(vec![1])),
+            (b"bank/bob".to_vec(), Op::Put(vec![2])),
+            (b"bank0".to_vec(), Op::Put(vec![3])),
+            (b"gov/alice".to_vec(), Op::Put(vec![4]))
+        ]).expect("apply failed");
+        let root_hash = merk.root_hash();
+
+        let bank = merk.prefixed(b"bank/");
+        let mut query = Query::new();
+        query.insert_all();
+        let proof = bank.prove_query(&query).expect("prove_query failed");
+        let entries = verify_prefixed_query_proof(&proof, b"bank/", &query, root_hash)
+            .expect("verify failed");
+        assert_eq!(entries, vec![
+            (b"alice".to_vec(), vec![1]),
+            (b"bob".to_vec(), vec![2])
+        ]);
+
+        // the proof does not prove the same query in another namespace
+        assert!(verify_prefixed_query_proof(&proof, b"gov/", &query, root_hash).is_err());
+
+        let keys = vec![b"bob".to_vec(), b"carol".to_vec()];
+        let proof = bank.prove(&keys).expect("prove failed");
+        let mut query = Query::new();
+        query.insert_key(b"bob".to_vec());
+        query.insert_key(b"carol".to_vec());
+        let entries = verify_prefixed_query_proof(&proof, b"bank/", &query, root_hash)
+            .expect("verify failed");
+        assert_eq!(entries, vec![(b"bob".to_vec(), vec![2])]);
+    }
+}
@@ -0,0 +1,309 @@
+use std::sync::{Arc, Mutex, RwLock};
+use crate::error::Result;
+use crate::proofs::Query;
+use crate::tree::{
+    Tree,
+    Link,
+    Fetch,
+    Batch,
+    SortedBatch,
+    OpFailure,
+    Hash,
+    NULL_HASH,
+    check_sorted
+};
+use super::Merk;
+use super::column::{Column, ColumnSnapshot};
+
+/// A handle to a store which can be shared between threads (e.g. in an `Arc`),
+/// so that reads and proofs can be served while a batch is being applied.
+///
+/// Reads are made through a `Snapshot` of the last commit, which pairs the root
+/// of the tree with a RocksDB snapshot taken at the same commit, so they are
+/// unaffected by a concurrent `apply`. Batches are applied one at a time, and
+/// the new root becomes visible to reads atomically once its commit has been
+/// written.
+///
+/// # Example
+/// ```
+/// # let path = std::env::temp_dir().join("merk-shared-doctest");
+/// use std::sync::Arc;
+/// use std::thread;
+/// use merk::{Merk, SharedMerk, Op, verify_proof};
+///
+/// let store = Arc::new(SharedMerk::new(Merk::open(&path).unwrap()));
+/// store.apply(&[(vec![1], Op::Put(vec![1]))]).unwrap();
+///
+/// let reader = {
+///     let store = store.clone();
+///     thread::spawn(move || {
+///         let snapshot = store.snapshot();
+///         let proof = snapshot.prove(&[vec![1]]).unwrap();
+///         verify_proof(&proof, &[vec![1]], snapshot.root_hash()).unwrap();
+///     })
+/// };
+/// store.apply(&[(vec![2], Op::Put(vec![2]))]).unwrap();
+/// reader.join().unwrap();
+/// # Arc::try_unwrap(store).ok().unwrap().into_inner().destroy().unwrap();
+/// ```
+pub struct SharedMerk {
+    merk: Mutex<Merk>,
+    root: RwLock<Option<Arc<Tree>>>,
+    db: Column
+}
+
+impl SharedMerk {
+    /// Creates a shared handle to the store.
+    pub fn new(merk: Merk) -> Self {
+        let root = RwLock::new(merk.tree.clone().map(Arc::new));
+        let db = merk.db.clone();
+        SharedMerk { merk: Mutex::new(merk), root, db }
+    }
+
+    /// Returns a consistent view of the store as of the last commit, which
+    /// is unaffected by later commits.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        // the snapshot is taken while holding the lock, so no commit can be
+        // written between reading the root and taking the snapshot
+        let root = self.root.read().unwrap();
+        Snapshot {
+            root: root.clone(),
+            snapshot: self.db.snapshot()
+        }
+    }
+
+    /// Returns the root hash of the tree as of the last commit.
+    pub fn root_hash(&self) -> Hash {
+        self.root.read().unwrap()
+            .as_ref()
+            .map_or(NULL_HASH, |tree| tree.hash())
+    }
+
+    /// Gets a value for the given key as of the last commit. Returns an `Err`
+    /// if the key is not found or something else goes wrong.
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.snapshot().get(key)
+    }
+
+    /// Creates a Merkle proof for the list of queried keys as of the last
+    /// commit (see `Merk::prove`). To check the proof, use the root hash of
+    /// the same `Snapshot` rather than calling `root_hash`, since another
+    /// commit may be written in between.
+    pub fn prove(&self, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+        self.snapshot().prove(keys)
+    }
+
+    /// Creates a Merkle proof for a query made up of keys and ranges of keys as
+    /// of the last commit (see `Merk::prove_query`).
+    pub fn prove_query(&self, query: &Query) -> Result<Vec<u8>> {
+        self.snapshot().prove_query(query)
+    }
+
+    /// Applies a batch of operations (puts and deletes) to the tree, then
+    /// commits it (see `Merk::apply`). Waits for any other batch which is
+    /// being applied to finish first.
+    ///
+    /// Reads are not blocked while the batch is applied, and keep seeing the
+    /// previous commit until the new one has been written. If applying the
+    /// batch fails, the store is left as of the previous commit.
+    pub fn apply(&self, batch: &Batch) -> Result<Vec<(Vec<u8>, OpFailure)>> {
+        check_sorted(batch)?;
+
+        let mut merk = self.merk.lock().unwrap();
+        match self.apply_locked(&mut merk, batch) {
+            Ok(failures) => Ok(failures),
+            Err(err) => {
+                // the tree may have been partially updated
+                merk.tree = self.root.read().unwrap().as_deref().cloned();
+                Err(err)
+            }
+        }
+    }
+
+    fn apply_locked(
+        &self,
+        merk: &mut Merk,
+        batch: &Batch
+    ) -> Result<Vec<(Vec<u8>, OpFailure)>> {
+        // the tree is updated and committed in memory, only reading the
+        // records in the store, so concurrent reads are unaffected
+        let batch = SortedBatch::new_unchecked(batch.to_vec());
+        let (deleted_keys, failures) = merk.apply_to_tree(batch)?;
+        let mut write_batch = rocksdb::WriteBatch::default();
+        merk.commit_into(&mut write_batch, deleted_keys)?;
+        let tree = merk.tree.clone().map(Arc::new);
+
+        // the records and the root are replaced together while no snapshot is
+        // being taken
+        let mut root = self.root.write().unwrap();
+        merk.db.write(write_batch, &merk.options.to_write_opts())?;
+        *root = tree;
+
+        Ok(failures)
+    }
+
+    /// Consumes the handle and returns the store.
+    pub fn into_inner(self) -> Merk {
+        self.merk.into_inner().unwrap()
+    }
+}
+
+/// A consistent view of a `SharedMerk` as of a single commit, created with
+/// `SharedMerk::snapshot`. All reads and proofs made through the same snapshot
+/// match its root hash, even if later commits are written meanwhile.
+pub struct Snapshot<'a> {
+    root: Option<Arc<Tree>>,
+    snapshot: ColumnSnapshot<'a>
+}
+
+impl<'a> Snapshot<'a> {
+    /// Returns the root hash of the tree as of the snapshot.
+    pub fn root_hash(&self) -> Hash {
+        self.root.as_ref().map_or(NULL_HASH, |tree| tree.hash())
+    }
+
+    /// Gets a value for the given key as of the snapshot. Returns an `Err` if
+    /// the key is not found or something else goes wrong.
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        let node = self.source().fetch_key(key)?;
+        Ok(node.value().to_vec())
+    }
+
+    /// Creates a Merkle proof for the list of queried keys as of the snapshot,
+    /// which can be verified against its root hash with `merk::verify`.
+    ///
+    /// This will fail if the keys in `keys` are not sorted and unique.
+    pub fn prove(&self, keys: &[Vec<u8>]) -> Result<Vec<u8>> {
+        match &self.root {
+            None => bail!("Cannot create proof for empty tree"),
+            Some(tree) => tree.prove(keys, self.source())
+        }
+    }
+
+    /// Creates a Merkle proof for a query made up of keys and ranges of keys
+    /// as of the snapshot, which can be verified against its root hash with
+    /// `merk::verify_query_proof`.
+    pub fn prove_query(&self, query: &Query) -> Result<Vec<u8>> {
+        match &self.root {
+            None => bail!("Cannot create proof for empty tree"),
+            Some(tree) => tree.prove_query(query, self.source())
+        }
+    }
+
+    fn source(&self) -> SnapshotSource<'_> {
+        SnapshotSource { snapshot: &self.snapshot }
+    }
+}
+
+/// A source which fetches nodes from a snapshot. Nodes are not cached, since
+/// the store's node cache holds the latest version of each node.
+#[derive(Clone)]
+struct SnapshotSource<'a> {
+    snapshot: &'a ColumnSnapshot<'a>
+}
+
+impl<'a> SnapshotSource<'a> {
+    fn fetch_key(&self, key: &[u8]) -> Result<Tree> {
+        match self.snapshot.get(key)? {
+            None => bail!("key not found: '{:?}'", key),
+            Some(bytes) => Tree::decode(key, &bytes)
+        }
+    }
+}
+
+impl<'a> Fetch for SnapshotSource<'a> {
+    fn fetch(&self, link: &Link) -> Result<Tree> {
+        self.fetch_key(link.key())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use crate::test_utils::*;
+    use crate::{Op, verify_proof};
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn snapshot_reads() {
+        assert_send_sync::<SharedMerk>();
+
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open(&path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..100)).expect("apply failed");
+        let shared = SharedMerk::new(merk);
+        let old_hash = shared.root_hash();
+
+        // a snapshot keeps seeing the commit it was taken at
+        let snapshot = shared.snapshot();
+        shared.apply(&[
+            (seq_key(5), Op::Put(vec![1])),
+            (seq_key(50), Op::Delete)
+        ]).expect("apply failed");
+        assert_ne!(shared.root_hash(), old_hash);
+
+        assert_eq!(snapshot.root_hash(), old_hash);
+        assert_eq!(snapshot.get(&seq_key(5)).unwrap(), vec![123; 60]);
+        assert_eq!(snapshot.get(&seq_key(50)).unwrap(), vec![123; 60]);
+        let keys = vec![seq_key(5), seq_key(50), seq_key(99)];
+        let proof = snapshot.prove(&keys).expect("prove failed");
+        let values = verify_proof(&proof, &keys, old_hash).expect("verify failed");
+        assert!(values.iter().all(|value| value.is_some()));
+        drop(snapshot);
+
+        // new snapshots see the new commit
+        assert_eq!(shared.get(&seq_key(5)).unwrap(), vec![1]);
+        assert!(shared.get(&seq_key(50)).is_err());
+        let proof = shared.prove(&keys).expect("prove failed");
+        let values = verify_proof(&proof, &keys, shared.root_hash()).expect("verify failed");
+        assert_eq!(values[0], Some(vec![1]));
+        assert_eq!(values[1], None);
+
+        // a failed apply leaves the last commit in place
+        let hash = shared.root_hash();
+        assert!(shared.apply(&[(seq_key(1), Op::Put(vec![1])), (seq_key(0), Op::Delete)]).is_err());
+        assert!(shared.apply(&[(seq_key(1000), Op::Delete)]).is_err());
+        assert_eq!(shared.root_hash(), hash);
+        shared.apply(&[(seq_key(6), Op::Put(vec![2]))]).expect("apply failed");
+
+        let merk = shared.into_inner();
+        assert_eq!(merk.get(&seq_key(6)).unwrap(), vec![2]);
+        merk.destroy().expect("failed to destroy merk");
+    }
+
+    #[test]
+    fn concurrent_reads() {
+        let path = thread::current().name().unwrap().to_owned();
+        let mut merk = Merk::open(&path).expect("failed to open merk");
+        merk.apply(&make_batch_seq(0..1000)).expect("apply failed");
+        let shared = Arc::new(SharedMerk::new(merk));
+
+        let readers: Vec<_> = (0..4)
+            .map(|i| {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for j in 0..50 {
+                        let snapshot = shared.snapshot();
+                        let keys = vec![seq_key(i * 250 + j), seq_key(i * 250 + j + 100)];
+                        let proof = snapshot.prove(&keys).expect("prove failed");
+                        let values = verify_proof(&proof, &keys, snapshot.root_hash())
+                            .expect("verify failed");
+                        assert!(values.iter().all(|value| value.is_some()));
+                    }
+                })
+            })
+            .collect();
+
+        for i in 0..10 {
+            shared.apply(&make_batch_rand(100, i)).expect("apply failed");
+        }
+        for reader in readers {
+            reader.join().expect("reader panicked");
+        }
+
+        let shared = Arc::try_unwrap(shared).ok().expect("store is still shared");
+        shared.into_inner().destroy().expect("failed to destroy merk");
+    }
+}
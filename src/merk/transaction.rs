@@ -0,0 +1,251 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use crate::error::Result;
+use crate::tree::{Op, SortedBatch, Tree};
+use super::{Merk, is_metadata_key};
+
+/// A buffered write, either a put of a value or a deletion (`None`).
+type Write = Option<Vec<u8>>;
+
+/// A transaction over a `Merk`, created with `Merk::begin`, which buffers puts
+/// and deletes in memory until it is committed.
+///
+/// Reads through the transaction see its own buffered writes merged with the
+/// committed store. Savepoints can be nested, and rolling back to a savepoint
+/// discards only the writes made since it was created. Dropping the
+/// transaction without committing discards all of its writes.
+pub struct Transaction<'a> {
+    merk: &'a mut Merk,
+    writes: BTreeMap<Vec<u8>, Write>,
+    undo: Vec<(Vec<u8>, Option<Write>)>,
+    savepoints: Vec<usize>
+}
+
+impl<'a> Transaction<'a> {
+    pub(super) fn new(merk: &'a mut Merk) -> Self {
+        Transaction {
+            merk,
+            writes: BTreeMap::new(),
+            undo: vec![],
+            savepoints: vec![]
+        }
+    }
+
+    /// Gets a value for the given key, including buffered writes. Returns an
+    /// `Err` if the key is not found or something else goes wrong.
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        match self.writes.get(key) {
+            None => self.merk.get(key),
+            Some(Some(value)) => Ok(value.clone()),
+            Some(None) => bail!("key not found: '{:?}'", key)
+        }
+    }
+
+    /// Buffers a write of the value for the given key.
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.write(key, Some(value));
+    }
+
+    /// Buffers a deletion of the given key. Deleting a key which does not
+    /// exist has no effect.
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.write(key, None);
+    }
+
+    fn write(&mut self, key: Vec<u8>, write: Write) {
+        let prev = self.writes.insert(key.clone(), write);
+
+        // only needed to roll back to a savepoint
+        if !self.savepoints.is_empty() {
+            self.undo.push((key, prev));
+        }
+    }
+
+    /// Returns an iterator over the key/value pairs visible to the
+    /// transaction, in key order. Buffered writes take precedence over the
+    /// entries in the committed store.
+    pub fn iter(&self) -> Result<impl Iterator<Item=Result<(Vec<u8>, Vec<u8>)>> + '_> {
+        let mut committed = self.merk.db.iterator(rocksdb::IteratorMode::Start)?
+            .filter(|(key, _)| !is_metadata_key(key))
+            .peekable();
+        let mut writes = self.writes.iter().peekable();
+
+        Ok(std::iter::from_fn(move || loop {
+            let order = match (committed.peek(), writes.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((committed_key, _)), Some((write_key, _))) => {
+                    committed_key[..].cmp(write_key.as_slice())
+                }
+            };
+
+            if order != Ordering::Greater {
+                let (key, value) = committed.next().unwrap();
+                if order == Ordering::Less {
+                    return Some(Tree::decode(&key, &value)
+                        .map(|node| (key.to_vec(), node.value().to_vec())));
+                }
+                // shadowed by a buffered write
+            }
+
+            let (key, write) = writes.next().unwrap();
+            if let Some(value) = write {
+                return Some(Ok((key.clone(), value.clone())));
+            }
+        }))
+    }
+
+    /// Creates a savepoint which the transaction can later be rolled back to.
+    /// Savepoints are nested, so each rollback or release applies to the most
+    /// recently created savepoint.
+    pub fn savepoint(&mut self) {
+        self.savepoints.push(self.undo.len());
+    }
+
+    /// Discards the writes made since the most recent savepoint, and removes
+    /// the savepoint. Fails if there is no savepoint.
+    pub fn rollback_to_savepoint(&mut self) -> Result<()> {
+        let start = match self.savepoints.pop() {
+            None => bail!("No savepoint to roll back to"),
+            Some(start) => start
+        };
+
+        for (key, prev) in self.undo.drain(start..).rev() {
+            match prev {
+                None => self.writes.remove(&key),
+                Some(write) => self.writes.insert(key, write)
+            };
+        }
+        Ok(())
+    }
+
+    /// Removes the most recent savepoint, keeping the writes made since it was
+    /// created. Fails if there is no savepoint.
+    pub fn release_savepoint(&mut self) -> Result<()> {
+        if self.savepoints.pop().is_none() {
+            bail!("No savepoint to release");
+        }
+        if self.savepoints.is_empty() {
+            self.undo.clear();
+        }
+        Ok(())
+    }
+
+    /// Applies all of the buffered writes to the store in a single batch.
+    pub fn commit(self) -> Result<()> {
+        let batch = self.writes.into_iter()
+            .map(|(key, write)| match write {
+                Some(value) => (key, Op::Put(value)),
+                None => (key, Op::DeleteIfExists)
+            })
+            .collect();
+
+        // keys of a BTreeMap are sorted and unique
+        self.merk.apply_sorted(SortedBatch::new_unchecked(batch))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::test_utils::*;
+    use crate::Op;
+
+    fn entries(merk: &mut TempMerk) -> Vec<(Vec<u8>, Vec<u8>)> {
+        merk.begin().iter()
+            .expect("iter failed")
+            .collect::<crate::Result<_>>()
+            .expect("iter failed")
+    }
+
+    #[test]
+    fn transaction() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        merk.apply(&[
+            (vec![1], Op::Put(vec![1])),
+            (vec![3], Op::Put(vec![3])),
+            (vec![5], Op::Put(vec![5]))
+        ]).expect("apply failed");
+        let root_hash = merk.root_hash();
+
+        let mut tx = merk.begin();
+        tx.put(vec![2], vec![2]);
+        tx.put(vec![3], vec![4]);
+        tx.delete(vec![5]);
+        tx.delete(vec![6]);
+        assert_eq!(tx.get(&[1]).unwrap(), vec![1]);
+        assert_eq!(tx.get(&[2]).unwrap(), vec![2]);
+        assert_eq!(tx.get(&[3]).unwrap(), vec![4]);
+        assert!(tx.get(&[5]).is_err());
+
+        let merged: Vec<_> = tx.iter()
+            .expect("iter failed")
+            .collect::<crate::Result<_>>()
+            .expect("iter failed");
+        assert_eq!(merged, vec![
+            (vec![1], vec![1]),
+            (vec![2], vec![2]),
+            (vec![3], vec![4])
+        ]);
+
+        // dropping the transaction discards its writes
+        drop(tx);
+        assert_eq!(merk.root_hash(), root_hash);
+        assert_eq!(merk.get(&[5]).unwrap(), vec![5]);
+
+        let mut tx = merk.begin();
+        tx.put(vec![2], vec![2]);
+        tx.put(vec![3], vec![4]);
+        tx.delete(vec![5]);
+        tx.delete(vec![6]);
+        tx.commit().expect("commit failed");
+
+        assert_eq!(entries(&mut merk), vec![
+            (vec![1], vec![1]),
+            (vec![2], vec![2]),
+            (vec![3], vec![4])
+        ]);
+        assert_eq!(merk.verify_integrity().unwrap().node_count, 3);
+    }
+
+    #[test]
+    fn savepoints() {
+        let mut merk = TempMerk::new().expect("failed to open merk");
+        merk.apply(&[(vec![1], Op::Put(vec![1]))]).expect("apply failed");
+
+        let mut tx = merk.begin();
+        assert!(tx.rollback_to_savepoint().is_err());
+        assert!(tx.release_savepoint().is_err());
+
+        tx.put(vec![2], vec![2]);
+        tx.savepoint();
+        tx.put(vec![2], vec![3]);
+        tx.delete(vec![1]);
+
+        tx.savepoint();
+        tx.put(vec![4], vec![4]);
+        tx.put(vec![5], vec![5]);
+        tx.release_savepoint().expect("release failed");
+
+        tx.savepoint();
+        tx.put(vec![6], vec![6]);
+        tx.rollback_to_savepoint().expect("rollback failed");
+        assert!(tx.get(&[6]).is_err());
+        assert_eq!(tx.get(&[4]).unwrap(), vec![4]);
+        assert!(tx.get(&[1]).is_err());
+
+        // released savepoints are rolled back with their parent
+        tx.rollback_to_savepoint().expect("rollback failed");
+        assert_eq!(tx.get(&[1]).unwrap(), vec![1]);
+        assert_eq!(tx.get(&[2]).unwrap(), vec![2]);
+        assert!(tx.get(&[4]).is_err());
+        assert!(tx.rollback_to_savepoint().is_err());
+
+        tx.commit().expect("commit failed");
+        assert_eq!(entries(&mut merk), vec![
+            (vec![1], vec![1]),
+            (vec![2], vec![2])
+        ]);
+    }
+}
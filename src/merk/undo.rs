@@ -0,0 +1,190 @@
+use byteorder::{BigEndian, ByteOrder};
+use crate::error::Result;
+use super::column::Column;
+
+/// The name of the column family which the undo log of a store is kept in.
+pub(super) const UNDO_CF: &str = "undo";
+
+/// The previous state of a record overwritten or deleted by a commit: its key,
+/// and its value before the commit (`None` if it did not exist).
+pub(super) type UndoRecord = (Vec<u8>, Option<Vec<u8>>);
+
+/// A log of the records overwritten and deleted by each of the most recent
+/// commits of a store, so that they can be undone. Each entry is keyed by the
+/// commit's sequence number, as a big-endian `u64`.
+pub(super) struct UndoLog {
+    column: Column
+}
+
+impl UndoLog {
+    pub(super) fn new(column: Column) -> Self {
+        UndoLog { column }
+    }
+
+    /// Returns the number of recorded commits.
+    pub(super) fn len(&self) -> Result<usize> {
+        Ok(self.column.iterator(rocksdb::IteratorMode::Start)?.count())
+    }
+
+    /// Adds an entry for a commit to `batch`, and deletes entries so that at
+    /// most `depth` commits are recorded once the batch is written.
+    pub(super) fn record(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        records: &[UndoRecord],
+        depth: usize
+    ) -> Result<()> {
+        let seq = match self.last_seq()? {
+            None => 0,
+            Some(last) => last + 1
+        };
+        self.column.batch_put(batch, encode_seq(seq), encode_records(records))?;
+        self.prune(batch, seq + 1, depth)
+    }
+
+    /// Adds deletions of the oldest entries to `batch`, so that at most
+    /// `depth` commits are recorded once the batch is written. `next_seq` is
+    /// the sequence number following the newest entry.
+    pub(super) fn prune(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        next_seq: u64,
+        depth: usize
+    ) -> Result<()> {
+        let oldest_kept = next_seq.saturating_sub(depth as u64);
+        for (key, _) in self.column.iterator(rocksdb::IteratorMode::Start)? {
+            if decode_seq(&key)? >= oldest_kept {
+                break;
+            }
+            self.column.batch_delete(batch, key)?;
+        }
+        Ok(())
+    }
+
+    /// Adds deletions of all entries beyond the `depth` most recent ones to
+    /// `batch`.
+    pub(super) fn truncate(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        depth: usize
+    ) -> Result<()> {
+        match self.last_seq()? {
+            None => Ok(()),
+            Some(last) => self.prune(batch, last + 1, depth)
+        }
+    }
+
+    /// Removes the entries of the `n` most recent commits, adding their
+    /// deletions to `batch`, and returns their records, newest commit first.
+    /// Fails if fewer than `n` commits are recorded.
+    pub(super) fn pop(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        n: usize
+    ) -> Result<Vec<Vec<UndoRecord>>> {
+        let mut entries = Vec::with_capacity(n);
+        for (key, value) in self.column.iterator(rocksdb::IteratorMode::End)?.take(n) {
+            entries.push(decode_records(&value)?);
+            self.column.batch_delete(batch, key)?;
+        }
+
+        if entries.len() < n {
+            bail!(
+                "Cannot roll back {} commits, only {} are recorded",
+                n,
+                entries.len()
+            );
+        }
+        Ok(entries)
+    }
+
+    fn last_seq(&self) -> Result<Option<u64>> {
+        match self.column.iterator(rocksdb::IteratorMode::End)?.next() {
+            None => Ok(None),
+            Some((key, _)) => Ok(Some(decode_seq(&key)?))
+        }
+    }
+}
+
+fn encode_seq(seq: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    BigEndian::write_u64(&mut bytes, seq);
+    bytes
+}
+
+fn decode_seq(bytes: &[u8]) -> Result<u64> {
+    if bytes.len() != 8 {
+        bail!("Invalid undo log key");
+    }
+    Ok(BigEndian::read_u64(bytes))
+}
+
+/// Encodes records as a sequence of the key length (big-endian `u32`), the
+/// key, then either a zero byte for an absent record, or a one byte followed
+/// by the value length (big-endian `u32`) and the value.
+fn encode_records(records: &[UndoRecord]) -> Vec<u8> {
+    let mut bytes = vec![];
+    let mut length = [0; 4];
+    for (key, value) in records {
+        BigEndian::write_u32(&mut length, key.len() as u32);
+        bytes.extend_from_slice(&length);
+        bytes.extend_from_slice(key);
+        match value {
+            None => bytes.push(0),
+            Some(value) => {
+                bytes.push(1);
+                BigEndian::write_u32(&mut length, value.len() as u32);
+                bytes.extend_from_slice(&length);
+                bytes.extend_from_slice(value);
+            }
+        }
+    }
+    bytes
+}
+
+fn decode_records(mut bytes: &[u8]) -> Result<Vec<UndoRecord>> {
+    fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+        if bytes.len() < length {
+            bail!("Unexpected end of undo log entry");
+        }
+        let (taken, rest) = bytes.split_at(length);
+        *bytes = rest;
+        Ok(taken)
+    }
+
+    let mut records = vec![];
+    while !bytes.is_empty() {
+        let key_length = BigEndian::read_u32(take(&mut bytes, 4)?) as usize;
+        let key = take(&mut bytes, key_length)?.to_vec();
+        let value = match take(&mut bytes, 1)?[0] {
+            0 => None,
+            1 => {
+                let value_length = BigEndian::read_u32(take(&mut bytes, 4)?) as usize;
+                Some(take(&mut bytes, value_length)?.to_vec())
+            },
+            _ => bail!("Invalid undo log entry")
+        };
+        records.push((key, value));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_decode_records() {
+        let records = vec![
+            (vec![1, 2, 3], Some(vec![4, 5, 6])),
+            (vec![7], None),
+            (vec![], Some(vec![]))
+        ];
+        let bytes = encode_records(&records);
+        assert_eq!(decode_records(&bytes).unwrap(), records);
+        assert_eq!(decode_records(&[]).unwrap(), vec![]);
+
+        assert!(decode_records(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode_records(&[0, 0, 0, 1, 7, 2]).is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::LinkedList;
+use super::{Op, Node};
+use super::verify::{Tree, execute, check_hash};
+use crate::tree::{self, RefWalker, Fetch, Hash};
+use crate::error::Result;
+
+/// The exclusive bounds on the keys of a subtree implied by its ancestors.
+/// `None` means the subtree is unbounded on that side.
+pub(super) type Bounds<'a> = (Option<&'a [u8]>, Option<&'a [u8]>);
+
+/// A value aggregated over subtrees which a tree can maintain, and which can
+/// be proven for a range of keys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Aggregate {
+    /// The sum of the amounts each node's value contributes (see
+    /// `Commit::value_sum`).
+    Sum,
+
+    /// The number of nodes (see `Commit::counts`).
+    Count
+}
+
+impl Aggregate {
+    /// Returns the aggregate of the tree, if it maintains it.
+    fn of_tree(self, tree: &tree::Tree) -> Option<u64> {
+        match self {
+            Aggregate::Sum => tree.sum(),
+            Aggregate::Count => tree.count()
+        }
+    }
+
+    /// Returns the aggregate contained in the proof node, if any.
+    pub(super) fn of_node(self, node: &Node) -> Option<u64> {
+        match self {
+            Aggregate::Sum => node.sum(),
+            Aggregate::Count => node.count()
+        }
+    }
+
+    /// Returns the name of the aggregate, for error messages.
+    fn name(self) -> &'static str {
+        match self {
+            Aggregate::Sum => "sum",
+            Aggregate::Count => "count"
+        }
+    }
+}
+
+/// Returns `true` if every key within `bounds` is within the range
+/// `[start, end)`.
+fn covered(bounds: Bounds, start: &[u8], end: &[u8]) -> bool {
+    bounds.0.map_or(start.is_empty(), |lower| lower >= start)
+        && bounds.1.map_or(false, |upper| upper <= end)
+}
+
+/// Returns `true` if no key within `bounds` is within the range `[start, end)`.
+fn disjoint(bounds: Bounds, start: &[u8], end: &[u8]) -> bool {
+    bounds.1.map_or(false, |upper| upper <= start)
+        || bounds.0.map_or(false, |lower| lower >= end)
+}
+
+impl<'a, S> RefWalker<'a, S>
+    where S: Fetch + Sized + Clone
+{
+    /// Generates a proof of the aggregate of the nodes with keys in the range
+    /// `[start, end)`. Subtrees which are entirely inside or entirely outside
+    /// of the range are included only as their hash and aggregate, so the
+    /// proof contains the nodes along the paths to the edges of the range.
+    pub(crate) fn create_range_proof(
+        &self,
+        aggregate: Aggregate,
+        start: &[u8],
+        end: &[u8],
+        bounds: Bounds
+    ) -> Result<LinkedList<Op>> {
+        if aggregate.of_tree(self.tree()).is_none() {
+            bail!("Tree does not maintain {}s", aggregate.name());
+        }
+
+        let key = self.tree().key().to_vec();
+
+        let mut proof = self.create_range_child_proof(
+            aggregate, true, start, end, (bounds.0, Some(&key))
+        )?;
+        let has_left = !proof.is_empty();
+
+        proof.push_back(Op::Push(self.to_kv_node()));
+        if has_left {
+            proof.push_back(Op::Parent);
+        }
+
+        let mut right_proof = self.create_range_child_proof(
+            aggregate, false, start, end, (Some(&key), bounds.1)
+        )?;
+        if !right_proof.is_empty() {
+            proof.append(&mut right_proof);
+            proof.push_back(Op::Child);
+        }
+
+        Ok(proof)
+    }
+
+    /// Generates the part of a range proof for the child on the given side, if
+    /// any.
+    fn create_range_child_proof(
+        &self,
+        aggregate: Aggregate,
+        left: bool,
+        start: &[u8],
+        end: &[u8],
+        bounds: Bounds
+    ) -> Result<LinkedList<Op>> {
+        let child = match self.walk(left)? {
+            None => return Ok(LinkedList::new()),
+            Some(child) => child
+        };
+
+        if covered(bounds, start, end) || disjoint(bounds, start, end) {
+            let mut proof = LinkedList::new();
+            proof.push_back(Op::Push(child.to_aggregate_hash_node(aggregate)?));
+            Ok(proof)
+        } else {
+            child.create_range_proof(aggregate, start, end, bounds)
+        }
+    }
+
+    /// Creates a `Node::HashWithSum` or `Node::HashWithCount` from the root
+    /// node.
+    pub(super) fn to_aggregate_hash_node(&self, aggregate: Aggregate) -> Result<Node> {
+        let tree = self.tree();
+        let value = match aggregate.of_tree(tree) {
+            None => bail!("Tree does not maintain {}s", aggregate.name()),
+            Some(value) => value
+        };
+        let hash = tree.node_hash();
+        Ok(match aggregate {
+            Aggregate::Sum => Node::HashWithSum(hash, value),
+            Aggregate::Count => Node::HashWithCount(hash, value)
+        })
+    }
+}
+
+/// Verifies an encoded proof of the sum of the values with keys in the range
+/// `[start, end)` (as created by `Merk::prove_sum`) against the expected root
+/// hash, returning the proven sum.
+///
+/// Returns `Err` if the proof is invalid, or if it does not contain enough of
+/// the tree to compute the sum of the range.
+pub fn verify_sum(
+    bytes: &[u8],
+    start: &[u8],
+    end: &[u8],
+    expected_hash: Hash
+) -> Result<u64> {
+    verify_range(Aggregate::Sum, bytes, start, end, expected_hash)
+}
+
+/// Verifies an encoded proof of the aggregate of the nodes with keys in the
+/// range `[start, end)` against the expected root hash, returning the proven
+/// aggregate.
+pub(super) fn verify_range(
+    aggregate: Aggregate,
+    bytes: &[u8],
+    start: &[u8],
+    end: &[u8],
+    expected_hash: Hash
+) -> Result<u64> {
+    if start > end {
+        bail!("Range start must not be greater than range end");
+    }
+
+    let root = execute(bytes, |_| Ok(()))?;
+    check_hash(&root, expected_hash)?;
+
+    range_aggregate(aggregate, &root, start, end, (None, None))
+}
+
+/// Computes the aggregate of the nodes with keys in the range `[start, end)`
+/// within the given proof tree, whose keys must be within `bounds`.
+fn range_aggregate(
+    aggregate: Aggregate,
+    tree: &Tree,
+    start: &[u8],
+    end: &[u8],
+    bounds: Bounds
+) -> Result<u64> {
+    let value = match aggregate.of_node(&tree.node) {
+        None => bail!("Proof node is missing {}", aggregate.name()),
+        Some(value) => value
+    };
+
+    if covered(bounds, start, end) {
+        return Ok(value);
+    }
+    if disjoint(bounds, start, end) {
+        return Ok(0);
+    }
+
+    let key = match tree.node.key() {
+        None => bail!("Proof does not cover range"),
+        Some(key) => key.as_slice()
+    };
+
+    check_bounds(key, bounds)?;
+
+    // the amount the node itself contributes is whatever its subtree's
+    // aggregate does not attribute to its children
+    let mut own = Some(value);
+    let mut total = Some(0u64);
+    for (left, child_bounds) in &[
+        (true, (bounds.0, Some(key))),
+        (false, (Some(key), bounds.1))
+    ] {
+        if let Some(child) = tree.child(*left) {
+            let child_value = match aggregate.of_node(&child.node) {
+                None => bail!("Proof node is missing {}", aggregate.name()),
+                Some(child_value) => child_value
+            };
+            own = own.and_then(|own| own.checked_sub(child_value));
+
+            let child_total = range_aggregate(
+                aggregate, child, start, end, *child_bounds
+            )?;
+            total = total.and_then(|total| total.checked_add(child_total));
+        }
+    }
+
+    let own = match own {
+        None => bail!("Proof node has a {} less than its children", aggregate.name()),
+        Some(own) => own
+    };
+    if aggregate == Aggregate::Count && own != 1 {
+        bail!("Proof node has an invalid count");
+    }
+    if key >= start && key < end {
+        total = total.and_then(|total| total.checked_add(own));
+    }
+
+    match total {
+        None => bail!("Aggregate overflowed"),
+        Some(total) => Ok(total)
+    }
+}
+
+/// Checks that the key of a node in a proof is within the bounds implied by
+/// its ancestors.
+pub(super) fn check_bounds(key: &[u8], bounds: Bounds) -> Result<()> {
+    let out_of_order =
+        bounds.0.map_or(false, |lower| key <= lower)
+        || bounds.1.map_or(false, |upper| key >= upper);
+    if out_of_order {
+        bail!("Incorrect key ordering");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::encode_into;
+    use crate::tree::{self, Commit, PanicSource};
+
+    struct SumCommit;
+
+    impl Commit for SumCommit {
+        fn write(&mut self, _tree: &tree::Tree) -> Result<()> {
+            Ok(())
+        }
+
+        fn prune(&self, _tree: &tree::Tree) -> (bool, bool) {
+            (false, false)
+        }
+
+        fn value_sum(&self, value: &[u8]) -> Option<u64> {
+            Some(value[0] as u64)
+        }
+    }
+
+    fn make_tree() -> tree::Tree {
+        let mut tree = tree::Tree::new(vec![5], vec![5])
+            .attach(true, Some(
+                tree::Tree::new(vec![2], vec![2])
+                    .attach(true, Some(tree::Tree::new(vec![1], vec![1])))
+                    .attach(false, Some(tree::Tree::new(vec![3], vec![3])))
+            ))
+            .attach(false, Some(
+                tree::Tree::new(vec![8], vec![8])
+                    .attach(true, Some(tree::Tree::new(vec![7], vec![7])))
+                    .attach(false, Some(tree::Tree::new(vec![9], vec![9])))
+            ));
+        tree.commit(&mut SumCommit).expect("commit failed");
+        tree
+    }
+
+    fn prove(tree: &tree::Tree, start: &[u8], end: &[u8]) -> Vec<u8> {
+        let walker = RefWalker::new(tree, PanicSource {});
+        let proof = walker.create_range_proof(Aggregate::Sum, start, end, (None, None))
+            .expect("create_range_proof failed");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn tree_sums() {
+        let tree = make_tree();
+        assert_eq!(tree.sum(), Some(35));
+        assert_eq!(tree.child(true).unwrap().sum(), Some(6));
+        assert_eq!(tree.child(false).unwrap().sum(), Some(24));
+    }
+
+    #[test]
+    fn range_sums() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        let cases: &[(&[u8], &[u8], u64)] = &[
+            (&[], &[10], 35),
+            (&[], &[], 0),
+            (&[2], &[8], 17),
+            (&[3], &[4], 3),
+            (&[4], &[5], 0),
+            (&[5], &[6], 5),
+            (&[6], &[10], 24),
+            (&[1, 0], &[9, 0], 34)
+        ];
+        for (start, end, expected) in cases.iter() {
+            let bytes = prove(&tree, start, end);
+            let sum = verify_sum(&bytes, start, end, hash)
+                .expect("verify_sum failed");
+            assert_eq!(sum, *expected);
+        }
+    }
+
+    #[test]
+    fn range_sum_wrong_range() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        let bytes = prove(&tree, &[3], &[4]);
+        assert!(verify_sum(&bytes, &[1], &[4], hash).is_err());
+    }
+
+    #[test]
+    fn range_sum_forged_child_sum() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        let bytes = prove(&tree, &[5], &[6]);
+        let mut ops = vec![];
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let op = Op::decode(&bytes[offset..]).unwrap();
+            offset += op.encoding_length();
+            ops.push(match op {
+                Op::Push(Node::HashWithSum(hash, sum)) => {
+                    Op::Push(Node::HashWithSum(hash, sum + 1))
+                },
+                op => op
+            });
+        }
+        let mut forged = vec![];
+        encode_into(ops.iter(), &mut forged);
+
+        assert!(verify_sum(&forged, &[5], &[6], hash).is_err());
+    }
+
+    #[test]
+    fn range_sum_without_sums() {
+        let mut tree = tree::Tree::new(vec![5], vec![5]);
+        tree.commit(&mut tree::NoopCommit {}).expect("commit failed");
+        let walker = RefWalker::new(&tree, PanicSource {});
+        let proof = walker.create_range_proof(Aggregate::Sum, &[], &[10], (None, None));
+        assert!(proof.is_err());
+    }
+}
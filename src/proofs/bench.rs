@@ -38,8 +38,8 @@ fn verify_present_1m_256_memonly(b: &mut Bencher) {
 fn proof_memonly(b: &mut Bencher, tree_size: u64, proof_size: u64) {
     let batch_size = 10_000;
     let seed = 59421441857 * proof_size;
-    let mut tree = make_tree_rand(tree_size, batch_size, seed);
-    let mut walker = RefWalker::new(&mut tree, PanicSource {});
+    let tree = make_tree_rand(tree_size, batch_size, seed);
+    let walker = RefWalker::new(&tree, PanicSource {});
 
     let mut i = 0;
     b.iter(|| {
@@ -57,8 +57,8 @@ fn proof_memonly(b: &mut Bencher, tree_size: u64, proof_size: u64) {
 fn verify_present_bench(b: &mut Bencher, tree_size: u64, proof_size: u64) {
     let batch_size = 10_000;
     let seed = 59421441857 * proof_size;
-    let mut tree = make_tree_rand(tree_size, batch_size, seed);
-    let mut walker = RefWalker::new(&mut tree, PanicSource {});
+    let tree = make_tree_rand(tree_size, batch_size, seed);
+    let walker = RefWalker::new(&tree, PanicSource {});
 
     let mut keys_and_proofs = vec![];
     for i in 0..10 {
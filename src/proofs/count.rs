@@ -0,0 +1,299 @@
+use std::collections::LinkedList;
+use super::{Op, Node};
+use super::aggregate::{Aggregate, verify_range};
+use super::verify::{Tree, execute, check_hash};
+use crate::tree::{RefWalker, Fetch, Hash};
+use crate::error::Result;
+
+impl<'a, S> RefWalker<'a, S>
+    where S: Fetch + Sized + Clone
+{
+    /// Generates a proof of the key/value pair at the given index in key
+    /// order. The proof contains the path to the node, with the subtrees beside
+    /// it included only as their hash and count.
+    pub(crate) fn create_index_proof(&self, index: u64) -> Result<LinkedList<Op>> {
+        let left_count = match self.tree().child_count(true) {
+            None => bail!("Tree does not maintain counts"),
+            Some(count) => count
+        };
+
+        let mut proof = if index < left_count {
+            match self.walk(true)? {
+                None => bail!("Tree has an invalid count"),
+                Some(child) => child.create_index_proof(index)?
+            }
+        } else {
+            self.create_count_child_proof(true)?
+        };
+        let has_left = !proof.is_empty();
+
+        proof.push_back(Op::Push(if index == left_count {
+            self.to_kv_node()
+        } else {
+            self.to_kvhash_node()
+        }));
+        if has_left {
+            proof.push_back(Op::Parent);
+        }
+
+        let mut right_proof = if index > left_count {
+            match self.walk(false)? {
+                None => bail!("Index out of bounds"),
+                Some(child) => child.create_index_proof(index - left_count - 1)?
+            }
+        } else {
+            self.create_count_child_proof(false)?
+        };
+        if !right_proof.is_empty() {
+            proof.append(&mut right_proof);
+            proof.push_back(Op::Child);
+        }
+
+        Ok(proof)
+    }
+
+    /// Generates a proof of the number of nodes in the tree, which contains
+    /// only the root node's hash and count.
+    pub(crate) fn create_len_proof(&self) -> Result<LinkedList<Op>> {
+        let mut proof = LinkedList::new();
+        proof.push_back(Op::Push(self.to_aggregate_hash_node(Aggregate::Count)?));
+        Ok(proof)
+    }
+
+    /// Includes the child on the given side (if any) in a proof as its hash and
+    /// count.
+    fn create_count_child_proof(&self, left: bool) -> Result<LinkedList<Op>> {
+        let mut proof = LinkedList::new();
+        if let Some(child) = self.walk(left)? {
+            proof.push_back(Op::Push(child.to_aggregate_hash_node(Aggregate::Count)?));
+        }
+        Ok(proof)
+    }
+}
+
+/// Verifies an encoded proof of the number of keys in the tree (as created by
+/// `Merk::prove_len`) against the expected root hash, returning the proven
+/// number of keys.
+pub fn verify_len(bytes: &[u8], expected_hash: Hash) -> Result<u64> {
+    let root = execute(bytes, |_| Ok(()))?;
+    check_hash(&root, expected_hash)?;
+
+    match root.node.count() {
+        None => bail!("Proof node is missing count"),
+        Some(count) => Ok(count)
+    }
+}
+
+/// Verifies an encoded proof of the number of keys in the tree which are less
+/// than `key` (as created by `Merk::prove_rank`) against the expected root
+/// hash, returning the proven rank.
+pub fn verify_rank(bytes: &[u8], key: &[u8], expected_hash: Hash) -> Result<u64> {
+    verify_range(Aggregate::Count, bytes, &[], key, expected_hash)
+}
+
+/// Verifies an encoded proof of the key/value pair at the given index in key
+/// order (as created by `Merk::prove_index`) against the expected root hash,
+/// returning the proven key/value pair.
+pub fn verify_index(
+    bytes: &[u8],
+    index: u64,
+    expected_hash: Hash
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let root = execute(bytes, |_| Ok(()))?;
+    check_hash(&root, expected_hash)?;
+
+    find_index(&root, index)
+}
+
+/// Finds the key/value pair at the given index within the given proof tree.
+fn find_index(tree: &Tree, index: u64) -> Result<(Vec<u8>, Vec<u8>)> {
+    let count = match tree.node {
+        Node::HashWithCount(_, _) => bail!("Proof does not contain index"),
+        _ => match tree.node.count() {
+            None => bail!("Proof node is missing count"),
+            Some(count) => count
+        }
+    };
+    if index >= count {
+        bail!("Index out of bounds");
+    }
+
+    fn child_count(tree: &Tree, left: bool) -> Result<u64> {
+        match tree.child(left) {
+            None => Ok(0),
+            Some(child) => match child.node.count() {
+                None => bail!("Proof node is missing count"),
+                Some(count) => Ok(count)
+            }
+        }
+    }
+    let left_count = child_count(tree, true)?;
+    let right_count = child_count(tree, false)?;
+
+    let own_count = count.checked_sub(left_count)
+        .and_then(|count| count.checked_sub(right_count));
+    if own_count != Some(1) {
+        bail!("Proof node has an invalid count");
+    }
+
+    if index < left_count {
+        find_index(tree.child(true).unwrap(), index)
+    } else if index == left_count {
+        match tree.node.kv() {
+            None => bail!("Proof does not contain index"),
+            Some((key, value)) => Ok((key.clone(), value.clone()))
+        }
+    } else {
+        find_index(tree.child(false).unwrap(), index - left_count - 1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::encode_into;
+    use super::super::verify_sum;
+    use crate::tree::{self, Commit, AggregateCommit, PanicSource};
+
+    struct CountCommit;
+
+    impl Commit for CountCommit {
+        fn write(&mut self, _tree: &tree::Tree) -> Result<()> {
+            Ok(())
+        }
+
+        fn prune(&self, _tree: &tree::Tree) -> (bool, bool) {
+            (false, false)
+        }
+
+        fn counts(&self) -> bool {
+            true
+        }
+    }
+
+    fn make_tree() -> tree::Tree {
+        make_tree_with(&mut CountCommit)
+    }
+
+    fn make_tree_with<C: Commit>(committer: &mut C) -> tree::Tree {
+        let mut tree = tree::Tree::new(vec![5], vec![50])
+            .attach(true, Some(
+                tree::Tree::new(vec![2], vec![20])
+                    .attach(true, Some(tree::Tree::new(vec![1], vec![10])))
+                    .attach(false, Some(tree::Tree::new(vec![3], vec![30])))
+            ))
+            .attach(false, Some(
+                tree::Tree::new(vec![8], vec![80])
+                    .attach(false, Some(tree::Tree::new(vec![9], vec![90])))
+            ));
+        tree.commit(committer).expect("commit failed");
+        tree
+    }
+
+    fn encode(proof: LinkedList<Op>) -> Vec<u8> {
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        bytes
+    }
+
+    #[test]
+    fn counts() {
+        let tree = make_tree();
+        assert_eq!(tree.count(), Some(6));
+        assert_eq!(tree.child(true).unwrap().count(), Some(3));
+        assert_eq!(tree.child(false).unwrap().count(), Some(2));
+    }
+
+    #[test]
+    fn rank_and_index() {
+        let tree = make_tree();
+        let walker = RefWalker::new(&tree, PanicSource {});
+
+        let keys = [1, 2, 3, 5, 8, 9];
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(walker.rank(&[*key]).unwrap(), i as u64);
+            assert_eq!(
+                walker.get_by_index(i as u64).unwrap(),
+                Some((vec![*key], vec![*key * 10]))
+            );
+        }
+        assert_eq!(walker.rank(&[0]).unwrap(), 0);
+        assert_eq!(walker.rank(&[4]).unwrap(), 3);
+        assert_eq!(walker.rank(&[10]).unwrap(), 6);
+        assert_eq!(walker.get_by_index(6).unwrap(), None);
+    }
+
+    #[test]
+    fn index_proofs() {
+        let tree = make_tree();
+        let hash = tree.hash();
+        let walker = RefWalker::new(&tree, PanicSource {});
+
+        let keys = [1, 2, 3, 5, 8, 9];
+        for (i, key) in keys.iter().enumerate() {
+            let bytes = encode(walker.create_index_proof(i as u64).unwrap());
+            let kv = verify_index(&bytes, i as u64, hash).expect("verify failed");
+            assert_eq!(kv, (vec![*key], vec![*key * 10]));
+
+            // proof does not prove other indexes
+            assert!(verify_index(&bytes, (i as u64 + 3) % 6, hash).is_err());
+        }
+
+        assert!(walker.create_index_proof(6).is_err());
+    }
+
+    #[test]
+    fn rank_proofs() {
+        let tree = make_tree();
+        let hash = tree.hash();
+        let walker = RefWalker::new(&tree, PanicSource {});
+
+        for (key, expected) in &[(0, 0), (1, 0), (3, 2), (4, 3), (9, 5), (10, 6)] {
+            let key = [*key];
+            let proof = walker.create_range_proof(Aggregate::Count, &[], &key, (None, None))
+                .unwrap();
+            let bytes = encode(proof);
+            assert_eq!(verify_rank(&bytes, &key, hash).expect("verify failed"), *expected);
+        }
+    }
+
+    #[test]
+    fn len_proof() {
+        let tree = make_tree();
+        let hash = tree.hash();
+        let walker = RefWalker::new(&tree, PanicSource {});
+
+        let bytes = encode(walker.create_len_proof().unwrap());
+        assert_eq!(verify_len(&bytes, hash).expect("verify failed"), 6);
+
+        let forged = encode(vec![
+            Op::Push(Node::HashWithCount(tree.node_hash(), 7))
+        ].into_iter().collect());
+        assert!(verify_len(&forged, hash).is_err());
+    }
+
+    #[test]
+    fn count_and_sum_hashes_differ() {
+        fn first_byte(value: &[u8]) -> u64 {
+            value[0] as u64
+        }
+
+        // the root of a sum tree does not prove its sum as a length
+        let sum_tree = make_tree_with(&mut AggregateCommit {
+            sum_fn: Some(first_byte),
+            counts: false
+        });
+        let sum = sum_tree.sum().unwrap();
+        let forged = encode(vec![
+            Op::Push(Node::HashWithCount(sum_tree.node_hash(), sum))
+        ].into_iter().collect());
+        assert!(verify_len(&forged, sum_tree.hash()).is_err());
+
+        // the root of a count tree does not prove its length as a sum
+        let count_tree = make_tree();
+        let forged = encode(vec![
+            Op::Push(Node::HashWithSum(count_tree.node_hash(), 6))
+        ].into_iter().collect());
+        assert!(verify_sum(&forged, &[], &[10], count_tree.hash()).is_err());
+    }
+}
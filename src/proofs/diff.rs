@@ -0,0 +1,223 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use super::{Op, Node, encode_into};
+use super::verify::{self, execute, check_hash};
+use super::encoding::split_pair;
+use crate::error::Result;
+use crate::tree::{Tree, Hash, NULL_HASH};
+
+/// A change to the value of a key between two versions of a store, as returned
+/// by `Merk::diff`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// The key was added with the given value.
+    Added(Vec<u8>),
+
+    /// The key was removed. Holds its previous value.
+    Removed(Vec<u8>),
+
+    /// The key's value was changed from the first value to the second.
+    Changed(Vec<u8>, Vec<u8>)
+}
+
+/// Appends the change between the old and new values of a key (either of which
+/// may be absent), if there is one.
+pub(crate) fn push_change(
+    changes: &mut Vec<(Vec<u8>, Change)>,
+    key: &[u8],
+    old: Option<&[u8]>,
+    new: Option<&[u8]>
+) {
+    let change = match (old, new) {
+        (None, None) => return,
+        (Some(old), None) => Change::Removed(old.to_vec()),
+        (None, Some(new)) => Change::Added(new.to_vec()),
+        (Some(old), Some(new)) => {
+            if old == new {
+                return;
+            }
+            Change::Changed(old.to_vec(), new.to_vec())
+        }
+    };
+    changes.push((key.to_vec(), change));
+}
+
+/// Encodes a proof of part of a tree, given the key and hash of its root node
+/// and the nodes which should be included (keyed by node key), each as a node
+/// with its key and value. Nodes which are not included are proven by their
+/// hash. If `root` is `None` (the tree is empty), the proof is empty.
+pub(crate) fn encode_partial_tree(
+    root: Option<(&[u8], Hash)>,
+    nodes: &BTreeMap<Vec<u8>, Tree>
+) -> Vec<u8> {
+    fn push_ops(tree: &Tree, nodes: &BTreeMap<Vec<u8>, Tree>, ops: &mut Vec<Op>) {
+        let push_child = |left: bool, ops: &mut Vec<Op>| -> bool {
+            let link = match tree.link(left) {
+                None => return false,
+                Some(link) => link
+            };
+            match nodes.get(link.key()) {
+                None => ops.push(Op::Push(Node::Hash(*link.hash()))),
+                Some(child) => push_ops(child, nodes, ops)
+            }
+            true
+        };
+
+        let has_left = push_child(true, ops);
+
+        let key = tree.key().to_vec();
+        let value = tree.value().to_vec();
+        ops.push(Op::Push(match (tree.sum(), tree.count()) {
+            (Some(sum), _) => Node::KVWithSum(key, value, sum),
+            (None, Some(count)) => Node::KVWithCount(key, value, count),
+            (None, None) => Node::KV(key, value)
+        }));
+        if has_left {
+            ops.push(Op::Parent);
+        }
+
+        if push_child(false, ops) {
+            ops.push(Op::Child);
+        }
+    }
+
+    let mut ops = vec![];
+    if let Some((key, hash)) = root {
+        match nodes.get(key) {
+            None => ops.push(Op::Push(Node::Hash(hash))),
+            Some(root) => push_ops(root, nodes, &mut ops)
+        }
+    }
+
+    let mut bytes = vec![];
+    encode_into(ops.iter(), &mut bytes);
+    bytes
+}
+
+/// A node of a proof tree in key order, either a key/value pair or a subtree
+/// which is only included as a hash.
+enum Item<'a> {
+    KV(&'a [u8], &'a [u8]),
+    Subtree(Hash)
+}
+
+/// Executes one side of a diff proof and checks it against the expected hash.
+/// Returns `None` if the proof is empty, which proves an empty tree.
+fn execute_side(bytes: &[u8], expected_hash: Hash) -> Result<Option<verify::Tree>> {
+    if bytes.is_empty() {
+        if expected_hash != NULL_HASH {
+            bail!("Proof is empty, but expected hash is not the null hash");
+        }
+        return Ok(None);
+    }
+
+    let root = execute(bytes, |_| Ok(()))?;
+    check_hash(&root, expected_hash)?;
+    Ok(Some(root))
+}
+
+/// Returns the nodes of one side of a diff proof in key order.
+fn collect_side(tree: Option<&verify::Tree>) -> Result<Vec<Item<'_>>> {
+    fn collect<'a>(tree: &'a verify::Tree, items: &mut Vec<Item<'a>>) -> Result<()> {
+        if let Some(child) = tree.child(true) {
+            collect(child, items)?;
+        }
+
+        items.push(match &tree.node {
+            Node::Hash(_) | Node::HashWithSum(_, _) | Node::HashWithCount(_, _) => {
+                Item::Subtree(tree.hash())
+            },
+            Node::KV(key, value)
+            | Node::KVWithSum(key, value, _)
+            | Node::KVWithCount(key, value, _) => Item::KV(key, value),
+            _ => bail!("Unexpected node in diff proof")
+        });
+
+        if let Some(child) = tree.child(false) {
+            collect(child, items)?;
+        }
+        Ok(())
+    }
+
+    let mut items = vec![];
+    if let Some(tree) = tree {
+        collect(tree, &mut items)?;
+    }
+
+    // keys should always be increasing
+    let mut last_key: Option<&[u8]> = None;
+    for item in items.iter() {
+        if let Item::KV(key, _) = item {
+            if let Some(last_key) = last_key {
+                if *key <= last_key {
+                    bail!("Incorrect key ordering");
+                }
+            }
+            last_key = Some(key);
+        }
+    }
+    Ok(items)
+}
+
+/// Verifies an encoded proof of the differences between two versions of a
+/// store (as created by `Merk::prove_diff`) against the root hashes of the old
+/// and new versions, returning the proven changes in key order.
+///
+/// The proof contains a proof of part of each version, encoded with the old
+/// version's proof prefixed by its length as a big-endian `u32`. Subtrees which
+/// are only included as hashes must be identical in both versions and at the
+/// same position in key order, otherwise the proof is invalid.
+pub fn verify_diff(
+    bytes: &[u8],
+    old_hash: Hash,
+    new_hash: Hash
+) -> Result<Vec<(Vec<u8>, Change)>> {
+    let (old_bytes, new_bytes) = split_pair(bytes)?;
+    let old_tree = execute_side(old_bytes, old_hash)?;
+    let new_tree = execute_side(new_bytes, new_hash)?;
+    let old_items = collect_side(old_tree.as_ref())?;
+    let new_items = collect_side(new_tree.as_ref())?;
+
+    let mut changes = vec![];
+    let mut old_items = old_items.iter().peekable();
+    let mut new_items = new_items.iter().peekable();
+    loop {
+        match (old_items.peek(), new_items.peek()) {
+            (None, None) => break,
+            (Some(Item::Subtree(old)), Some(Item::Subtree(new))) if old == new => {
+                old_items.next();
+                new_items.next();
+            },
+            (Some(Item::Subtree(_)), _) | (_, Some(Item::Subtree(_))) => {
+                bail!("Proof does not contain enough of the trees to prove the diff");
+            },
+            (Some(Item::KV(key, value)), None) => {
+                push_change(&mut changes, key, Some(value), None);
+                old_items.next();
+            },
+            (None, Some(Item::KV(key, value))) => {
+                push_change(&mut changes, key, None, Some(value));
+                new_items.next();
+            },
+            (Some(Item::KV(old_key, old_value)), Some(Item::KV(new_key, new_value))) => {
+                match old_key.cmp(new_key) {
+                    Ordering::Less => {
+                        push_change(&mut changes, old_key, Some(old_value), None);
+                        old_items.next();
+                    },
+                    Ordering::Greater => {
+                        push_change(&mut changes, new_key, None, Some(new_value));
+                        new_items.next();
+                    },
+                    Ordering::Equal => {
+                        push_change(&mut changes, old_key, Some(old_value), Some(new_value));
+                        old_items.next();
+                        new_items.next();
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(changes)
+}
@@ -1,7 +1,8 @@
 use std::convert::TryInto;
+use byteorder::{BigEndian, ByteOrder};
 
 use super::{Op, Node};
-use crate::tree::HASH_LENGTH;
+use crate::tree::{Hash, HASH_LENGTH};
 use crate::error::Result;
 
 // TODO: Encode, Decode traits
@@ -19,11 +20,51 @@ impl Op {
             },
             Op::Push(Node::KV(key, value)) => {
                 output.push(0x03);
-                output.push(key.len().try_into().unwrap());
-                output.extend(key);
-                output.push((value.len() & 0xff).try_into().unwrap());
-                output.push((value.len() >> 8).try_into().unwrap());
-                output.extend(value);
+                encode_kv(key, value, output);
+            },
+            Op::Push(Node::HashWithSum(hash, sum)) => {
+                output.push(0x04);
+                output.extend(hash);
+                encode_u64(*sum, output);
+            },
+            Op::Push(Node::KVHashWithSum(kv_hash, sum)) => {
+                output.push(0x05);
+                output.extend(kv_hash);
+                encode_u64(*sum, output);
+            },
+            Op::Push(Node::KVWithSum(key, value, sum)) => {
+                output.push(0x06);
+                encode_kv(key, value, output);
+                encode_u64(*sum, output);
+            },
+            Op::Push(Node::HashWithCount(hash, count)) => {
+                output.push(0x07);
+                output.extend(hash);
+                encode_u64(*count, output);
+            },
+            Op::Push(Node::KVHashWithCount(kv_hash, count)) => {
+                output.push(0x08);
+                output.extend(kv_hash);
+                encode_u64(*count, output);
+            },
+            Op::Push(Node::KVWithCount(key, value, count)) => {
+                output.push(0x09);
+                encode_kv(key, value, output);
+                encode_u64(*count, output);
+            },
+            Op::Push(Node::KVDigest(key, value_hash)) => {
+                output.push(0x0a);
+                encode_kv_digest(key, value_hash, output);
+            },
+            Op::Push(Node::KVDigestWithSum(key, value_hash, sum)) => {
+                output.push(0x0b);
+                encode_kv_digest(key, value_hash, output);
+                encode_u64(*sum, output);
+            },
+            Op::Push(Node::KVDigestWithCount(key, value_hash, count)) => {
+                output.push(0x0c);
+                encode_kv_digest(key, value_hash, output);
+                encode_u64(*count, output);
             },
             Op::Parent => output.push(0x10),
             Op::Child => output.push(0x11)
@@ -35,6 +76,15 @@ impl Op {
             Op::Push(Node::Hash(_)) => 1 + HASH_LENGTH,
             Op::Push(Node::KVHash(_)) => 1 + HASH_LENGTH,
             Op::Push(Node::KV(key, value)) => 4 + key.len() + value.len(),
+            Op::Push(Node::HashWithSum(_, _)) => 1 + HASH_LENGTH + 8,
+            Op::Push(Node::KVHashWithSum(_, _)) => 1 + HASH_LENGTH + 8,
+            Op::Push(Node::KVWithSum(key, value, _)) => 12 + key.len() + value.len(),
+            Op::Push(Node::HashWithCount(_, _)) => 1 + HASH_LENGTH + 8,
+            Op::Push(Node::KVHashWithCount(_, _)) => 1 + HASH_LENGTH + 8,
+            Op::Push(Node::KVWithCount(key, value, _)) => 12 + key.len() + value.len(),
+            Op::Push(Node::KVDigest(key, _)) => 2 + key.len() + HASH_LENGTH,
+            Op::Push(Node::KVDigestWithSum(key, _, _)) => 10 + key.len() + HASH_LENGTH,
+            Op::Push(Node::KVDigestWithCount(key, _, _)) => 10 + key.len() + HASH_LENGTH,
             Op::Parent => 1,
             Op::Child => 1
         }
@@ -53,22 +103,57 @@ impl Op {
                 Op::Push(Node::KVHash(hash))
             },
             0x03 => {
-                let mut offset = 1;
-
-                let key_len = bytes[offset] as usize;
-                offset += 1;
-                let key = bytes[offset..offset + key_len].to_vec();
-                offset += key_len;
-
-                let value_len =
-                    bytes[offset] as usize
-                    + ((bytes[offset + 1] as usize) << 8);
-                offset += 2;
-                let value = bytes[offset..offset + value_len].to_vec();
-                // offset += value_len;
-
+                let (key, value, _) = decode_kv(&bytes[1..]);
                 Op::Push(Node::KV(key, value))
             },
+            0x04 => {
+                let mut hash = [0; HASH_LENGTH];
+                hash.copy_from_slice(&bytes[1..HASH_LENGTH + 1]);
+                let sum = decode_u64(&bytes[HASH_LENGTH + 1..])?;
+                Op::Push(Node::HashWithSum(hash, sum))
+            },
+            0x05 => {
+                let mut hash = [0; HASH_LENGTH];
+                hash.copy_from_slice(&bytes[1..HASH_LENGTH + 1]);
+                let sum = decode_u64(&bytes[HASH_LENGTH + 1..])?;
+                Op::Push(Node::KVHashWithSum(hash, sum))
+            },
+            0x06 => {
+                let (key, value, offset) = decode_kv(&bytes[1..]);
+                let sum = decode_u64(&bytes[1 + offset..])?;
+                Op::Push(Node::KVWithSum(key, value, sum))
+            },
+            0x07 => {
+                let mut hash = [0; HASH_LENGTH];
+                hash.copy_from_slice(&bytes[1..HASH_LENGTH + 1]);
+                let count = decode_u64(&bytes[HASH_LENGTH + 1..])?;
+                Op::Push(Node::HashWithCount(hash, count))
+            },
+            0x08 => {
+                let mut hash = [0; HASH_LENGTH];
+                hash.copy_from_slice(&bytes[1..HASH_LENGTH + 1]);
+                let count = decode_u64(&bytes[HASH_LENGTH + 1..])?;
+                Op::Push(Node::KVHashWithCount(hash, count))
+            },
+            0x09 => {
+                let (key, value, offset) = decode_kv(&bytes[1..]);
+                let count = decode_u64(&bytes[1 + offset..])?;
+                Op::Push(Node::KVWithCount(key, value, count))
+            },
+            0x0a => {
+                let (key, value_hash, _) = decode_kv_digest(&bytes[1..])?;
+                Op::Push(Node::KVDigest(key, value_hash))
+            },
+            0x0b => {
+                let (key, value_hash, offset) = decode_kv_digest(&bytes[1..])?;
+                let sum = decode_u64(&bytes[1 + offset..])?;
+                Op::Push(Node::KVDigestWithSum(key, value_hash, sum))
+            },
+            0x0c => {
+                let (key, value_hash, offset) = decode_kv_digest(&bytes[1..])?;
+                let count = decode_u64(&bytes[1 + offset..])?;
+                Op::Push(Node::KVDigestWithCount(key, value_hash, count))
+            },
             0x10 => Op::Parent,
             0x11 => Op::Child,
             _ => bail!("Proof has unexpected value")
@@ -76,6 +161,71 @@ impl Op {
     }
 }
 
+fn encode_kv(key: &[u8], value: &[u8], output: &mut Vec<u8>) {
+    output.push(key.len().try_into().unwrap());
+    output.extend(key);
+    output.push((value.len() & 0xff).try_into().unwrap());
+    output.push((value.len() >> 8).try_into().unwrap());
+    output.extend(value);
+}
+
+/// Decodes a key/value pair, returning it along with the length of its
+/// encoding.
+fn decode_kv(bytes: &[u8]) -> (Vec<u8>, Vec<u8>, usize) {
+    let mut offset = 0;
+
+    let key_len = bytes[offset] as usize;
+    offset += 1;
+    let key = bytes[offset..offset + key_len].to_vec();
+    offset += key_len;
+
+    let value_len =
+        bytes[offset] as usize
+        + ((bytes[offset + 1] as usize) << 8);
+    offset += 2;
+    let value = bytes[offset..offset + value_len].to_vec();
+    offset += value_len;
+
+    (key, value, offset)
+}
+
+fn encode_kv_digest(key: &[u8], value_hash: &Hash, output: &mut Vec<u8>) {
+    output.push(key.len().try_into().unwrap());
+    output.extend(key);
+    output.extend(value_hash);
+}
+
+/// Decodes a key and value hash, returning them along with the length of
+/// their encoding.
+fn decode_kv_digest(bytes: &[u8]) -> Result<(Vec<u8>, Hash, usize)> {
+    if bytes.is_empty() {
+        bail!("Unexpected end of proof");
+    }
+    let key_len = bytes[0] as usize;
+    let length = 1 + key_len + HASH_LENGTH;
+    if bytes.len() < length {
+        bail!("Unexpected end of proof");
+    }
+
+    let key = bytes[1..1 + key_len].to_vec();
+    let mut value_hash = [0; HASH_LENGTH];
+    value_hash.copy_from_slice(&bytes[1 + key_len..length]);
+    Ok((key, value_hash, length))
+}
+
+fn encode_u64(sum: u64, output: &mut Vec<u8>) {
+    let mut bytes = [0; 8];
+    BigEndian::write_u64(&mut bytes, sum);
+    output.extend(&bytes);
+}
+
+fn decode_u64(bytes: &[u8]) -> Result<u64> {
+    if bytes.len() < 8 {
+        bail!("Unexpected end of proof");
+    }
+    Ok(BigEndian::read_u64(&bytes[..8]))
+}
+
 pub(crate) fn encode_into<'a, T: Iterator<Item=&'a Op>>(ops: T, output: &mut Vec<u8>) {
     for op in ops {
         op.encode_into(output);
@@ -86,6 +236,30 @@ pub(crate) fn encoding_length<'a, T: Iterator<Item=&'a Op>>(ops: T) -> usize {
     ops.map(|op| op.encoding_length()).sum()
 }
 
+/// Encodes two proofs as one, with the first prefixed by its length as a
+/// big-endian `u32`.
+pub(crate) fn encode_pair(first: &[u8], second: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + first.len() + second.len());
+    let mut length = [0; 4];
+    BigEndian::write_u32(&mut length, first.len() as u32);
+    bytes.extend_from_slice(&length);
+    bytes.extend_from_slice(first);
+    bytes.extend_from_slice(second);
+    bytes
+}
+
+/// Splits two proofs encoded with `encode_pair`.
+pub(super) fn split_pair(bytes: &[u8]) -> Result<(&[u8], &[u8])> {
+    if bytes.len() < 4 {
+        bail!("Unexpected end of proof");
+    }
+    let first_length = BigEndian::read_u32(&bytes[..4]) as usize;
+    if bytes.len() - 4 < first_length {
+        bail!("Unexpected end of proof");
+    }
+    Ok(bytes[4..].split_at(first_length))
+}
+
 #[cfg(test)]
 mod test {
     use super::super::{Op, Node};
@@ -121,6 +295,118 @@ mod test {
         assert_eq!(bytes, vec![0x03, 3, 1, 2, 3, 3, 0, 4, 5, 6]);
     }
 
+    #[test]
+    fn encode_push_hash_with_sum() {
+        let op = Op::Push(Node::HashWithSum([123; HASH_LENGTH], 258));
+        assert_eq!(op.encoding_length(), 1 + HASH_LENGTH + 8);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes);
+        assert_eq!(bytes, vec![0x04, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+    }
+
+    #[test]
+    fn encode_push_kvhash_with_sum() {
+        let op = Op::Push(Node::KVHashWithSum([123; HASH_LENGTH], 258));
+        assert_eq!(op.encoding_length(), 1 + HASH_LENGTH + 8);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes);
+        assert_eq!(bytes, vec![0x05, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+    }
+
+    #[test]
+    fn encode_push_kv_with_sum() {
+        let op = Op::Push(Node::KVWithSum(vec![1, 2, 3], vec![4, 5, 6], 258));
+        assert_eq!(op.encoding_length(), 18);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes);
+        assert_eq!(bytes, vec![0x06, 3, 1, 2, 3, 3, 0, 4, 5, 6, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+    }
+
+    #[test]
+    fn decode_push_kv_with_sum_truncated() {
+        let bytes = [0x06, 3, 1, 2, 3, 3, 0, 4, 5, 6, 0, 0, 0];
+        assert!(Op::decode(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn encode_push_hash_with_count() {
+        let op = Op::Push(Node::HashWithCount([123; HASH_LENGTH], 258));
+        assert_eq!(op.encoding_length(), 1 + HASH_LENGTH + 8);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes);
+        assert_eq!(bytes, vec![0x07, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+    }
+
+    #[test]
+    fn encode_push_kvhash_with_count() {
+        let op = Op::Push(Node::KVHashWithCount([123; HASH_LENGTH], 258));
+        assert_eq!(op.encoding_length(), 1 + HASH_LENGTH + 8);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes);
+        assert_eq!(bytes, vec![0x08, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+    }
+
+    #[test]
+    fn encode_push_kv_with_count() {
+        let op = Op::Push(Node::KVWithCount(vec![1, 2, 3], vec![4, 5, 6], 258));
+        assert_eq!(op.encoding_length(), 18);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes);
+        assert_eq!(bytes, vec![0x09, 3, 1, 2, 3, 3, 0, 4, 5, 6, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+    }
+
+    #[test]
+    fn encode_push_kv_digest() {
+        let op = Op::Push(Node::KVDigest(vec![1, 2, 3], [123; HASH_LENGTH]));
+        assert_eq!(op.encoding_length(), 5 + HASH_LENGTH);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes);
+        assert_eq!(bytes, vec![0x0a, 3, 1, 2, 3, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123]);
+        assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+    }
+
+    #[test]
+    fn encode_push_kv_digest_with_sum() {
+        let op = Op::Push(Node::KVDigestWithSum(vec![1, 2, 3], [123; HASH_LENGTH], 258));
+        assert_eq!(op.encoding_length(), 13 + HASH_LENGTH);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes);
+        assert_eq!(bytes, vec![0x0b, 3, 1, 2, 3, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+    }
+
+    #[test]
+    fn encode_push_kv_digest_with_count() {
+        let op = Op::Push(Node::KVDigestWithCount(vec![1, 2, 3], [123; HASH_LENGTH], 258));
+        assert_eq!(op.encoding_length(), 13 + HASH_LENGTH);
+
+        let mut bytes = vec![];
+        op.encode_into(&mut bytes);
+        assert_eq!(bytes, vec![0x0c, 3, 1, 2, 3, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 123, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(Op::decode(&bytes[..]).expect("decode failed"), op);
+    }
+
+    #[test]
+    fn decode_push_kv_digest_truncated() {
+        let bytes = [0x0a, 3, 1, 2, 3, 123, 123, 123];
+        assert!(Op::decode(&bytes[..]).is_err());
+        assert!(Op::decode(&[0x0a][..]).is_err());
+    }
+
     #[test]
     fn encode_parent() {
         let op = Op::Parent;
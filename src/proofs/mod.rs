@@ -1,12 +1,29 @@
 mod encoding;
 mod verify;
+mod aggregate;
+mod count;
+mod page;
+mod query;
+mod multi;
+mod witness;
+mod diff;
 mod bench;
 
 use std::collections::LinkedList;
 use crate::error::Result;
-use crate::tree::{Link, RefWalker, Hash, Fetch};
-pub(crate) use encoding::{encode_into, encoding_length};
+use crate::tree::{Tree, Link, RefWalker, Hash, Fetch, value_hash};
+pub(crate) use encoding::{encode_into, encoding_length, encode_pair};
 pub use verify::verify;
+pub(crate) use aggregate::Aggregate;
+pub use aggregate::verify_sum;
+pub use count::{verify_len, verify_rank, verify_index};
+pub use page::{verify_page, Direction};
+pub use multi::verify_multi;
+pub(crate) use witness::encode_witness;
+pub use witness::apply_to_proof;
+pub(crate) use diff::{push_change, encode_partial_tree};
+pub use diff::{verify_diff, Change};
+pub use query::{verify_query, verify_prefixed_query, Query, QueryItem};
 
 /// A proof operator, executed to verify the data in a Merkle proof.
 #[derive(Debug, PartialEq)]
@@ -36,7 +53,92 @@ pub(crate) enum Node {
     KVHash(Hash),
 
     /// Represents the key and value of a tree node.
-    KV(Vec<u8>, Vec<u8>)
+    KV(Vec<u8>, Vec<u8>),
+
+    /// Represents a tree node which maintains sums by the hash of the node
+    /// without its sum (as computed by `node_hash`), along with the sum of its
+    /// subtree.
+    HashWithSum(Hash, u64),
+
+    /// Represents the hash of the key/value pair of a tree node which
+    /// maintains sums, along with the sum of its subtree.
+    KVHashWithSum(Hash, u64),
+
+    /// Represents the key and value of a tree node which maintains sums, along
+    /// with the sum of its subtree.
+    KVWithSum(Vec<u8>, Vec<u8>, u64),
+
+    /// Represents a tree node which maintains counts by the hash of the node
+    /// without its count (as computed by `node_hash`), along with the number
+    /// of nodes in its subtree.
+    HashWithCount(Hash, u64),
+
+    /// Represents the hash of the key/value pair of a tree node which
+    /// maintains counts, along with the number of nodes in its subtree.
+    KVHashWithCount(Hash, u64),
+
+    /// Represents the key and value of a tree node which maintains counts,
+    /// along with the number of nodes in its subtree.
+    KVWithCount(Vec<u8>, Vec<u8>, u64),
+
+    /// Represents the key of a tree node and the hash of its value (as computed
+    /// by `value_hash`), which proves where the key is in the tree without
+    /// including the value.
+    KVDigest(Vec<u8>, Hash),
+
+    /// Represents the key and value hash of a tree node which maintains sums,
+    /// along with the sum of its subtree.
+    KVDigestWithSum(Vec<u8>, Hash, u64),
+
+    /// Represents the key and value hash of a tree node which maintains counts,
+    /// along with the number of nodes in its subtree.
+    KVDigestWithCount(Vec<u8>, Hash, u64)
+}
+
+impl Node {
+    /// Returns the key and value contained in the node, if any.
+    fn kv(&self) -> Option<(&Vec<u8>, &Vec<u8>)> {
+        match self {
+            Node::KV(key, value) => Some((key, value)),
+            Node::KVWithSum(key, value, _) => Some((key, value)),
+            Node::KVWithCount(key, value, _) => Some((key, value)),
+            _ => None
+        }
+    }
+
+    /// Returns the key contained in the node, if any. Unlike `kv`, this
+    /// includes nodes which only contain the hash of their value.
+    fn key(&self) -> Option<&Vec<u8>> {
+        match self {
+            Node::KVDigest(key, _) => Some(key),
+            Node::KVDigestWithSum(key, _, _) => Some(key),
+            Node::KVDigestWithCount(key, _, _) => Some(key),
+            _ => self.kv().map(|(key, _)| key)
+        }
+    }
+
+    /// Returns the sum of the subtree contained in the node, if any.
+    fn sum(&self) -> Option<u64> {
+        match self {
+            Node::HashWithSum(_, sum) => Some(*sum),
+            Node::KVHashWithSum(_, sum) => Some(*sum),
+            Node::KVWithSum(_, _, sum) => Some(*sum),
+            Node::KVDigestWithSum(_, _, sum) => Some(*sum),
+            _ => None
+        }
+    }
+
+    /// Returns the number of nodes in the subtree contained in the node, if
+    /// any.
+    fn count(&self) -> Option<u64> {
+        match self {
+            Node::HashWithCount(_, count) => Some(*count),
+            Node::KVHashWithCount(_, count) => Some(*count),
+            Node::KVWithCount(_, _, count) => Some(*count),
+            Node::KVDigestWithCount(_, _, count) => Some(*count),
+            _ => None
+        }
+    }
 }
 
 impl Link {
@@ -55,20 +157,44 @@ impl Link {
 }
 
 impl<'a, S> RefWalker<'a, S>
-    where S: Fetch + Sized + Send + Clone
+    where S: Fetch + Sized + Clone
 {
-    /// Creates a `Node::KV` from the key/value pair of the root node.
+    /// Creates a `Node::KV` from the key/value pair of the root node, or a
+    /// `Node::KVWithSum` or `Node::KVWithCount` if the tree maintains sums or
+    /// counts.
     fn to_kv_node(&self) -> Node {
-        Node::KV(
-            self.tree().key().to_vec(),
-            self.tree().value().to_vec()
-        )
+        let key = self.tree().key().to_vec();
+        let value = self.tree().value().to_vec();
+        match (self.tree().sum(), self.tree().count()) {
+            (Some(sum), _) => Node::KVWithSum(key, value, sum),
+            (None, Some(count)) => Node::KVWithCount(key, value, count),
+            (None, None) => Node::KV(key, value)
+        }
     }
 
     /// Creates a `Node::KVHash` from the hash of the key/value pair of the root
-    /// node.
+    /// node, or a `Node::KVHashWithSum` or `Node::KVHashWithCount` if the tree
+    /// maintains sums or counts.
     fn to_kvhash_node(&self) -> Node {
-        Node::KVHash(*self.tree().kv_hash())
+        let kv_hash = *self.tree().kv_hash();
+        match (self.tree().sum(), self.tree().count()) {
+            (Some(sum), _) => Node::KVHashWithSum(kv_hash, sum),
+            (None, Some(count)) => Node::KVHashWithCount(kv_hash, count),
+            (None, None) => Node::KVHash(kv_hash)
+        }
+    }
+
+    /// Creates a `Node::KVDigest` from the key and the hash of the value of the
+    /// root node, or a `Node::KVDigestWithSum` or `Node::KVDigestWithCount` if
+    /// the tree maintains sums or counts.
+    fn to_kvdigest_node(&self) -> Node {
+        let key = self.tree().key().to_vec();
+        let value_hash = value_hash(self.tree().value());
+        match (self.tree().sum(), self.tree().count()) {
+            (Some(sum), _) => Node::KVDigestWithSum(key, value_hash, sum),
+            (None, Some(count)) => Node::KVDigestWithCount(key, value_hash, count),
+            (None, None) => Node::KVDigest(key, value_hash)
+        }
     }
 
     /// Generates a proof for the list of queried keys. Returns a tuple
@@ -76,7 +202,7 @@ impl<'a, S> RefWalker<'a, S>
     /// any keys were queried were less than the left edge or greater than the
     /// right edge, respectively.
     pub(crate) fn create_proof(
-        &mut self,
+        &self,
         keys: &[Vec<u8>],
     ) -> Result<(
         LinkedList<Op>,
@@ -104,8 +230,10 @@ impl<'a, S> RefWalker<'a, S>
         proof.push_back(match search {
             Ok(_) => Op::Push(self.to_kv_node()),
             Err(_) => {
+                // the key is only needed to show where the absent keys
+                // would be, so the value is left out
                 if left_absence.1 || right_absence.0 {
-                    Op::Push(self.to_kv_node())
+                    Op::Push(self.to_kvdigest_node())
                 } else {
                     Op::Push(self.to_kvhash_node())
                 }
@@ -130,7 +258,7 @@ impl<'a, S> RefWalker<'a, S>
     /// Similar to `create_proof`. Recurses into the child on the given side and
     /// generates a proof for the queried keys.
     fn create_child_proof(
-        &mut self,
+        &self,
         left: bool,
         keys: &[Vec<u8>]
     ) -> Result<(
@@ -138,7 +266,7 @@ impl<'a, S> RefWalker<'a, S>
         (bool, bool)
     )> {
         Ok(if !keys.is_empty() {
-            if let Some(mut child) = self.walk(left)? {
+            if let Some(child) = self.walk(left)? {
                 child.create_proof(keys)?
             } else {
                 (LinkedList::new(), (true, true))
@@ -153,10 +281,73 @@ impl<'a, S> RefWalker<'a, S>
     }
 }
 
+/// Fails if the keys of a query are not sorted and unique.
+pub(crate) fn check_keys(keys: &[Vec<u8>]) -> Result<()> {
+    for pair in keys.windows(2) {
+        if pair[0] > pair[1] {
+            bail!("Keys in query must be sorted");
+        } else if pair[0] == pair[1] {
+            bail!("Keys in query must be unique");
+        }
+    }
+    Ok(())
+}
+
+impl Tree {
+    /// Creates a Merkle proof for the list of queried keys, which must be
+    /// sorted and unique, in the same encoded format as `Merk::prove`. Pruned
+    /// nodes are fetched from `source` as needed (use `PanicSource` for a tree
+    /// which is kept entirely in memory).
+    ///
+    /// The tree must be committed, so that the hashes of all its nodes have
+    /// been computed.
+    ///
+    /// ```
+    /// use merk::{PanicSource, verify_proof};
+    /// use merk::test_utils::make_tree_seq;
+    ///
+    /// let tree = make_tree_seq(10);
+    /// let keys = vec![vec![0; 20]];
+    /// let proof = tree.prove(&keys, PanicSource {}).unwrap();
+    /// let values = verify_proof(&proof, &keys, tree.hash()).unwrap();
+    /// assert_eq!(values, vec![Some(vec![123; 60])]);
+    /// ```
+    pub fn prove<S>(&self, keys: &[Vec<u8>], source: S) -> Result<Vec<u8>>
+        where S: Fetch + Sized + Clone
+    {
+        check_keys(keys)?;
+
+        let walker = RefWalker::new(self, source);
+        let (proof, _) = walker.create_proof(keys)?;
+
+        let mut bytes = Vec::with_capacity(128);
+        encode_into(proof.iter(), &mut bytes);
+        Ok(bytes)
+    }
+
+    /// Creates a Merkle proof for a query made up of keys and ranges of keys,
+    /// in the same encoded format as `Merk::prove_query`. Pruned nodes are
+    /// fetched from `source` as needed.
+    ///
+    /// The tree must be committed, so that the hashes of all its nodes have
+    /// been computed.
+    pub fn prove_query<S>(&self, query: &Query, source: S) -> Result<Vec<u8>>
+        where S: Fetch + Sized + Clone
+    {
+        let walker = RefWalker::new(self, source);
+        let (proof, _) = walker.create_query_proof(query.items())?;
+
+        let mut bytes = Vec::with_capacity(128);
+        encode_into(proof.iter(), &mut bytes);
+        Ok(bytes)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::tree::{Tree, PanicSource, RefWalker};
+    use crate::tree::{Tree, PanicSource, RefWalker, NoopCommit};
+    use crate::test_utils::{make_tree_seq, seq_key};
 
     fn make_3_node_tree() -> Tree {
         Tree::from_fields(
@@ -182,8 +373,8 @@ mod test {
 
     #[test]
     fn empty_proof() {
-        let mut tree = make_3_node_tree();
-        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let tree = make_3_node_tree();
+        let walker = RefWalker::new(&tree, PanicSource {});
 
         let (proof, absence) = walker
             .create_proof(vec![].as_slice())
@@ -201,8 +392,8 @@ mod test {
 
     #[test]
     fn root_proof() {
-        let mut tree = make_3_node_tree();
-        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let tree = make_3_node_tree();
+        let walker = RefWalker::new(&tree, PanicSource {});
 
         let (proof, absence) = walker
             .create_proof(vec![vec![5]].as_slice())
@@ -220,8 +411,8 @@ mod test {
 
     #[test]
     fn leaf_proof() {
-        let mut tree = make_3_node_tree();
-        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let tree = make_3_node_tree();
+        let walker = RefWalker::new(&tree, PanicSource {});
 
         let (proof, absence) = walker
             .create_proof(vec![vec![3]].as_slice())
@@ -239,8 +430,8 @@ mod test {
 
     #[test]
     fn double_leaf_proof() {
-        let mut tree = make_3_node_tree();
-        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let tree = make_3_node_tree();
+        let walker = RefWalker::new(&tree, PanicSource {});
 
         let (proof, absence) = walker
             .create_proof(vec![vec![3], vec![7]].as_slice())
@@ -258,8 +449,8 @@ mod test {
 
     #[test]
     fn all_nodes_proof() {
-        let mut tree = make_3_node_tree();
-        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let tree = make_3_node_tree();
+        let walker = RefWalker::new(&tree, PanicSource {});
 
         let (proof, absence) = walker
             .create_proof(vec![vec![3], vec![5], vec![7]].as_slice())
@@ -277,8 +468,8 @@ mod test {
 
     #[test]
     fn global_edge_absence_proof() {
-        let mut tree = make_3_node_tree();
-        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let tree = make_3_node_tree();
+        let walker = RefWalker::new(&tree, PanicSource {});
 
         let (proof, absence) = walker
             .create_proof(vec![vec![8]].as_slice())
@@ -288,7 +479,7 @@ mod test {
         assert_eq!(iter.next(), Some(&Op::Push(Node::Hash([3; 20]))));
         assert_eq!(iter.next(), Some(&Op::Push(Node::KVHash([105; 20]))));
         assert_eq!(iter.next(), Some(&Op::Parent));
-        assert_eq!(iter.next(), Some(&Op::Push(Node::KV(vec![7], vec![7]))));
+        assert_eq!(iter.next(), Some(&Op::Push(Node::KVDigest(vec![7], value_hash(&[7])))));
         assert_eq!(iter.next(), Some(&Op::Child));
         assert!(iter.next().is_none());
         assert_eq!(absence, (false, true));
@@ -296,8 +487,8 @@ mod test {
 
     #[test]
     fn absence_proof() {
-        let mut tree = make_3_node_tree();
-        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let tree = make_3_node_tree();
+        let walker = RefWalker::new(&tree, PanicSource {});
 
         let (proof, absence) = walker
             .create_proof(vec![vec![6]].as_slice())
@@ -305,9 +496,9 @@ mod test {
 
         let mut iter = proof.iter();
         assert_eq!(iter.next(), Some(&Op::Push(Node::Hash([3; 20]))));
-        assert_eq!(iter.next(), Some(&Op::Push(Node::KV(vec![5], vec![5]))));
+        assert_eq!(iter.next(), Some(&Op::Push(Node::KVDigest(vec![5], value_hash(&[5])))));
         assert_eq!(iter.next(), Some(&Op::Parent));
-        assert_eq!(iter.next(), Some(&Op::Push(Node::KV(vec![7], vec![7]))));
+        assert_eq!(iter.next(), Some(&Op::Push(Node::KVDigest(vec![7], value_hash(&[7])))));
         assert_eq!(iter.next(), Some(&Op::Child));
         assert!(iter.next().is_none());
         assert_eq!(absence, (false, false));
@@ -315,7 +506,7 @@ mod test {
 
     #[test]
     fn doc_proof() {
-        let mut tree = Tree::from_fields(
+        let tree = Tree::from_fields(
             vec![5], vec![5], [105; 20],
             Some(Link::Stored {
                 child_heights: (0, 0),
@@ -395,7 +586,7 @@ mod test {
                 )
             })
         );
-        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let walker = RefWalker::new(&tree, PanicSource {});
 
         let (proof, absence) = walker.create_proof(vec![
             vec![1],
@@ -423,5 +614,53 @@ mod test {
         encode_into(proof.iter(), &mut bytes);
         assert_eq!(bytes, vec![3, 1, 1, 1, 0, 1, 3, 1, 2, 1, 0, 2, 16, 3, 1, 3, 1, 0, 3, 3, 1, 4, 1, 0, 4, 16, 17, 2, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 105, 16, 1, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9, 17]);
     }
-}
 
+    #[test]
+    fn tree_prove() {
+        let tree = make_tree_seq(100);
+        let hash = tree.hash();
+
+        let keys = vec![seq_key(5), seq_key(50), seq_key(500)];
+        let proof = tree.prove(&keys, PanicSource {}).expect("prove failed");
+        let values = verify(&proof, &keys, hash).expect("verify failed");
+        assert_eq!(values, vec![Some(vec![123; 60]), Some(vec![123; 60]), None]);
+
+        assert!(tree.prove(&[seq_key(2), seq_key(1)], PanicSource {}).is_err());
+        assert!(tree.prove(&[seq_key(1), seq_key(1)], PanicSource {}).is_err());
+
+        let mut query = Query::new();
+        query.insert_range(seq_key(10)..seq_key(20));
+        let proof = tree.prove_query(&query, PanicSource {}).expect("prove failed");
+        let entries = verify_query(&proof, &query, hash).expect("verify failed");
+        assert_eq!(entries.len(), 10);
+    }
+
+    #[test]
+    fn absence_proof_omits_values() {
+        let mut tree = Tree::new(vec![1], vec![1; 10_000])
+            .attach(false, Some(Tree::new(vec![3], vec![3; 10_000])));
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+
+        let keys = vec![vec![0], vec![2], vec![4]];
+        let proof = tree.prove(&keys, PanicSource {}).expect("prove failed");
+        assert!(proof.len() < 100);
+        let values = verify(&proof, &keys, tree.hash()).expect("verify failed");
+        assert_eq!(values, vec![None, None, None]);
+    }
+
+    #[test]
+    fn query_absence_proof_omits_values() {
+        let mut tree = Tree::new(vec![1], vec![1; 10_000])
+            .attach(false, Some(Tree::new(vec![3], vec![3; 10_000])));
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+
+        let mut query = Query::new();
+        query.insert_key(vec![0]);
+        query.insert_range(vec![2]..vec![3]);
+        query.insert_range_from(vec![4]..);
+        let proof = tree.prove_query(&query, PanicSource {}).expect("prove failed");
+        assert!(proof.len() < 100);
+        let entries = verify_query(&proof, &query, tree.hash()).expect("verify failed");
+        assert!(entries.is_empty());
+    }
+}
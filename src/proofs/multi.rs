@@ -0,0 +1,34 @@
+use super::verify;
+use super::encoding::split_pair;
+use crate::tree::{Hash, HASH_LENGTH, NULL_HASH};
+use crate::error::Result;
+
+/// Verifies an encoded proof of keys in the subtree with the given name (as
+/// created by `MultiMerk::prove`) against the expected combined root hash.
+///
+/// The proof is made of two layers, encoded with the first prefixed by its
+/// length as a big-endian `u32`: the first proves the subtree's root hash
+/// as the value of its name in the root tree, and the second proves the keys
+/// against the subtree's root hash. Returns the proven values in the same form
+/// as `verify`.
+pub fn verify_multi(
+    bytes: &[u8],
+    name: &str,
+    keys: &[Vec<u8>],
+    expected_hash: Hash
+) -> Result<Vec<Option<Vec<u8>>>> {
+    let (root_proof, subtree_proof) = split_pair(bytes)?;
+
+    let mut values = verify(root_proof, &[name.as_bytes().to_vec()], expected_hash)?;
+    let subtree_hash = match values.remove(0) {
+        None => bail!("Proof shows subtree {} does not exist", name),
+        Some(value) => value
+    };
+    if subtree_hash.len() != HASH_LENGTH {
+        bail!("Subtree root hash has an invalid length");
+    }
+    let mut hash = NULL_HASH;
+    hash.copy_from_slice(&subtree_hash);
+
+    verify(subtree_proof, keys, hash)
+}
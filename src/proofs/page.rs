@@ -0,0 +1,228 @@
+use super::verify::{Item, execute, check_hash, collect_items};
+use crate::tree::Hash;
+use crate::error::Result;
+
+/// The order in which a page of entries is read, starting from the page's
+/// start key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Reads entries with keys greater than the start key, in increasing order.
+    Ascending,
+
+    /// Reads entries with keys less than the start key, in decreasing order.
+    Descending
+}
+
+impl Direction {
+    /// Returns `true` if `key` comes after `start` when reading in this
+    /// direction.
+    pub(crate) fn is_after(self, key: &[u8], start: &[u8]) -> bool {
+        match self {
+            Direction::Ascending => key > start,
+            Direction::Descending => key < start
+        }
+    }
+}
+
+/// Verifies an encoded proof of a page of entries (as created by
+/// `Merk::query_page`) against the expected root hash, returning the proven
+/// entries.
+///
+/// The entries are the first `limit` entries after `start` in the given
+/// direction (or from the first entry in the given direction if `start` is
+/// `None`). The proof is checked to contain every entry between `start` and the
+/// last entry of the page, so that none could have been skipped, and if the
+/// page has fewer than `limit` entries, to contain every entry up to the edge
+/// of the tree.
+pub fn verify_page(
+    bytes: &[u8],
+    start: Option<&[u8]>,
+    direction: Direction,
+    limit: usize,
+    expected_hash: Hash
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let root = execute(bytes, |_| Ok(()))?;
+    check_hash(&root, expected_hash)?;
+
+    if limit == 0 {
+        return Ok(vec![]);
+    }
+
+    let mut items = vec![];
+    collect_items(&root, &mut items);
+    if direction == Direction::Descending {
+        items.reverse();
+    }
+
+    // keys should always be increasing in the direction of the page
+    let mut last_key = None;
+    for item in items.iter() {
+        if let Item::KV(key, _) | Item::Key(key) = item {
+            if let Some(last_key) = last_key {
+                if !direction.is_after(key, last_key) {
+                    bail!("Incorrect key ordering");
+                }
+            }
+            last_key = Some(*key);
+        }
+    }
+
+    let is_after_start = |key: &[u8]| {
+        start.map_or(true, |start| direction.is_after(key, start))
+    };
+    let first_index = items.iter()
+        .position(|item| match item {
+            Item::KV(key, _) | Item::Key(key) => is_after_start(key),
+            Item::Opaque => false
+        })
+        .unwrap_or(items.len());
+
+    // the page must start at an edge of the tree, or right after a key which
+    // is not after `start`
+    if first_index > 0 {
+        if let Item::Opaque = items[first_index - 1] {
+            bail!("Proof does not contain the start of the page");
+        }
+    }
+
+    let mut entries = Vec::with_capacity(limit);
+    for item in items[first_index..].iter() {
+        if entries.len() == limit {
+            break;
+        }
+
+        match item {
+            Item::KV(key, value) => entries.push((key.to_vec(), value.to_vec())),
+            Item::Key(_) => bail!("Proof is missing the value of an entry of the page"),
+            Item::Opaque => bail!("Proof skipped entries of the page")
+        }
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::encode_into;
+    use crate::tree::{self, NoopCommit, PanicSource, RefWalker};
+
+    fn make_tree() -> tree::Tree {
+        let mut tree = tree::Tree::new(vec![5], vec![5])
+            .attach(true, Some(
+                tree::Tree::new(vec![2], vec![2])
+                    .attach(true, Some(tree::Tree::new(vec![1], vec![1])))
+                    .attach(false, Some(tree::Tree::new(vec![3], vec![3])))
+            ))
+            .attach(false, Some(
+                tree::Tree::new(vec![8], vec![8])
+                    .attach(true, Some(tree::Tree::new(vec![7], vec![7])))
+                    .attach(false, Some(tree::Tree::new(vec![9], vec![9])))
+            ));
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        tree
+    }
+
+    fn prove(tree: &tree::Tree, keys: &[u8]) -> Vec<u8> {
+        let keys: Vec<Vec<u8>> = keys.iter().map(|key| vec![*key]).collect();
+        let walker = RefWalker::new(tree, PanicSource {});
+        let (proof, _) = walker.create_proof(keys.as_slice())
+            .expect("create_proof failed");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        bytes
+    }
+
+    fn keys(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+        entries.into_iter().map(|(key, _)| key[0]).collect()
+    }
+
+    #[test]
+    fn ascending_page() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        let bytes = prove(&tree, &[2, 3, 5]);
+        let entries = verify_page(&bytes, Some(&[2]), Direction::Ascending, 2, hash)
+            .expect("verify failed");
+        assert_eq!(keys(entries), vec![3, 5]);
+
+        // the proof does not contain the entries of a longer page
+        assert!(verify_page(&bytes, Some(&[2]), Direction::Ascending, 3, hash).is_err());
+
+        // the proof does not contain the start of a page from the left edge
+        assert!(verify_page(&bytes, None, Direction::Ascending, 2, hash).is_err());
+    }
+
+    #[test]
+    fn absent_start() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        let bytes = prove(&tree, &[4, 5, 7]);
+        let entries = verify_page(&bytes, Some(&[4]), Direction::Ascending, 2, hash)
+            .expect("verify failed");
+        assert_eq!(keys(entries), vec![5, 7]);
+    }
+
+    #[test]
+    fn page_from_edge() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        let bytes = prove(&tree, &[1, 2]);
+        let entries = verify_page(&bytes, None, Direction::Ascending, 2, hash)
+            .expect("verify failed");
+        assert_eq!(keys(entries), vec![1, 2]);
+
+        let bytes = prove(&tree, &[8, 9]);
+        let entries = verify_page(&bytes, None, Direction::Descending, 2, hash)
+            .expect("verify failed");
+        assert_eq!(keys(entries), vec![9, 8]);
+    }
+
+    #[test]
+    fn short_page() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        let bytes = prove(&tree, &[7, 8, 9]);
+        let entries = verify_page(&bytes, Some(&[7]), Direction::Ascending, 10, hash)
+            .expect("verify failed");
+        assert_eq!(keys(entries), vec![8, 9]);
+
+        let bytes = prove(&tree, &[10]);
+        let entries = verify_page(&bytes, Some(&[10]), Direction::Ascending, 10, hash)
+            .expect("verify failed");
+        assert!(entries.is_empty());
+
+        // a short page must reach the edge of the tree
+        let bytes = prove(&tree, &[2, 3]);
+        assert!(verify_page(&bytes, Some(&[2]), Direction::Ascending, 10, hash).is_err());
+    }
+
+    #[test]
+    fn descending_page() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        let bytes = prove(&tree, &[3, 5, 6]);
+        let entries = verify_page(&bytes, Some(&[6]), Direction::Descending, 2, hash)
+            .expect("verify failed");
+        assert_eq!(keys(entries), vec![5, 3]);
+    }
+
+    #[test]
+    fn skipped_entries() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        // 3 is only included as a hash
+        let bytes = prove(&tree, &[2, 5]);
+        assert!(verify_page(&bytes, Some(&[2]), Direction::Ascending, 2, hash).is_err());
+
+        // 7 is only included with the hash of its value
+        let bytes = prove(&tree, &[6]);
+        assert!(verify_page(&bytes, Some(&[5]), Direction::Ascending, 1, hash).is_err());
+    }
+}
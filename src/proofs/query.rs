@@ -0,0 +1,647 @@
+use std::collections::LinkedList;
+use std::ops::{Bound, Range, RangeInclusive, RangeFrom, RangeTo, RangeToInclusive};
+use super::Op;
+use super::verify::{Item, execute, check_hash, collect_items};
+use crate::tree::{RefWalker, Fetch, Hash};
+use crate::error::Result;
+
+use Bound::{Included, Excluded, Unbounded};
+
+/// A single item of a `Query`, either an exact key or a range of keys.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryItem {
+    /// Selects a single key.
+    Key(Vec<u8>),
+
+    /// Selects the keys between a lower and an upper bound.
+    Range(Bound<Vec<u8>>, Bound<Vec<u8>>)
+}
+
+impl QueryItem {
+    /// Returns the lower bound of the keys selected by the item.
+    pub fn lower(&self) -> Bound<&[u8]> {
+        match self {
+            QueryItem::Key(key) => Included(key.as_slice()),
+            QueryItem::Range(lower, _) => as_ref(lower)
+        }
+    }
+
+    /// Returns the upper bound of the keys selected by the item.
+    pub fn upper(&self) -> Bound<&[u8]> {
+        match self {
+            QueryItem::Key(key) => Included(key.as_slice()),
+            QueryItem::Range(_, upper) => as_ref(upper)
+        }
+    }
+
+    /// Returns `true` if the item selects the given key.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        !is_empty(
+            max_lower(self.lower(), Included(key)),
+            min_upper(self.upper(), Included(key))
+        )
+    }
+
+    /// Creates an item from a pair of bounds, as a `QueryItem::Key` if they
+    /// select exactly one key.
+    fn from_bounds(lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Self {
+        match (lower, upper) {
+            (Included(lower), Included(upper)) if lower == upper => {
+                QueryItem::Key(lower.to_vec())
+            },
+            (lower, upper) => QueryItem::Range(to_owned(lower), to_owned(upper))
+        }
+    }
+}
+
+/// Converts a `&Bound<Vec<u8>>` into a `Bound<&[u8]>`.
+fn as_ref(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Included(key) => Included(key.as_slice()),
+        Excluded(key) => Excluded(key.as_slice()),
+        Unbounded => Unbounded
+    }
+}
+
+/// Converts a `Bound<&[u8]>` into a `Bound<Vec<u8>>`.
+fn to_owned(bound: Bound<&[u8]>) -> Bound<Vec<u8>> {
+    match bound {
+        Included(key) => Included(key.to_vec()),
+        Excluded(key) => Excluded(key.to_vec()),
+        Unbounded => Unbounded
+    }
+}
+
+/// Returns `true` if no key is within both bounds.
+fn is_empty(lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+    match (lower, upper) {
+        (Unbounded, _) | (_, Unbounded) => false,
+        (Included(lower), Included(upper)) => lower > upper,
+        (Included(lower), Excluded(upper))
+        | (Excluded(lower), Included(upper))
+        | (Excluded(lower), Excluded(upper)) => lower >= upper
+    }
+}
+
+/// Returns `true` if a range ending at `upper` and a range starting at `lower`
+/// overlap or are adjacent, so that their union is a single range.
+fn touches(upper: Bound<&[u8]>, lower: Bound<&[u8]>) -> bool {
+    match (upper, lower) {
+        (Unbounded, _) | (_, Unbounded) => true,
+        (Excluded(upper), Excluded(lower)) => lower < upper,
+        (Included(upper), Included(lower))
+        | (Included(upper), Excluded(lower))
+        | (Excluded(upper), Included(lower)) => lower <= upper
+    }
+}
+
+/// Returns the more restrictive of two lower bounds.
+fn max_lower<'a>(a: Bound<&'a [u8]>, b: Bound<&'a [u8]>) -> Bound<&'a [u8]> {
+    match (a, b) {
+        (Unbounded, bound) | (bound, Unbounded) => bound,
+        (Included(a), Included(b)) => Included(a.max(b)),
+        (Excluded(a), Excluded(b)) => Excluded(a.max(b)),
+        (Included(a), Excluded(b)) | (Excluded(b), Included(a)) => {
+            if a > b { Included(a) } else { Excluded(b) }
+        }
+    }
+}
+
+/// Returns the more restrictive of two upper bounds.
+fn min_upper<'a>(a: Bound<&'a [u8]>, b: Bound<&'a [u8]>) -> Bound<&'a [u8]> {
+    match (a, b) {
+        (Unbounded, bound) | (bound, Unbounded) => bound,
+        (Included(a), Included(b)) => Included(a.min(b)),
+        (Excluded(a), Excluded(b)) => Excluded(a.min(b)),
+        (Included(a), Excluded(b)) | (Excluded(b), Included(a)) => {
+            if a < b { Included(a) } else { Excluded(b) }
+        }
+    }
+}
+
+/// Returns the less restrictive of two lower bounds.
+fn min_lower<'a>(a: Bound<&'a [u8]>, b: Bound<&'a [u8]>) -> Bound<&'a [u8]> {
+    if max_lower(a, b) == a { b } else { a }
+}
+
+/// Returns the less restrictive of two upper bounds.
+fn max_upper<'a>(a: Bound<&'a [u8]>, b: Bound<&'a [u8]>) -> Bound<&'a [u8]> {
+    if min_upper(a, b) == a { b } else { a }
+}
+
+/// A set of keys and ranges of keys to be proven, built up by inserting items.
+/// Items are normalized as they are inserted, so the query always consists of
+/// disjoint items sorted by key, with overlapping or adjacent items merged.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Query {
+    items: Vec<QueryItem>
+}
+
+impl Query {
+    /// Creates an empty query.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the normalized items of the query, sorted by key.
+    pub fn items(&self) -> &[QueryItem] {
+        self.items.as_slice()
+    }
+
+    /// Returns the number of normalized items in the query.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Returns `true` if the query selects no keys.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Returns `true` if the query selects the given key.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        intersects(&self.items, Included(key), Included(key))
+    }
+
+    /// Adds a single key to the query.
+    pub fn insert_key(&mut self, key: Vec<u8>) {
+        self.insert_item(QueryItem::Key(key));
+    }
+
+    /// Adds the keys in the range `start..end` to the query.
+    pub fn insert_range(&mut self, range: Range<Vec<u8>>) {
+        self.insert_item(QueryItem::Range(Included(range.start), Excluded(range.end)));
+    }
+
+    /// Adds the keys in the range `start..=end` to the query.
+    pub fn insert_range_inclusive(&mut self, range: RangeInclusive<Vec<u8>>) {
+        let (start, end) = range.into_inner();
+        self.insert_item(QueryItem::Range(Included(start), Included(end)));
+    }
+
+    /// Adds the keys in the range `start..` to the query.
+    pub fn insert_range_from(&mut self, range: RangeFrom<Vec<u8>>) {
+        self.insert_item(QueryItem::Range(Included(range.start), Unbounded));
+    }
+
+    /// Adds the keys in the range `..end` to the query.
+    pub fn insert_range_to(&mut self, range: RangeTo<Vec<u8>>) {
+        self.insert_item(QueryItem::Range(Unbounded, Excluded(range.end)));
+    }
+
+    /// Adds the keys in the range `..=end` to the query.
+    pub fn insert_range_to_inclusive(&mut self, range: RangeToInclusive<Vec<u8>>) {
+        self.insert_item(QueryItem::Range(Unbounded, Included(range.end)));
+    }
+
+    /// Adds every key starting with `prefix` to the query.
+    pub fn insert_prefix(&mut self, prefix: Vec<u8>) {
+        let end = prefix_end(&prefix);
+        self.insert_item(QueryItem::Range(Included(prefix), end));
+    }
+
+    /// Adds every key to the query.
+    pub fn insert_all(&mut self) {
+        self.insert_item(QueryItem::Range(Unbounded, Unbounded));
+    }
+
+    /// Returns a query which selects the keys selected by this query with
+    /// `prefix` prepended, e.g. to query a namespace of the store (see
+    /// `Merk::prefixed`).
+    pub fn with_prefix(&self, prefix: &[u8]) -> Query {
+        let prefix_bound = |bound: Bound<&[u8]>| match bound {
+            Included(key) => Included([prefix, key].concat()),
+            Excluded(key) => Excluded([prefix, key].concat()),
+            Unbounded => Unbounded
+        };
+
+        let items = self.items.iter()
+            .map(|item| match item {
+                QueryItem::Key(key) => QueryItem::Key([prefix, key].concat()),
+                QueryItem::Range(_, _) => {
+                    let lower = match item.lower() {
+                        Unbounded => Included(prefix.to_vec()),
+                        lower => prefix_bound(lower)
+                    };
+                    let upper = match item.upper() {
+                        Unbounded => prefix_end(prefix),
+                        upper => prefix_bound(upper)
+                    };
+                    QueryItem::Range(lower, upper)
+                }
+            })
+            .collect();
+
+        // prepending the same prefix to every key preserves their order, so
+        // the items are still sorted and disjoint
+        Query { items }
+    }
+
+    /// Adds an item to the query, merging it with any items it overlaps or is
+    /// adjacent to. Items which select no keys are ignored.
+    pub fn insert_item(&mut self, item: QueryItem) {
+        if is_empty(item.lower(), item.upper()) {
+            return;
+        }
+
+        let mut lower = item.lower();
+        let mut upper = item.upper();
+
+        // items before `start` end before the new item, items from `end` start
+        // after it, and the items in between are merged into it
+        let start = self.items.iter()
+            .take_while(|existing| !touches(existing.upper(), lower))
+            .count();
+        let end = start + self.items[start..].iter()
+            .take_while(|existing| touches(upper, existing.lower()))
+            .count();
+
+        if let Some(first) = self.items[start..end].first() {
+            lower = min_lower(lower, first.lower());
+        }
+        if let Some(last) = self.items[start..end].last() {
+            upper = max_upper(upper, last.upper());
+        }
+
+        let merged = QueryItem::from_bounds(lower, upper);
+        self.items.drain(start..end);
+        self.items.insert(start, merged);
+    }
+}
+
+/// Returns the exclusive upper bound of the keys starting with `prefix`.
+pub(crate) fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    // the keys with the prefix are the keys less than the prefix with its
+    // last non-0xff byte incremented (and the bytes after it removed)
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&0xff) {
+        end.pop();
+    }
+    match end.last_mut() {
+        None => Unbounded,
+        Some(byte) => {
+            *byte += 1;
+            Excluded(end)
+        }
+    }
+}
+
+/// Returns `true` if any of the given sorted, disjoint items selects a key
+/// within both bounds.
+fn intersects(items: &[QueryItem], lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+    // the first item which does not end before `lower` is the only one which
+    // can intersect the bounds without an earlier item also intersecting them
+    let index = items.partition_point(|item| is_empty(lower, item.upper()));
+    match items.get(index) {
+        None => false,
+        Some(item) => !is_empty(
+            max_lower(item.lower(), lower),
+            min_upper(item.upper(), upper)
+        )
+    }
+}
+
+impl<'a, S> RefWalker<'a, S>
+    where S: Fetch + Sized + Clone
+{
+    /// Generates a proof for the given sorted, disjoint query items. Returns a
+    /// tuple containing the generated proof operators, and a tuple
+    /// representing if the query extended past the left edge or the right edge
+    /// of the tree, respectively.
+    ///
+    /// Nodes whose keys are selected by the query are included as key/value
+    /// pairs, along with the keys of the nodes bounding the query's items so
+    /// that no selected key can be omitted.
+    pub(crate) fn create_query_proof(
+        &self,
+        items: &[QueryItem]
+    ) -> Result<(
+        LinkedList<Op>,
+        (bool, bool)
+    )> {
+        let (left_items, right_items, matched) = {
+            let key = self.tree().key();
+            let left_end = items.partition_point(
+                |item| !is_empty(item.lower(), Excluded(key))
+            );
+            let right_start = items.partition_point(
+                |item| is_empty(Excluded(key), item.upper())
+            );
+            let matched = intersects(items, Included(key), Included(key));
+            (&items[..left_end], &items[right_start..], matched)
+        };
+
+        let (mut proof, left_absence) =
+            self.create_child_query_proof(true, left_items)?;
+        let (mut right_proof, right_absence) =
+            self.create_child_query_proof(false, right_items)?;
+
+        let (has_left, has_right) = (
+            !proof.is_empty(),
+            !right_proof.is_empty()
+        );
+
+        proof.push_back(if matched {
+            Op::Push(self.to_kv_node())
+        } else if left_absence.1 || right_absence.0 {
+            // the key is only needed to bound the query's items, so the
+            // value is left out
+            Op::Push(self.to_kvdigest_node())
+        } else {
+            Op::Push(self.to_kvhash_node())
+        });
+
+        if has_left {
+            proof.push_back(Op::Parent);
+        }
+
+        if has_right {
+            proof.append(&mut right_proof);
+            proof.push_back(Op::Child);
+        }
+
+        Ok((
+            proof,
+            (left_absence.0, right_absence.1)
+        ))
+    }
+
+    /// Similar to `create_query_proof`. Recurses into the child on the given
+    /// side and generates a proof for the query items.
+    fn create_child_query_proof(
+        &self,
+        left: bool,
+        items: &[QueryItem]
+    ) -> Result<(
+        LinkedList<Op>,
+        (bool, bool)
+    )> {
+        Ok(if !items.is_empty() {
+            if let Some(child) = self.walk(left)? {
+                child.create_query_proof(items)?
+            } else {
+                (LinkedList::new(), (true, true))
+            }
+        } else if let Some(link) = self.tree().link(left) {
+            let mut proof = LinkedList::new();
+            proof.push_back(Op::Push(link.to_hash_node()));
+            (proof, (false, false))
+        } else {
+            (LinkedList::new(), (false, false))
+        })
+    }
+}
+
+/// Verifies an encoded proof of a query (as created by `Merk::prove_query`)
+/// against the expected root hash, returning the proven key/value pairs
+/// selected by the query, sorted by key.
+///
+/// Every key selected by the query is checked to either have its key/value
+/// pair in the proof, or to have its absence proven. Keys which are absent
+/// from the tree have no entry in the returned list.
+pub fn verify_query(
+    bytes: &[u8],
+    query: &Query,
+    expected_hash: Hash
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let root = execute(bytes, |_| Ok(()))?;
+    check_hash(&root, expected_hash)?;
+
+    let mut items = vec![];
+    collect_items(&root, &mut items);
+
+    let mut entries = vec![];
+    let mut last_key: Option<&[u8]> = None;
+    for (i, item) in items.iter().enumerate() {
+        match *item {
+            Item::KV(key, _) | Item::Key(key) => {
+                // keys should always be increasing
+                if let Some(last_key) = last_key {
+                    if key <= last_key {
+                        bail!("Incorrect key ordering");
+                    }
+                }
+                last_key = Some(key);
+
+                if query.contains(key) {
+                    match *item {
+                        Item::KV(_, value) => entries.push((key.to_vec(), value.to_vec())),
+                        _ => bail!("Proof is missing the value of a key selected by the query")
+                    }
+                }
+            },
+            Item::Opaque => {
+                // the keys hidden in this part of the proof are the keys
+                // between the key/value pairs around it, which must not be
+                // selected by the query
+                let next_key = items[i + 1..].iter()
+                    .find_map(|item| match item {
+                        Item::KV(key, _) | Item::Key(key) => Some(*key),
+                        Item::Opaque => None
+                    });
+                let lower = last_key.map_or(Unbounded, Excluded);
+                let upper = next_key.map_or(Unbounded, Excluded);
+                if intersects(&query.items, lower, upper) {
+                    bail!("Proof skipped keys selected by the query");
+                }
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Verifies an encoded proof of a query of the keys starting with `prefix`
+/// (as created by `Prefixed::prove_query`) against the expected root hash. The
+/// query's keys are relative to the prefix, and the returned key/value pairs
+/// have the prefix removed from their keys.
+pub fn verify_prefixed_query(
+    bytes: &[u8],
+    prefix: &[u8],
+    query: &Query,
+    expected_hash: Hash
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let entries = verify_query(bytes, &query.with_prefix(prefix), expected_hash)?;
+    Ok(entries.into_iter()
+        .map(|(key, value)| (key[prefix.len()..].to_vec(), value))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::{encode_into, Node};
+    use crate::tree::{self, NoopCommit, PanicSource};
+
+    fn make_tree() -> tree::Tree {
+        let mut tree = tree::Tree::new(vec![5], vec![5])
+            .attach(true, Some(
+                tree::Tree::new(vec![2], vec![2])
+                    .attach(true, Some(tree::Tree::new(vec![1], vec![1])))
+                    .attach(false, Some(tree::Tree::new(vec![3], vec![3])))
+            ))
+            .attach(false, Some(
+                tree::Tree::new(vec![8], vec![8])
+                    .attach(true, Some(tree::Tree::new(vec![7], vec![7])))
+                    .attach(false, Some(tree::Tree::new(vec![9], vec![9])))
+            ));
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        tree
+    }
+
+    fn prove(tree: &tree::Tree, query: &Query) -> Vec<u8> {
+        let walker = RefWalker::new(tree, PanicSource {});
+        let (proof, _) = walker.create_query_proof(query.items())
+            .expect("create_query_proof failed");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        bytes
+    }
+
+    fn keys(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Vec<u8> {
+        entries.into_iter().map(|(key, _)| key[0]).collect()
+    }
+
+    #[test]
+    fn normalize() {
+        let mut query = Query::new();
+        query.insert_key(vec![5]);
+        query.insert_key(vec![1]);
+        query.insert_key(vec![5]);
+        assert_eq!(query.items(), &[
+            QueryItem::Key(vec![1]),
+            QueryItem::Key(vec![5])
+        ]);
+
+        // overlapping and adjacent items are merged
+        query.insert_range(vec![2]..vec![5]);
+        assert_eq!(query.items(), &[
+            QueryItem::Key(vec![1]),
+            QueryItem::Range(Included(vec![2]), Included(vec![5]))
+        ]);
+        query.insert_range_inclusive(vec![0]..=vec![2]);
+        assert_eq!(query.items(), &[
+            QueryItem::Range(Included(vec![0]), Included(vec![5]))
+        ]);
+
+        // exclusive bounds at the same key are not adjacent
+        query.insert_item(QueryItem::Range(Excluded(vec![6]), Unbounded));
+        query.insert_range_to(..vec![6]);
+        assert_eq!(query.items(), &[
+            QueryItem::Range(Unbounded, Excluded(vec![6])),
+            QueryItem::Range(Excluded(vec![6]), Unbounded)
+        ]);
+        assert!(!query.contains(&[6]));
+        assert!(query.contains(&[5, 0]));
+
+        query.insert_key(vec![6]);
+        assert_eq!(query.items(), &[QueryItem::Range(Unbounded, Unbounded)]);
+
+        // empty ranges are ignored
+        let mut query = Query::new();
+        query.insert_range(vec![3]..vec![3]);
+        query.insert_range_inclusive(vec![4]..=vec![3]);
+        assert!(query.is_empty());
+    }
+
+    #[test]
+    fn prefix() {
+        let mut query = Query::new();
+        query.insert_prefix(vec![1, 2]);
+        assert_eq!(query.items(), &[
+            QueryItem::Range(Included(vec![1, 2]), Excluded(vec![1, 3]))
+        ]);
+
+        let mut query = Query::new();
+        query.insert_prefix(vec![1, 0xff]);
+        assert_eq!(query.items(), &[
+            QueryItem::Range(Included(vec![1, 0xff]), Excluded(vec![2]))
+        ]);
+        assert!(query.contains(&[1, 0xff, 0xff]));
+        assert!(!query.contains(&[2]));
+
+        let mut query = Query::new();
+        query.insert_prefix(vec![0xff]);
+        assert_eq!(query.items(), &[
+            QueryItem::Range(Included(vec![0xff]), Unbounded)
+        ]);
+    }
+
+    #[test]
+    fn query_proofs() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        let cases: Vec<(Vec<QueryItem>, Vec<u8>)> = vec![
+            (vec![], vec![]),
+            (vec![QueryItem::Key(vec![3])], vec![3]),
+            (vec![QueryItem::Key(vec![4])], vec![]),
+            (vec![QueryItem::Key(vec![0]), QueryItem::Key(vec![10])], vec![]),
+            (vec![QueryItem::Range(Included(vec![2]), Excluded(vec![7]))], vec![2, 3, 5]),
+            (vec![QueryItem::Range(Excluded(vec![2]), Included(vec![7]))], vec![3, 5, 7]),
+            (vec![QueryItem::Range(Included(vec![4]), Excluded(vec![5]))], vec![]),
+            (vec![QueryItem::Range(Included(vec![6]), Unbounded)], vec![7, 8, 9]),
+            (vec![QueryItem::Range(Unbounded, Included(vec![2]))], vec![1, 2]),
+            (vec![QueryItem::Range(Unbounded, Unbounded)], vec![1, 2, 3, 5, 7, 8, 9]),
+            (
+                vec![
+                    QueryItem::Key(vec![1]),
+                    QueryItem::Range(Included(vec![3]), Included(vec![5])),
+                    QueryItem::Range(Excluded(vec![8]), Unbounded)
+                ],
+                vec![1, 3, 5, 9]
+            )
+        ];
+        for (items, expected) in cases {
+            let mut query = Query::new();
+            for item in items {
+                query.insert_item(item);
+            }
+
+            let bytes = prove(&tree, &query);
+            let entries = verify_query(&bytes, &query, hash)
+                .expect("verify failed");
+            assert_eq!(keys(entries), expected);
+        }
+    }
+
+    #[test]
+    fn query_proof_skipped_keys() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        let mut query = Query::new();
+        query.insert_key(vec![3]);
+        let bytes = prove(&tree, &query);
+
+        // the proof does not contain the other keys of a larger query
+        let mut query = Query::new();
+        query.insert_range(vec![3]..vec![6]);
+        assert!(verify_query(&bytes, &query, hash).is_err());
+
+        let mut query = Query::new();
+        query.insert_key(vec![9]);
+        assert!(verify_query(&bytes, &query, hash).is_err());
+    }
+
+    #[test]
+    fn query_proof_digests() {
+        let tree = make_tree();
+        let hash = tree.hash();
+
+        // the nodes around 6 are only included as keys
+        let mut query = Query::new();
+        query.insert_key(vec![6]);
+        let walker = RefWalker::new(&tree, PanicSource {});
+        let (proof, _) = walker.create_query_proof(query.items())
+            .expect("create_query_proof failed");
+        assert!(!proof.iter().any(|op| matches!(op, Op::Push(Node::KV(..)))));
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+        assert!(verify_query(&bytes, &query, hash).expect("verify failed").is_empty());
+
+        let mut query = Query::new();
+        query.insert_range_inclusive(vec![6]..=vec![7]);
+        assert!(verify_query(&bytes, &query, hash).is_err());
+    }
+}
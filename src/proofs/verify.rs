@@ -1,11 +1,11 @@
 use super::{Op, Node};
-use crate::tree::{NULL_HASH, Hash, kv_hash, node_hash};
+use crate::tree::{NULL_HASH, Hash, kv_hash, kv_digest_hash, node_hash, aggregate_hash};
 use crate::error::Result;
 
 /// A binary tree data structure used to represent a select subset of a tree
 /// when verifying Merkle proofs.
-struct Tree {
-    node: Node,
+pub(super) struct Tree {
+    pub(super) node: Node,
     left: Option<Box<Tree>>,
     right: Option<Box<Tree>>
 }
@@ -18,7 +18,7 @@ impl From<Node> for Tree {
 
 impl Tree {
     /// Returns an immutable reference to the child on the given side, if any.
-    fn child(&self, left: bool) -> Option<&Box<Tree>> {
+    pub(super) fn child(&self, left: bool) -> Option<&Box<Tree>> {
         if left {
             self.left.as_ref()
         } else {
@@ -35,83 +35,104 @@ impl Tree {
         }
     }
 
-    /// Attaches the child to the `Tree`'s given side, and calculates its hash.
-    /// Panics if there is already a child attached to this side.
+    /// Attaches the child to the `Tree`'s given side. Returns an error if there
+    /// is already a child attached to this side, or if this node is a hash
+    /// (which already commits to its children).
     fn attach(&mut self, left: bool, child: Tree) -> Result<()> {
         if self.child(left).is_some() {
             bail!("Tried to attach to left child, but it is already Some");
         }
 
-        let child = child.into_hash();
+        match self.node {
+            Node::Hash(_) | Node::HashWithSum(_, _) | Node::HashWithCount(_, _) => {
+                bail!("Tried to attach child to hash node");
+            },
+            _ => {}
+        }
+
         let boxed = Box::new(child);
         *self.child_mut(left) = Some(boxed);
         Ok(())
     }
 
-    /// Gets the already-computed hash for this tree node. Panics if the hash
-    /// has not already been calculated.
-    #[inline]
-    fn hash(&self) -> Hash {
-        match self.node {
-            Node::Hash(hash) => hash,
-            _ => unreachable!("Expected Node::Hash")
-        }
+    /// Computes the hash of this tree node from its data and the hashes of its
+    /// children.
+    pub(super) fn hash(&self) -> Hash {
+        let left = self.child_hash(true);
+        let right = self.child_hash(false);
+
+        let count = self.node.count();
+        let sum = self.node.sum();
+
+        let hash = match &self.node {
+            Node::Hash(hash) => return *hash,
+            Node::HashWithSum(hash, _) => *hash,
+            Node::HashWithCount(hash, _) => *hash,
+            Node::KVHash(kv_hash)
+            | Node::KVHashWithSum(kv_hash, _)
+            | Node::KVHashWithCount(kv_hash, _) => {
+                node_hash(kv_hash, &left, &right)
+            },
+            Node::KV(key, value)
+            | Node::KVWithSum(key, value, _)
+            | Node::KVWithCount(key, value, _) => {
+                let kv_hash = kv_hash(key.as_slice(), value.as_slice());
+                node_hash(&kv_hash, &left, &right)
+            },
+            Node::KVDigest(key, value_hash)
+            | Node::KVDigestWithSum(key, value_hash, _)
+            | Node::KVDigestWithCount(key, value_hash, _) => {
+                let kv_hash = kv_digest_hash(key.as_slice(), value_hash);
+                node_hash(&kv_hash, &left, &right)
+            }
+        };
+
+        aggregate_hash(hash, count, sum)
     }
 
-    /// Returns the already-computed hash for this tree node's child on the
-    /// given side, if any. If there is no child, returns the null hash
-    /// (zero-filled).
+    /// Computes the hash of this tree node's child on the given side, if any.
+    /// If there is no child, returns the null hash (zero-filled).
     #[inline]
     fn child_hash(&self, left: bool) -> Hash {
         self.child(left)
             .map_or(NULL_HASH, |c| c.hash())
     }
+}
+
+/// A node of a proof tree in key order, either a key/value pair, a key whose
+/// value is only included as a hash, or a part of the tree which is only
+/// included as a hash.
+pub(super) enum Item<'a> {
+    KV(&'a [u8], &'a [u8]),
+    Key(&'a [u8]),
+    Opaque
+}
+
+/// Pushes the nodes of the proof tree onto `items`, in key order.
+pub(super) fn collect_items<'a>(tree: &'a Tree, items: &mut Vec<Item<'a>>) {
+    if let Some(child) = tree.child(true) {
+        collect_items(child, items);
+    }
 
-    /// Consumes the tree node, calculates its hash, and returns a `Node::Hash`
-    /// variant.
-    fn into_hash(self) -> Tree {
-        fn to_hash_node(tree: &Tree, kv_hash: Hash) -> Node {
-            let hash = node_hash(
-                &kv_hash,
-                &tree.child_hash(true),
-                &tree.child_hash(false)
-            );
-            Node::Hash(hash)
-        }
+    items.push(match (tree.node.kv(), tree.node.key()) {
+        (Some((key, value)), _) => Item::KV(key, value),
+        (None, Some(key)) => Item::Key(key),
+        (None, None) => Item::Opaque
+    });
 
-        match &self.node {
-            Node::Hash(hash) => self.node,
-            Node::KVHash(kv_hash) => to_hash_node(&self, *kv_hash),
-            Node::KV(key, value) => {
-                let kv_hash = kv_hash(key.as_slice(), value.as_slice());
-                to_hash_node(&self, kv_hash)
-            }
-        }.into()
+    if let Some(child) = tree.child(false) {
+        collect_items(child, items);
     }
 }
 
-/// Verifies the encoded proof with the given query and expected hash.
-///
-/// Every key in `keys` is checked to either have a key/value pair in the proof,
-/// or to have its absence in the tree proven.
-///
-/// Returns `Err` if the proof is invalid, or a list of proven values associated
-/// with `keys`. For example, if `keys` contains keys `A` and `B`, the returned
-/// list will contain 2 elements, the value of `A` and the value of `B`. Keys
-/// proven to be absent in the tree will have an entry of `None`, keys that have
-/// a proven value will have an entry of `Some(value)`.
-pub fn verify(
-    bytes: &[u8],
-    keys: &[Vec<u8>],
-    expected_hash: Hash
-) -> Result<Vec<Option<Vec<u8>>>> {
+/// Executes the encoded proof, returning the tree it describes. `visit_node`
+/// is called with each pushed node, in the order they appear in the proof.
+pub(super) fn execute<F>(bytes: &[u8], mut visit_node: F) -> Result<Tree>
+    where F: FnMut(&Node) -> Result<()>
+{
     // TODO: enforce a maximum proof size
 
     let mut stack: Vec<Tree> = Vec::with_capacity(32);
-    let mut output = Vec::with_capacity(keys.len());
-
-    let mut key_index = 0;
-    let mut last_push = None;
 
     fn try_pop(stack: &mut Vec<Tree>) -> Result<Tree> {
         match stack.pop() {
@@ -147,48 +168,92 @@ pub fn verify(
                 stack.push(parent);
             },
             Op::Push(node) => {
-                let node_clone = node.clone();
-                let tree: Tree = node.into();
-                stack.push(tree);
-
-                if let Node::KV(key, value) = &node_clone {
-                    // keys should always be increasing
-                    if let Some(Node::KV(last_key, _)) = &last_push {
-                        if key <= &last_key {
-                            bail!("Incorrect key ordering");
-                        }
-                    }
+                visit_node(&node)?;
+                stack.push(node.into());
+            }
+        }
+    }
+
+    if stack.len() != 1 {
+        bail!("Expected proof to result in exactly one stack item");
+    }
 
-                    loop {
-                        if key_index >= keys.len() || key < &keys[key_index] {
-                            break;
-                        } else if key == &keys[key_index] {
-                            // KV for queried key
-                            output.push(Some(value.clone()));
-                        } else if key > &keys[key_index] {
-                            match &last_push {
-                                None | Some(Node::KV(_, _)) => {
-                                    // previous push was a boundary (global edge or lower key),
-                                    // so this is a valid absence proof
-                                    output.push(None);
-                                },
-                                // proof is incorrect since it skipped queried keys
-                                _ => bail!("Proof incorrectly formed")
-                            }
-                        }
-
-                        key_index += 1;
+    Ok(stack.pop().unwrap())
+}
+
+/// Checks that the tree built from a proof has the expected root hash.
+pub(super) fn check_hash(root: &Tree, expected_hash: Hash) -> Result<()> {
+    let hash = root.hash();
+    if hash != expected_hash {
+        bail!(
+            "Proof did not match expected hash\n\tExpected: {:?}\n\tActual: {:?}",
+            expected_hash, hash
+        );
+    }
+    Ok(())
+}
+
+/// Verifies the encoded proof with the given query and expected hash.
+///
+/// Every key in `keys` is checked to either have a key/value pair in the proof,
+/// or to have its absence in the tree proven.
+///
+/// Returns `Err` if the proof is invalid, or a list of proven values associated
+/// with `keys`. For example, if `keys` contains keys `A` and `B`, the returned
+/// list will contain 2 elements, the value of `A` and the value of `B`. Keys
+/// proven to be absent in the tree will have an entry of `None`, keys that have
+/// a proven value will have an entry of `Some(value)`.
+pub fn verify(
+    bytes: &[u8],
+    keys: &[Vec<u8>],
+    expected_hash: Hash
+) -> Result<Vec<Option<Vec<u8>>>> {
+    let mut output = Vec::with_capacity(keys.len());
+
+    let mut key_index = 0;
+    let mut last_push: Option<Node> = None;
+
+    let root = execute(bytes, |node| {
+        if let Some(key) = node.key() {
+            // keys should always be increasing
+            if let Some(last_key) = last_push.as_ref().and_then(Node::key) {
+                if key <= last_key {
+                    bail!("Incorrect key ordering");
+                }
+            }
+
+            loop {
+                if key_index >= keys.len() || key < &keys[key_index] {
+                    break;
+                } else if key == &keys[key_index] {
+                    // KV for queried key
+                    match node.kv() {
+                        Some((_, value)) => output.push(Some(value.clone())),
+                        None => bail!("Proof is missing value for queried key")
+                    }
+                } else if key > &keys[key_index] {
+                    match last_push.as_ref().map(|last| last.key().is_some()) {
+                        None | Some(true) => {
+                            // previous push was a boundary (global edge or lower key),
+                            // so this is a valid absence proof
+                            output.push(None);
+                        },
+                        // proof is incorrect since it skipped queried keys
+                        _ => bail!("Proof incorrectly formed")
                     }
                 }
 
-                last_push = Some(node_clone);
+                key_index += 1;
             }
         }
-    }
+
+        last_push = Some(node.clone());
+        Ok(())
+    })?;
 
     // absence proofs for right edge
     if key_index < keys.len() {
-        if let Some(Node::KV(_, _)) = last_push {
+        if last_push.as_ref().and_then(Node::key).is_some() {
             for _ in 0..(keys.len() - key_index) {
                 output.push(None);
             }
@@ -199,18 +264,7 @@ pub fn verify(
         debug_assert_eq!(keys.len(), output.len());
     }
 
-    if stack.len() != 1 {
-        bail!("Expected proof to result in exactly one stack item");
-    }
-
-    let root = stack.pop().unwrap();
-    let hash = root.into_hash().hash();
-    if hash != expected_hash {
-        bail!(
-            "Proof did not match expected hash\n\tExpected: {:?}\n\tActual: {:?}",
-            expected_hash, hash
-        );
-    }
+    check_hash(&root, expected_hash)?;
 
     Ok(output)
 }
@@ -219,7 +273,7 @@ pub fn verify(
 mod test {
     use super::*;
     use super::super::*;
-    use crate::tree::{NoopCommit, RefWalker, PanicSource};
+    use crate::tree::{NoopCommit, RefWalker, PanicSource, legacy_kv_hash};
     use crate::tree;
 
     fn make_3_node_tree() -> tree::Tree {
@@ -232,20 +286,29 @@ mod test {
     }
 
     fn verify_test(keys: Vec<Vec<u8>>, expected_result: Vec<Option<Vec<u8>>>) {
-        let mut tree = make_3_node_tree();
-        let mut walker = RefWalker::new(&mut tree, PanicSource {});
+        let tree = make_3_node_tree();
+        let walker = RefWalker::new(&tree, PanicSource {});
 
         let (proof, _) = walker.create_proof(keys.as_slice())
             .expect("failed to create proof");
         let mut bytes = vec![];
         encode_into(proof.iter(), &mut bytes);
 
-        let expected_hash = [65, 23, 96, 10, 165, 42, 240, 100, 206, 125, 192, 81, 44, 89, 119, 39, 35, 215, 211, 24];
+        let expected_hash = [254, 9, 123, 119, 208, 60, 152, 146, 113, 130, 85, 25, 182, 229, 17, 189, 160, 147, 152, 68];
         let result = verify(bytes.as_slice(), keys.as_slice(), expected_hash)
             .expect("verify failed");
         assert_eq!(result, expected_result);
     }
 
+    #[test]
+    fn legacy_root_hash() {
+        // the root hash of the same tree before format version 1
+        let leaf_hash = |n| node_hash(&legacy_kv_hash(&[n], &[n]), &NULL_HASH, &NULL_HASH);
+        let hash = node_hash(&legacy_kv_hash(&[5], &[5]), &leaf_hash(3), &leaf_hash(7));
+        assert_eq!(hash, [65, 23, 96, 10, 165, 42, 240, 100, 206, 125, 192, 81, 44, 89, 119, 39, 35, 215, 211, 24]);
+        assert_ne!(hash, make_3_node_tree().hash());
+    }
+
     #[test]
     fn root_verify() {
         verify_test(vec![ vec![5] ], vec![ Some(vec![5]) ]);
@@ -311,5 +374,20 @@ mod test {
            vec![ Some(vec![5]), None ]
         );
     }
-}
 
+    #[test]
+    fn digest_for_queried_key() {
+        let tree = make_3_node_tree();
+        let walker = RefWalker::new(&tree, PanicSource {});
+
+        // the boundaries of the absence proof only include their keys
+        let (proof, _) = walker.create_proof(&[vec![6]])
+            .expect("failed to create proof");
+        let mut bytes = vec![];
+        encode_into(proof.iter(), &mut bytes);
+
+        let err = verify(&bytes, &[vec![5]], tree.hash()).unwrap_err();
+        assert_eq!(err.to_string(), "Proof is missing value for queried key");
+        assert!(verify(&bytes, &[vec![7]], tree.hash()).is_err());
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use byteorder::{BigEndian, ByteOrder};
+use crate::error::Result;
+use crate::merk::MerkOptions;
+use crate::tree::{
+    Tree,
+    Link,
+    Fetch,
+    Walker,
+    AggregateCommit,
+    Batch,
+    Hash,
+    HASH_LENGTH,
+    NULL_HASH,
+    kv_hash,
+    aggregate_hash,
+    check_sorted
+};
+
+/// The maximum height of a tree, since heights are stored as a `u8`.
+const MAX_HEIGHT: usize = 255;
+
+/// Encodes the nodes touched by a batch as a witness, each as its key length
+/// (`u8`), its key, its encoding length (big-endian `u32`), then its encoding
+/// as stored in the backing store. The root node comes first.
+///
+/// If the store maintains sums or counts, each node is followed by the
+/// `node_hash` of each of its children (left first, as computed by
+/// `child_node_hash`), so that the aggregates in its links can be checked
+/// against the links' hashes.
+pub(crate) fn encode_witness<F>(
+    nodes: &[Tree],
+    aggregates: bool,
+    mut child_node_hash: F
+) -> Result<Vec<u8>>
+    where F: FnMut(&Link) -> Result<Hash>
+{
+    let mut bytes = vec![];
+    let mut length = [0; 4];
+    for node in nodes {
+        bytes.push(node.key().len() as u8);
+        bytes.extend_from_slice(node.key());
+        BigEndian::write_u32(&mut length, node.encoding_length() as u32);
+        bytes.extend_from_slice(&length);
+        node.encode_into(&mut bytes);
+
+        if aggregates {
+            for link in [true, false].iter().filter_map(|left| node.link(*left)) {
+                bytes.extend_from_slice(&child_node_hash(link)?);
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+/// Decodes the nodes of a witness, checking that each node's `kv_hash` matches
+/// its key and value, and if the store maintains sums or counts, that the
+/// aggregates in each node's links match the links' hashes.
+fn decode_witness(mut bytes: &[u8], aggregates: bool) -> Result<Vec<Tree>> {
+    fn take<'a>(bytes: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+        if bytes.len() < length {
+            bail!("Unexpected end of witness");
+        }
+        let (taken, rest) = bytes.split_at(length);
+        *bytes = rest;
+        Ok(taken)
+    }
+
+    let mut nodes = vec![];
+    while !bytes.is_empty() {
+        let key_length = take(&mut bytes, 1)?[0] as usize;
+        let key = take(&mut bytes, key_length)?;
+        let node_length = BigEndian::read_u32(take(&mut bytes, 4)?) as usize;
+        let node = Tree::decode(key, take(&mut bytes, node_length)?)?;
+
+        if kv_hash(node.key(), node.value()) != *node.kv_hash() {
+            bail!("Witness node has invalid kv_hash");
+        }
+
+        if aggregates {
+            // a node's hash only commits to the totals of its subtree, so the
+            // amounts its links split between its children are checked
+            // against the children's hashes
+            for link in [true, false].iter().filter_map(|left| node.link(*left)) {
+                let mut child_node_hash = NULL_HASH;
+                child_node_hash.copy_from_slice(take(&mut bytes, HASH_LENGTH)?);
+                let hash = aggregate_hash(child_node_hash, link.count(), link.sum());
+                if hash != *link.hash() {
+                    bail!("Witness node has invalid aggregates for child {:?}", link.key());
+                }
+            }
+        }
+        nodes.push(node);
+    }
+    Ok(nodes)
+}
+
+/// Assembles the partial tree of the witness nodes, attaching each node to
+/// the parent whose link points to its key. Children which are not in the
+/// witness are left pruned.
+fn assemble(nodes: Vec<Tree>) -> Result<Option<Tree>> {
+    let mut nodes = nodes.into_iter();
+    let root = match nodes.next() {
+        None => return Ok(None),
+        Some(root) => root
+    };
+
+    let mut by_key = BTreeMap::new();
+    for node in nodes {
+        let key = node.key().to_vec();
+        if by_key.insert(key, node).is_some() {
+            bail!("Witness contains a node more than once");
+        }
+    }
+
+    let tree = attach_children(root, &mut by_key, 1)?;
+    if !by_key.is_empty() {
+        bail!("Witness contains nodes which are not in the tree");
+    }
+    Ok(Some(tree))
+}
+
+fn attach_children(
+    mut tree: Tree,
+    nodes: &mut BTreeMap<Vec<u8>, Tree>,
+    depth: usize
+) -> Result<Tree> {
+    if depth > MAX_HEIGHT {
+        bail!("Witness tree is too tall");
+    }
+
+    for left in &[true, false] {
+        let child_key = match tree.link(*left) {
+            None => continue,
+            Some(link) => link.key().to_vec()
+        };
+        if let Some(child) = nodes.remove(&child_key) {
+            let child = attach_children(child, nodes, depth + 1)?;
+            tree = tree.detach(*left).0.attach(*left, Some(child));
+        }
+    }
+    Ok(tree)
+}
+
+/// A source for the witness tree, which fails on any node not included in the
+/// witness.
+#[derive(Clone)]
+struct WitnessSource {}
+
+impl Fetch for WitnessSource {
+    fn fetch(&self, link: &Link) -> Result<Tree> {
+        bail!("Witness does not contain node {:?}", link.key())
+    }
+}
+
+/// Computes the root hash of a store after applying a batch, given a witness
+/// for the batch (as created by `Merk::apply_with_witness`) and the store's
+/// root hash before the batch. Only `sum_fn` and `counts` are used from
+/// `options`, which must match the options of the store.
+///
+/// The witness is checked against `expected_hash`, then the batch is replayed
+/// on the partial tree with the same insert, delete and rebalancing logic as
+/// `Merk::apply`. Fails if the witness does not match, if the batch touches a
+/// node which is not in the witness, or if the batch would fail to apply.
+///
+/// **NOTE:** Node hashes commit to the sums and counts of subtrees, but not to
+/// their heights, so the heights of the subtrees which are not in the witness
+/// can not be checked. Since they decide how the tree is rebalanced, a witness
+/// with incorrect heights gives a tree with the same entries but a different
+/// shape, and so a different root hash. The returned hash can only be trusted
+/// if the witness comes from a trusted source: a prover can not use a witness
+/// to show that a root hash it did not compute itself is wrong.
+pub fn apply_to_proof(
+    witness: &[u8],
+    batch: &Batch,
+    expected_hash: Hash,
+    options: &MerkOptions
+) -> Result<Hash> {
+    check_sorted(batch)?;
+
+    let committer = || AggregateCommit {
+        sum_fn: options.sum_fn,
+        counts: options.counts
+    };
+
+    let aggregates = options.sum_fn.is_some() || options.counts;
+    let maybe_tree = match assemble(decode_witness(witness, aggregates)?)? {
+        None => None,
+        Some(mut tree) => {
+            // recompute hashes of the witness nodes from the pruned subtrees
+            tree.commit(&mut committer())?;
+            Some(tree)
+        }
+    };
+    let hash = maybe_tree.as_ref().map_or(NULL_HASH, |tree| tree.hash());
+    if hash != expected_hash {
+        bail!(
+            "Witness did not match expected hash\n\tExpected: {:?}\n\tActual: {:?}",
+            expected_hash,
+            hash
+        );
+    }
+
+    let mut batch = batch.to_vec();
+    let maybe_walker = maybe_tree.map(|tree| Walker::new(tree, WitnessSource {}));
+    let (maybe_tree, _) = Walker::apply_to(maybe_walker, batch.as_mut_slice())?;
+
+    match maybe_tree {
+        None => Ok(NULL_HASH),
+        Some(mut tree) => {
+            tree.commit(&mut committer())?;
+            Ok(tree.hash())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tree::NoopCommit;
+
+    #[test]
+    fn witness_encoding() {
+        let mut tree = Tree::new(vec![5], vec![5])
+            .attach(true, Some(Tree::new(vec![3], vec![3])));
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        let child = tree.child(true).unwrap().clone();
+
+        let bytes = encode(&[tree.clone(), child]);
+        let nodes = decode_witness(&bytes, false).expect("decode failed");
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[1].key(), &[3]);
+
+        let assembled = assemble(nodes).unwrap().unwrap();
+        assert_eq!(assembled.child(true).unwrap().key(), &[3]);
+        assert_eq!(committed_hash(assembled), tree.hash());
+
+        assert!(decode_witness(&bytes[..bytes.len() - 1], false).is_err());
+
+        // a node with a value which does not match its kv_hash
+        let mut tampered = bytes.clone();
+        tampered[2 + 4 + 2] = 6;
+        assert!(decode_witness(&tampered, false).is_err());
+
+        // nodes which are not reachable from the root
+        let other = Tree::new(vec![8], vec![8]);
+        let bytes = encode(&[tree.clone(), other]);
+        assert!(assemble(decode_witness(&bytes, false).unwrap()).is_err());
+
+        let bytes = encode(&[]);
+        assert!(assemble(decode_witness(&bytes, false).unwrap()).unwrap().is_none());
+    }
+
+    fn encode(nodes: &[Tree]) -> Vec<u8> {
+        encode_witness(nodes, false, |link| Ok(*link.hash())).expect("encode failed")
+    }
+
+    fn committed_hash(mut tree: Tree) -> Hash {
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+        tree.hash()
+    }
+}
@@ -41,8 +41,10 @@ pub fn assert_tree_invariants(tree: &Tree) {
 
 pub fn apply_memonly_unchecked(tree: Tree, batch: &Batch) -> Tree {
     let walker = Walker::<PanicSource>::new(tree, PanicSource {});
-    let mut tree = Walker::<PanicSource>::apply_to(Some(walker), batch)
+    let mut batch = batch.to_vec();
+    let mut tree = Walker::<PanicSource>::apply_to(Some(walker), &mut batch)
         .expect("apply failed")
+        .0
         .expect("expected tree");
     tree.commit(&mut NoopCommit {})
         .expect("commit failed");
@@ -59,8 +61,10 @@ pub fn apply_to_memonly(maybe_tree: Option<Tree>, batch: &Batch) -> Option<Tree>
     let maybe_walker = maybe_tree.map(|tree| {
         Walker::<PanicSource>::new(tree, PanicSource {})
     });
-    Walker::<PanicSource>::apply_to(maybe_walker, batch)
+    let mut batch = batch.to_vec();
+    Walker::<PanicSource>::apply_to(maybe_walker, &mut batch)
         .expect("apply failed")
+        .0
         .map(|mut tree| {
             tree.commit(&mut NoopCommit {}).expect("commit failed");
             println!("{:?}", &tree);
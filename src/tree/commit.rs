@@ -15,6 +15,19 @@ pub trait Commit {
     fn prune(&self, _tree: &Tree) -> (bool, bool) {
         (true, true)
     }
+
+    /// Called once per node before writing it, to get the amount the node's
+    /// value contributes to the sum of its subtree. Returning `None` (the
+    /// default) means the tree does not maintain sums.
+    fn value_sum(&self, _value: &[u8]) -> Option<u64> {
+        None
+    }
+
+    /// Returns whether each node should keep the number of nodes in its
+    /// subtree. Defaults to `false`.
+    fn counts(&self) -> bool {
+        false
+    }
 }
 
 /// A `Commit` implementation which does not write to a store and does not prune
@@ -29,3 +42,30 @@ impl Commit for NoopCommit {
         (false, false)
     }
 }
+
+/// A `Commit` implementation which, like `NoopCommit`, does not write to a store
+/// and does not prune any nodes, but maintains the same aggregates as a store
+/// (see `Commit::value_sum` and `Commit::counts`), so that the hash of an
+/// uncommitted tree matches the hash the store would compute.
+pub struct AggregateCommit {
+    pub sum_fn: Option<fn(&[u8]) -> u64>,
+    pub counts: bool
+}
+
+impl Commit for AggregateCommit {
+    fn write(&mut self, _tree: &Tree) -> Result<()> {
+        Ok(())
+    }
+
+    fn prune(&self, _tree: &Tree) -> (bool, bool) {
+        (false, false)
+    }
+
+    fn value_sum(&self, value: &[u8]) -> Option<u64> {
+        self.sum_fn.map(|sum_fn| sum_fn(value))
+    }
+
+    fn counts(&self) -> bool {
+        self.counts
+    }
+}
@@ -1,4 +1,5 @@
 use std::convert::TryInto;
+use byteorder::{BigEndian, ByteOrder};
 
 use super::{Tree, Link, Hash};
 use crate::error::Result;
@@ -11,7 +12,7 @@ impl Link {
     /// Pushes a bianry encoding of the `Link` into the given byte vector.
     pub fn encode_into(&self, output: &mut Vec<u8>) {
         let (hash, key, (left_height, right_height)) = match self {
-            Link::Pruned { hash, key, child_heights } => (hash, key.as_slice(), child_heights),
+            Link::Pruned { hash, key, child_heights, .. } => (hash, key.as_slice(), child_heights),
             Link::Stored { hash, tree, child_heights } => (hash, tree.key(), child_heights),
             Link::Modified { .. } => panic!("No encoding for Link::Modified")
         };
@@ -41,6 +42,10 @@ impl Link {
         let length = bytes[offset];
         offset += 1;
 
+        if bytes.len() < offset + length as usize + 20 + 2 {
+            bail!("Unexpected end of link encoding");
+        }
+
         let key = bytes[offset..offset + length as usize].to_vec();
         offset += length as usize;
 
@@ -51,7 +56,7 @@ impl Link {
         let child_heights = (bytes[offset], bytes[offset + 1]);
         // offset += 2;
 
-        Ok(Link::Pruned { key, hash, child_heights })
+        Ok(Link::Pruned { key, hash, sum: None, count: None, child_heights })
     }
 }
 
@@ -75,6 +80,48 @@ impl Tree {
             None => output.push(0),
             Some(link) => link.encode_into(output)
         }
+
+        // trees which maintain aggregates have a trailer with a byte of flags
+        // for which aggregates follow, then for each one its value for the
+        // tree and for each child which is present
+        let flags = self.aggregate_flags();
+        if flags != 0 {
+            output.push(flags);
+        }
+        if let Some(sum) = self.sum() {
+            write_u64(output, sum);
+            for link in self.links() {
+                let child_sum = link.sum()
+                    .expect("Child of tree with sum does not have sum");
+                write_u64(output, child_sum);
+            }
+        }
+        if let Some(count) = self.count() {
+            write_u64(output, count);
+            for link in self.links() {
+                let child_count = link.count()
+                    .expect("Child of tree with count does not have count");
+                write_u64(output, child_count);
+            }
+        }
+    }
+
+    /// Returns the flags written at the start of the aggregate trailer.
+    fn aggregate_flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.sum().is_some() {
+            flags |= SUM_FLAG;
+        }
+        if self.count().is_some() {
+            flags |= COUNT_FLAG;
+        }
+        flags
+    }
+
+    /// Returns an iterator over the root node's links which are present, left
+    /// first.
+    fn links(&self) -> impl Iterator<Item=&Link> {
+        self.link(true).into_iter().chain(self.link(false).into_iter())
     }
 
     /// Returns the size of the `Tree`'s binary encoding, in bytes.
@@ -83,18 +130,37 @@ impl Tree {
         self.inner.kv.value().len() + // value bytes
         20 + // kv_hash length
         self.link(true).map_or(1, |link| link.encoding_length()) +
-        self.link(false).map_or(1, |link| link.encoding_length())
+        self.link(false).map_or(1, |link| link.encoding_length()) +
+        self.aggregates_encoding_length()
+    }
+
+    /// Returns the size of the trailer containing the tree's aggregates, if
+    /// any.
+    fn aggregates_encoding_length(&self) -> usize {
+        let flags = self.aggregate_flags();
+        if flags == 0 {
+            return 0;
+        }
+        let aggregate_count = flags.count_ones() as usize;
+        1 + aggregate_count * 8 * (1 + self.links().count())
     }
 
     /// Decodes a `Tree` from its binary encoding.
     pub fn decode(key: &[u8], bytes: &[u8]) -> Result<Tree> {
         let mut offset = 0;
 
+        if bytes.len() < 2 {
+            bail!("Unexpected end of tree encoding");
+        }
         let value_len =
             bytes[offset] as usize
             + ((bytes[offset + 1] as usize) << 8);
         offset += 2;
 
+        // value, kv_hash, and at least one byte for each link
+        if bytes.len() < offset + value_len + 20 + 2 {
+            bail!("Unexpected end of tree encoding");
+        }
         let value = bytes[offset..offset + value_len].to_vec();
         offset += value_len;
 
@@ -112,29 +178,83 @@ impl Tree {
             None
         };
 
+        if offset >= bytes.len() {
+            bail!("Unexpected end of tree encoding");
+        }
         let link_length = bytes[offset];
         let right = if link_length > 0 {
             let link = Link::decode(&bytes[offset..])?;
-            // offset += link.encoding_length();
+            offset += link.encoding_length();
             Some(link)
         } else {
-            // offset += 1;
+            offset += 1;
             None
         };
 
-        Ok(Tree::from_fields(
+        let mut tree = Tree::from_fields(
             key.to_vec(),
             value,
             kv_hash,
             left,
             right
-        ))
+        );
+
+        if offset < bytes.len() {
+            let flags = bytes[offset];
+            offset += 1;
+
+            if flags & SUM_FLAG != 0 {
+                tree.inner.sum = Some(read_u64(bytes, &mut offset)?);
+                for left in &[true, false] {
+                    let child_sum = match tree.link(*left) {
+                        None => continue,
+                        Some(_) => read_u64(bytes, &mut offset)?
+                    };
+                    if let Some(Link::Pruned { sum, .. }) = tree.slot_mut(*left) {
+                        *sum = Some(child_sum);
+                    }
+                }
+            }
+
+            if flags & COUNT_FLAG != 0 {
+                tree.inner.count = Some(read_u64(bytes, &mut offset)?);
+                for left in &[true, false] {
+                    let child_count = match tree.link(*left) {
+                        None => continue,
+                        Some(_) => read_u64(bytes, &mut offset)?
+                    };
+                    if let Some(Link::Pruned { count, .. }) = tree.slot_mut(*left) {
+                        *count = Some(child_count);
+                    }
+                }
+            }
+        }
+
+        Ok(tree)
+    }
+}
+
+const SUM_FLAG: u8 = 0x01;
+const COUNT_FLAG: u8 = 0x02;
+
+fn write_u64(output: &mut Vec<u8>, n: u64) {
+    let mut bytes = [0; 8];
+    BigEndian::write_u64(&mut bytes, n);
+    output.extend_from_slice(&bytes);
+}
+
+fn read_u64(bytes: &[u8], offset: &mut usize) -> Result<u64> {
+    if bytes.len() < *offset + 8 {
+        bail!("Unexpected end of tree encoding");
     }
+    let n = BigEndian::read_u64(&bytes[*offset..*offset + 8]);
+    *offset += 8;
+    Ok(n)
 }
 
 #[cfg(test)]
 mod test {
-    use super::super::{Tree, Link};
+    use super::super::{Tree, Link, AggregateCommit};
 
     #[test]
     fn encode_leaf_tree() {
@@ -143,7 +263,7 @@ mod test {
 
         let mut bytes = vec![];
         tree.encode_into(&mut bytes);
-        assert_eq!(bytes, vec![1, 0, 1, 195, 201, 244, 70, 50, 255, 177, 215, 40, 246, 8, 69, 174, 17, 72, 99, 29, 112, 226, 212, 0, 0]);
+        assert_eq!(bytes, vec![1, 0, 1, 11, 84, 219, 169, 135, 208, 18, 203, 210, 139, 76, 6, 141, 118, 78, 40, 219, 123, 206, 43, 0, 0]);
     }
 
     #[test]
@@ -151,7 +271,9 @@ mod test {
         let link = Link::Pruned {
             key: vec![1, 2, 3],
             child_heights: (123, 124),
-            hash: [55; 20]
+            hash: [55; 20],
+            sum: None,
+            count: None
         };
         assert_eq!(link.encoding_length(), 26);
 
@@ -166,7 +288,9 @@ mod test {
         let link = Link::Pruned {
             key: vec![123; 300],
             child_heights: (123, 124),
-            hash: [55; 20]
+            hash: [55; 20],
+            sum: None,
+            count: None
         };
         let mut bytes = vec![];
         link.encode_into(&mut bytes);
@@ -214,6 +338,8 @@ mod test {
             [55; 20],
             Some(Link::Pruned {
                 hash: [66; 20],
+                sum: None,
+                count: None,
                 child_heights: (123, 124),
                 key: vec![2]
             }),
@@ -228,7 +354,7 @@ mod test {
 
     #[test]
     fn decode_leaf_tree() {
-        let bytes = vec![1, 0, 1, 195, 201, 244, 70, 50, 255, 177, 215, 40, 246, 8, 69, 174, 17, 72, 99, 29, 112, 226, 212, 0, 0];
+        let bytes = vec![1, 0, 1, 11, 84, 219, 169, 135, 208, 18, 203, 210, 139, 76, 6, 141, 118, 78, 40, 219, 123, 206, 43, 0, 0];
         let tree = Tree::decode(&[0], bytes.as_slice()).expect("decode failed");
         assert_eq!(tree.key(), &[0]);
         assert_eq!(tree.value(), &[1]);
@@ -240,7 +366,7 @@ mod test {
         let tree = Tree::decode(&[0], bytes.as_slice()).expect("decode failed");
         assert_eq!(tree.key(), &[0]);
         assert_eq!(tree.value(), &[1]);
-        if let Some(Link::Pruned { key, child_heights, hash }) = tree.link(true) {
+        if let Some(Link::Pruned { key, child_heights, hash, .. }) = tree.link(true) {
             assert_eq!(key, &[2]);
             assert_eq!(*child_heights, (123 as u8, 124 as u8));
             assert_eq!(hash, &[66; 20]);
@@ -248,4 +374,41 @@ mod test {
             panic!("Expected Link::Pruned");
         }
     }
+
+    #[test]
+    fn decode_truncated() {
+        let bytes = vec![1, 0, 1, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 55, 1, 2, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 66, 123, 124, 0];
+        for length in 0..bytes.len() {
+            assert!(Tree::decode(&[0], &bytes[..length]).is_err());
+        }
+    }
+
+    #[test]
+    fn encode_decode_aggregates() {
+        let mut tree = Tree::new(vec![5], vec![5])
+            .attach(true, Some(Tree::new(vec![3], vec![3])));
+        let mut committer = AggregateCommit {
+            sum_fn: Some(|value| value[0] as u64),
+            counts: true
+        };
+        tree.commit(&mut committer).expect("commit failed");
+        assert_eq!(tree.sum(), Some(8));
+        assert_eq!(tree.count(), Some(2));
+
+        // 1 flag byte, then sum and count for the tree and its left child
+        assert_eq!(tree.encoding_length(), 48 + 1 + 32);
+        let mut bytes = vec![];
+        tree.encode_into(&mut bytes);
+        assert_eq!(bytes.len(), tree.encoding_length());
+        assert_eq!(&bytes[48..], &[3, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 1][..]);
+
+        let decoded = Tree::decode(&[5], bytes.as_slice()).expect("decode failed");
+        assert_eq!(decoded.sum(), Some(8));
+        assert_eq!(decoded.count(), Some(2));
+        assert_eq!(decoded.link(true).unwrap().sum(), Some(3));
+        assert_eq!(decoded.link(true).unwrap().count(), Some(1));
+        assert_eq!(decoded.hash(), tree.hash());
+
+        assert!(Tree::decode(&[5], &bytes[..bytes.len() - 1]).is_err());
+    }
 }
@@ -122,8 +122,20 @@ fn apply_to_map(map: &mut Map, batch: &Batch) {
             (key, Op::Put(value)) => {
                 map.insert(key.to_vec(), value.to_vec());
             },
-            (key, Op::Delete) => {
+            (key, Op::Delete) | (key, Op::DeleteIfExists) => {
                 map.remove(key);
+            },
+            (key, Op::PutIfAbsent(value)) => {
+                map.entry(key.to_vec()).or_insert_with(|| value.to_vec());
+            },
+            (key, Op::CompareAndSwap(expected, new)) => {
+                if map.get(key) == Some(expected) {
+                    map.insert(key.to_vec(), new.to_vec());
+                }
+            },
+            (key, Op::Merge(merge)) => {
+                let value = merge(map.get(key).map(|value| value.as_slice()));
+                map.insert(key.to_vec(), value);
             }
         }
     }
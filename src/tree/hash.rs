@@ -11,11 +11,42 @@ pub const NULL_HASH: Hash = [0; HASH_LENGTH];
 /// A cryptographic hash digest.
 pub type Hash = [u8; HASH_LENGTH];
 
-/// Hashes a key/value pair.
+/// Hashes a key/value pair, by hashing the key along with the hash of the
+/// value (see `kv_digest_hash`).
 ///
 /// **NOTE:** This will panic if the key is longer than 255 bytes, or the value
 /// is longer than 65,535 bytes.
 pub fn kv_hash(key: &[u8], value: &[u8]) -> Hash {
+    kv_digest_hash(key, &value_hash(value))
+}
+
+/// Hashes a value.
+///
+/// **NOTE:** This will panic if the value is longer than 65,535 bytes.
+pub fn value_hash(value: &[u8]) -> Hash {
+    // TODO: result instead of panic
+    // TODO: make generic to allow other hashers
+    let mut hasher = Blake2b::new(HASH_LENGTH);
+
+    // panics if value is longer than 65535!
+    let val_length = u16::try_from(value.len())
+        .expect("value must be less than 65,536 bytes");
+    hasher.update(&val_length.to_be_bytes());
+    hasher.update(&value);
+
+    let res = hasher.finalize();
+    let mut hash: Hash = Default::default();
+    // TODO: if blake2 lib returned an array we wouldn't need this copy
+    hash.copy_from_slice(res.as_bytes());
+    hash
+}
+
+/// Hashes a key along with the hash of its value (as computed by
+/// `value_hash`). This lets a proof show a node's key without including its
+/// value.
+///
+/// **NOTE:** This will panic if the key is longer than 255 bytes.
+pub fn kv_digest_hash(key: &[u8], value_hash: &Hash) -> Hash {
     // TODO: result instead of panic
     // TODO: make generic to allow other hashers
     let mut hasher = Blake2b::new(HASH_LENGTH);
@@ -25,12 +56,35 @@ pub fn kv_hash(key: &[u8], value: &[u8]) -> Hash {
         .expect("key must be less than 256 bytes");
     hasher.update(&key_length.to_be_bytes());
     hasher.update(&key);
+    hasher.update(value_hash);
+
+    let res = hasher.finalize();
+    let mut hash: Hash = Default::default();
+    // TODO: if blake2 lib returned an array we wouldn't need this copy
+    hash.copy_from_slice(res.as_bytes());
+    hash
+}
+
+/// Hashes a key/value pair the way `kv_hash` did before format version 1, by
+/// hashing the key and value directly. Only used to check stores written in
+/// that format while upgrading them (see `Merk::migrate`).
+///
+/// **NOTE:** This will panic if the key is longer than 255 bytes, or the value
+/// is longer than 65,535 bytes.
+pub fn legacy_kv_hash(key: &[u8], value: &[u8]) -> Hash {
+    let mut hasher = Blake2b::new(HASH_LENGTH);
+
+    // panics if key is longer than 255!
+    let key_length = u8::try_from(key.len())
+        .expect("key must be less than 256 bytes");
+    hasher.update(&key_length.to_be_bytes());
+    hasher.update(key);
 
     // panics if value is longer than 65535!
     let val_length = u16::try_from(value.len())
         .expect("value must be less than 65,536 bytes");
     hasher.update(&val_length.to_be_bytes());
-    hasher.update(&value);
+    hasher.update(value);
 
     let res = hasher.finalize();
     let mut hash: Hash = Default::default();
@@ -55,3 +109,43 @@ pub fn node_hash(kv: &Hash, left: &Hash, right: &Hash) -> Hash {
     hash.copy_from_slice(res.as_bytes());
     hash
 }
+
+/// Combines the hash of a node (as computed by `node_hash`) with the
+/// aggregates which its tree maintains, if any: first the number of nodes in
+/// its subtree (see `count_hash`), then the sum of its subtree (see
+/// `sum_hash`).
+pub fn aggregate_hash(hash: Hash, count: Option<u64>, sum: Option<u64>) -> Hash {
+    let hash = count.map_or(hash, |count| count_hash(&hash, count));
+    sum.map_or(hash, |sum| sum_hash(&hash, sum))
+}
+
+/// Combines the hash of a node with the number of nodes in its subtree. Since
+/// the count is hashed last, a proof can reveal the count of a subtree without
+/// revealing its children.
+pub fn count_hash(hash: &Hash, count: u64) -> Hash {
+    u64_hash(b"count", hash, count)
+}
+
+/// Combines the hash of a node with the sum of its subtree. Since the sum is
+/// hashed last, a proof can reveal the sum of a subtree without revealing its
+/// children.
+pub fn sum_hash(hash: &Hash, sum: u64) -> Hash {
+    u64_hash(b"sum", hash, sum)
+}
+
+/// Hashes `tag`, `hash` and `n` together. The tag names the aggregate, so that
+/// hashes of different aggregates of the same node cannot be mistaken for each
+/// other.
+fn u64_hash(tag: &[u8], hash: &Hash, n: u64) -> Hash {
+    // TODO: make generic to allow other hashers
+    let mut hasher = Blake2b::new(HASH_LENGTH);
+
+    hasher.update(tag);
+    hasher.update(hash);
+    hasher.update(&n.to_be_bytes());
+
+    let res = hasher.finalize();
+    let mut hash: Hash = Default::default();
+    hash.copy_from_slice(res.as_bytes());
+    hash
+}
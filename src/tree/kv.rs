@@ -6,6 +6,7 @@ use super::hash::{Hash, kv_hash};
 //       field and value field.
 
 /// Contains a key/value pair, and the hash of the key/value pair.
+#[derive(Clone)]
 pub struct KV {
     key: Vec<u8>,
     value: Vec<u8>,
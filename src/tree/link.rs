@@ -6,12 +6,15 @@ use super::Tree;
 
 /// Represents a reference to a child tree node. Links may or may not contain
 /// the child's `Tree` instance (storing its key if not).
+#[derive(Clone)]
 pub enum Link {
     /// Represents a child tree node which has been pruned from memory, only
     /// retaining its key. The child node can always be fetched from the backing
     /// store by this key when necessary.
     Pruned {
         hash: Hash,
+        sum: Option<u64>,
+        count: Option<u64>,
         child_heights: (u8, u8),
         key: Vec<u8>
     },
@@ -115,26 +118,52 @@ impl Link {
         }
     }
 
+    /// Returns the sum of the tree referenced by the link, or `None` if the
+    /// tree does not maintain sums. Panics if link is of variant
+    /// `Link::Modified` since we have not yet recomputed the tree's sum.
+    pub fn sum(&self) -> Option<u64> {
+        match self {
+            Link::Modified { .. } => panic!("Cannot get sum from modified link"),
+            Link::Pruned { sum, .. } => *sum,
+            Link::Stored { tree, .. } => tree.sum()
+        }
+    }
+
+    /// Returns the number of nodes in the tree referenced by the link, or
+    /// `None` if the tree does not maintain counts. Panics if link is of
+    /// variant `Link::Modified` since we have not yet recomputed the tree's
+    /// count.
+    pub fn count(&self) -> Option<u64> {
+        match self {
+            Link::Modified { .. } => panic!("Cannot get count from modified link"),
+            Link::Pruned { count, .. } => *count,
+            Link::Stored { tree, .. } => tree.count()
+        }
+    }
+
+    /// Returns the heights of the children of the tree referenced by the link,
+    /// as `(left_child_height, right_child_height)`.
+    #[inline]
+    pub fn child_heights(&self) -> (u8, u8) {
+        match self {
+            Link::Pruned { child_heights, .. } => *child_heights,
+            Link::Modified { child_heights, .. } => *child_heights,
+            Link::Stored { child_heights, .. } => *child_heights
+        }
+    }
+
     /// Returns the height of the children of the tree referenced by the link,
     /// if any (note: not the height of the referenced tree itself). Return
     /// value is `(left_child_height, right_child_height)`.
     pub fn height(&self) -> u8 {
-        let (left_height, right_height) = match self {
-            Link::Pruned { child_heights, .. } => *child_heights,
-            Link::Modified { child_heights, .. } => *child_heights,
-            Link::Stored { child_heights, .. } => *child_heights
-        };
+        let (left_height, right_height) = self.child_heights();
         1 + max(left_height, right_height)
     }
     
     /// Returns the balance factor of the tree referenced by the link.
     #[inline]
     pub fn balance_factor(&self) -> i8 {
-        let (left_height, right_height) = match self {
-            Link::Pruned { child_heights, .. } => *child_heights,
-            Link::Modified { child_heights, .. } => *child_heights,
-            Link::Stored { child_heights, .. } => *child_heights
-        };
+        let (left_height, right_height) = self.child_heights();
         right_height as i8 - left_height as i8
     }
 
@@ -146,6 +175,8 @@ impl Link {
             Link::Modified { .. } => panic!("Cannot prune Modified tree"),
             Link::Stored { hash, child_heights, tree } => Link::Pruned {
                 hash,
+                sum: tree.sum(),
+                count: tree.count(),
                 child_heights,
                 key: tree.take_key()
             }
@@ -191,7 +222,7 @@ mod test {
         let key = vec![0];
         let tree = || Tree::new(vec![0], vec![1]);
 
-        let pruned = Link::Pruned { hash, child_heights, key };
+        let pruned = Link::Pruned { hash, sum: None, count: None, child_heights, key };
         let modified = Link::Modified { pending_writes, child_heights, tree: tree(), deleted_keys: vec![] };
         let stored = Link::Stored { hash, child_heights, tree: tree() };
 
@@ -14,17 +14,42 @@ use std::cmp::max;
 
 pub use walk::{Walker, RefWalker, Fetch};
 use super::error::Result;
-pub use commit::{Commit, NoopCommit};
+pub use commit::{Commit, NoopCommit, AggregateCommit};
 use kv::KV;
 pub use link::Link;
-pub use hash::{Hash, kv_hash, node_hash, NULL_HASH, HASH_LENGTH};
-pub use ops::{Batch, BatchEntry, PanicSource, Op};
+pub use hash::{
+    Hash,
+    kv_hash,
+    value_hash,
+    kv_digest_hash,
+    legacy_kv_hash,
+    node_hash,
+    aggregate_hash,
+    count_hash,
+    sum_hash,
+    NULL_HASH,
+    HASH_LENGTH
+};
+pub use ops::{
+    Batch,
+    BatchEntry,
+    PanicSource,
+    Op,
+    OpFailure,
+    MergeFn,
+    DuplicateKeys,
+    SortedBatch,
+    check_sorted
+};
 
 /// The fields of the `Tree` type, stored on the heap.
+#[derive(Clone)]
 struct TreeInner {
     kv: KV,
     left: Option<Link>,
-    right: Option<Link>
+    right: Option<Link>,
+    sum: Option<u64>,
+    count: Option<u64>
 }
 
 /// A binary AVL tree data structure, with Merkle hashes.
@@ -32,6 +57,7 @@ struct TreeInner {
 /// Trees' inner fields are stored on the heap so that nodes can recursively
 /// link to each other, and so we can detach nodes from their parents, then
 /// reattach without allocating or freeing heap memory.
+#[derive(Clone)]
 pub struct Tree {
     inner: Box<TreeInner>
 }
@@ -45,7 +71,9 @@ impl Tree {
             inner: Box::new(TreeInner {
                 kv: KV::new(key, value),
                 left: None,
-                right: None
+                right: None,
+                sum: None,
+                count: None
             })
         }
     }
@@ -63,7 +91,9 @@ impl Tree {
             inner: Box::new(TreeInner {
                 kv: KV::from_fields(key, value, kv_hash),
                 left,
-                right
+                right,
+                sum: None,
+                count: None
             })
         }
     }
@@ -131,8 +161,19 @@ impl Tree {
             .map_or(&NULL_HASH, |link| link.hash())
     }
 
-    /// Computes and returns the hash of the root node.
+    /// Computes and returns the hash of the root node. If the tree maintains
+    /// counts or sums, the hash also commits to them (see `aggregate_hash`).
     pub fn hash(&self) -> Hash {
+        aggregate_hash(
+            self.node_hash(),
+            self.inner.count,
+            self.inner.sum
+        )
+    }
+
+    /// Computes the hash of the root node without the aggregates the tree
+    /// maintains (see `node_hash`).
+    pub fn node_hash(&self) -> Hash {
         node_hash(
             self.inner.kv.hash(),
             self.child_hash(true),
@@ -140,6 +181,33 @@ impl Tree {
         )
     }
 
+    /// Returns the sum of the tree as of the last commit (the amounts which
+    /// every node's value contributes, see `Commit::value_sum`), or `None` if
+    /// the tree does not maintain sums.
+    #[inline]
+    pub fn sum(&self) -> Option<u64> {
+        self.inner.sum
+    }
+
+    /// Returns the sum of the root node's child on the given side. If there is
+    /// no child, returns 0.
+    pub fn child_sum(&self, left: bool) -> Option<u64> {
+        self.link(left).map_or(Some(0), |link| link.sum())
+    }
+
+    /// Returns the number of nodes in the tree as of the last commit, or
+    /// `None` if the tree does not maintain counts (see `Commit::counts`).
+    #[inline]
+    pub fn count(&self) -> Option<u64> {
+        self.inner.count
+    }
+
+    /// Returns the number of nodes in the root node's child on the given side.
+    /// If there is no child, returns 0.
+    pub fn child_count(&self, left: bool) -> Option<u64> {
+        self.link(left).map_or(Some(0), |link| link.count())
+    }
+
     /// Returns the number of pending writes for the child on the given side, if
     /// any. If there is no child, returns 0.
     pub fn child_pending_writes(&self, left: bool) -> usize {
@@ -320,6 +388,31 @@ impl Tree {
             }
         }
 
+        self.inner.sum = match c.value_sum(self.value()) {
+            None => None,
+            Some(value_sum) => {
+                let sum = match (self.child_sum(true), self.child_sum(false)) {
+                    (Some(left), Some(right)) => value_sum
+                        .checked_add(left)
+                        .and_then(|sum| sum.checked_add(right)),
+                    _ => bail!("Tried to compute sum, but child does not have a sum")
+                };
+                match sum {
+                    None => bail!("Sum of tree overflowed"),
+                    sum => sum
+                }
+            }
+        };
+
+        self.inner.count = if c.counts() {
+            match (self.child_count(true), self.child_count(false)) {
+                (Some(left), Some(right)) => Some(1 + left + right),
+                _ => bail!("Tried to compute count, but child does not have a count")
+            }
+        } else {
+            None
+        };
+
         c.write(&self)?;
 
         let (prune_left, prune_right) = c.prune(&self);
@@ -365,7 +458,7 @@ pub fn side_to_str(left: bool) -> &'static str {
 #[cfg(test)]
 mod test {
     use super::Tree;
-    use super::hash::NULL_HASH;
+    use super::hash::{NULL_HASH, legacy_kv_hash, node_hash};
     use super::commit::NoopCommit;
 
     #[test]
@@ -457,14 +550,23 @@ mod test {
         let mut tree = Tree::new(vec![0], vec![1])
             .attach(true, Some(Tree::new(vec![2], vec![3])));
         tree.commit(&mut NoopCommit {}).expect("commit failed");
-        assert_eq!(tree.child_hash(true), &[23, 66, 77, 65, 141, 140, 245, 11, 53, 36, 157, 248, 208, 6, 160, 222, 213, 143, 249, 85]);
+        assert_eq!(tree.child_hash(true), &[1, 146, 203, 141, 189, 52, 157, 142, 177, 216, 223, 209, 248, 153, 93, 251, 6, 53, 74, 251]);
         assert_eq!(tree.child_hash(false), &NULL_HASH);
     }
 
     #[test]
     fn hash() {
         let tree = Tree::new(vec![0], vec![1]);
-        assert_eq!(tree.hash(), [9, 242, 41, 142, 47, 227, 251, 242, 27, 29, 140, 24, 184, 111, 118, 188, 20, 58, 223, 197]);
+        assert_eq!(tree.hash(), [77, 74, 48, 22, 188, 241, 35, 127, 29, 18, 31, 98, 83, 125, 47, 239, 212, 166, 17, 159]);
+    }
+
+    #[test]
+    fn legacy_hash() {
+        // the hashes of the same node before format version 1
+        let kv_hash = legacy_kv_hash(&[0], &[1]);
+        assert_eq!(kv_hash, [195, 201, 244, 70, 50, 255, 177, 215, 40, 246, 8, 69, 174, 17, 72, 99, 29, 112, 226, 212]);
+        let hash = node_hash(&kv_hash, &NULL_HASH, &NULL_HASH);
+        assert_eq!(hash, [9, 242, 41, 142, 47, 227, 251, 242, 27, 29, 140, 24, 184, 111, 118, 188, 20, 58, 223, 197]);
     }
 
     #[test]
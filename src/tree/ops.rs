@@ -1,29 +1,199 @@
 use std::fmt;
+use std::mem;
+use std::ops::Deref;
+use std::collections::LinkedList;
+use std::sync::Arc;
 use crate::error::Result;
 use super::{Tree, Link, Walker, Fetch};
 use Op::*;
 
+/// A function which computes a key's new value from its current value (or
+/// `None` if the key does not exist), used by `Op::Merge`.
+pub type MergeFn = Arc<dyn Fn(Option<&[u8]>) -> Vec<u8> + Send + Sync>;
+
 /// An operation to be applied to a key in the store.
+#[derive(Clone)]
 pub enum Op {
+    /// Sets the key's value.
     Put(Vec<u8>),
-    Delete
+
+    /// Deletes the key. Fails the whole batch if the key does not exist.
+    Delete,
+
+    /// Sets the key's value if the key does not exist, otherwise fails with
+    /// `OpFailure::KeyExists`.
+    PutIfAbsent(Vec<u8>),
+
+    /// Deletes the key if it exists, otherwise does nothing.
+    DeleteIfExists,
+
+    /// Sets the key's value to the second value if its current value is equal
+    /// to the first, otherwise fails with `OpFailure::KeyNotFound` or
+    /// `OpFailure::ValueMismatch`.
+    CompareAndSwap(Vec<u8>, Vec<u8>),
+
+    /// Sets the key's value to the result of calling the function with its
+    /// current value. The function is called once when the batch is applied,
+    /// but is called again when a witness for the batch is checked with
+    /// `apply_to_proof`, so it should be deterministic.
+    Merge(MergeFn)
 }
 
 impl fmt::Debug for Op {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "{}", match self {
             Put(value) => format!("Put({:?})", value),
-            Delete => "Delete".to_string()
+            Delete => "Delete".to_string(),
+            PutIfAbsent(value) => format!("PutIfAbsent({:?})", value),
+            DeleteIfExists => "DeleteIfExists".to_string(),
+            CompareAndSwap(expected, new) => {
+                format!("CompareAndSwap({:?}, {:?})", expected, new)
+            },
+            Merge(_) => "Merge(..)".to_string()
         })
     }
 }
 
+/// The reason a conditional operation was not applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpFailure {
+    /// `PutIfAbsent` found an existing value for the key.
+    KeyExists,
+
+    /// `CompareAndSwap` found no value for the key.
+    KeyNotFound,
+
+    /// `CompareAndSwap` found a value other than the expected one.
+    ValueMismatch
+}
+
+/// The effects of applying a batch which are not captured by the resulting
+/// tree.
+#[derive(Debug, Default)]
+pub struct BatchOutcome {
+    /// Keys of the nodes which were removed from the tree, so their records
+    /// can be deleted from the backing store.
+    pub deleted_keys: LinkedList<Vec<u8>>,
+
+    /// Keys of conditional operations which were not applied, along with the
+    /// reason. These do not prevent the rest of the batch from being applied.
+    pub failures: LinkedList<(Vec<u8>, OpFailure)>
+}
+
+impl BatchOutcome {
+    fn append(&mut self, mut other: BatchOutcome) {
+        self.deleted_keys.append(&mut other.deleted_keys);
+        self.failures.append(&mut other.failures);
+    }
+
+    fn fail(&mut self, key: &[u8], failure: OpFailure) {
+        self.failures.push_back((key.to_vec(), failure));
+    }
+}
+
 /// A single `(key, operation)` pair.
 pub type BatchEntry = (Vec<u8>, Op);
 
 /// A mapping of keys and operations. Keys should be sorted and unique.
 pub type Batch = [BatchEntry];
 
+/// How to handle a key which appears more than once in an unsorted batch (see
+/// `sort_batch`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicateKeys {
+    /// Keep only the operation which appears last in the batch.
+    LastWriteWins,
+
+    /// Fail if any key appears more than once.
+    Error
+}
+
+/// A batch whose keys have been checked to be sorted and unique. Owning the
+/// batch allows keys and values to be moved into the tree when it is applied,
+/// rather than copied.
+#[derive(Clone, Debug)]
+pub struct SortedBatch(Vec<BatchEntry>);
+
+impl SortedBatch {
+    /// Creates a `SortedBatch`, failing if the keys in `batch` are not sorted
+    /// and unique.
+    pub fn new(batch: Vec<BatchEntry>) -> Result<Self> {
+        check_sorted(&batch)?;
+        Ok(SortedBatch(batch))
+    }
+
+    /// Creates a `SortedBatch` by sorting `batch` by key, resolving duplicate
+    /// keys according to `duplicates`.
+    pub fn from_unsorted(
+        batch: Vec<BatchEntry>,
+        duplicates: DuplicateKeys
+    ) -> Result<Self> {
+        Ok(SortedBatch(sort_batch(batch, duplicates)?))
+    }
+
+    /// Creates a `SortedBatch` without checking that its keys are sorted and
+    /// unique.
+    pub(crate) fn new_unchecked(batch: Vec<BatchEntry>) -> Self {
+        SortedBatch(batch)
+    }
+
+    /// Returns the entries as a mutable slice, so their keys and values can be
+    /// moved out while applying. Must not be used to reorder the entries.
+    pub(crate) fn as_mut_slice(&mut self) -> &mut Batch {
+        &mut self.0
+    }
+
+    /// Consumes the `SortedBatch` and returns its entries.
+    pub fn into_inner(self) -> Vec<BatchEntry> {
+        self.0
+    }
+}
+
+impl Deref for SortedBatch {
+    type Target = Batch;
+
+    fn deref(&self) -> &Batch {
+        &self.0
+    }
+}
+
+/// Checks that the keys in `batch` are sorted and unique.
+pub fn check_sorted(batch: &Batch) -> Result<()> {
+    for pair in batch.windows(2) {
+        let (prev_key, key) = (&pair[0].0, &pair[1].0);
+        if prev_key > key {
+            bail!("Keys in batch must be sorted");
+        } else if prev_key == key {
+            bail!("Keys in batch must be unique");
+        }
+    }
+    Ok(())
+}
+
+/// Sorts a batch by key, resolving duplicate keys according to `duplicates`.
+/// The sort is stable, so "last" refers to the original order of the batch.
+fn sort_batch(
+    mut batch: Vec<BatchEntry>,
+    duplicates: DuplicateKeys
+) -> Result<Vec<BatchEntry>> {
+    batch.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut sorted: Vec<BatchEntry> = Vec::with_capacity(batch.len());
+    for entry in batch {
+        match sorted.last_mut() {
+            Some(prev) if prev.0 == entry.0 => {
+                if duplicates == DuplicateKeys::Error {
+                    bail!("Duplicate key in batch: {:?}", entry.0);
+                }
+                *prev = entry;
+            },
+            _ => sorted.push(entry)
+        }
+    }
+
+    Ok(sorted)
+}
+
 /// A source of data which panics when called. Useful when creating a store
 /// which always keeps the state in memory.
 #[derive(Clone)]
@@ -39,15 +209,20 @@ impl<S> Walker<S>
 {
     /// Applies a batch of operations, possibly creating a new tree if
     /// `maybe_tree` is `None`. This is similar to `Walker<S>::apply`, but does
-    /// not require a non-empty tree.
+    /// not require a non-empty tree. Also returns the keys of the nodes which
+    /// were removed from the tree and of any conditional operations which
+    /// failed.
+    ///
+    /// Keys and values are moved out of the batch rather than copied, so the
+    /// entries should not be used afterwards.
     /// 
     /// Keys in batch must be sorted and unique.
     pub fn apply_to(
         maybe_tree: Option<Self>,
-        batch: &Batch
-    ) -> Result<Option<Tree>> {
-        let maybe_walker = if batch.is_empty() {
-            maybe_tree
+        batch: &mut Batch
+    ) -> Result<(Option<Tree>, BatchOutcome)> {
+        let (maybe_walker, outcome) = if batch.is_empty() {
+            (maybe_tree, BatchOutcome::default())
         } else {
             match maybe_tree {
                 None => return Self::build(batch),
@@ -55,58 +230,111 @@ impl<S> Walker<S>
             }
         };
 
-        Ok(maybe_walker.map(|walker| walker.into_inner()))
+        let maybe_tree = maybe_walker.map(|walker| walker.into_inner());
+        Ok((maybe_tree, outcome))
     }
 
-    /// Builds a `Tree` from a batch of inserts. Fails if the batch contains a
-    /// `Delete` operation.
+    /// Builds a `Tree` from a batch of operations on keys which do not exist.
+    /// Fails if the batch contains a `Delete` operation.
     /// 
     /// Keys in batch must be sorted and unique.
-    fn build(batch: &Batch) -> Result<Option<Tree>> {
+    fn build(batch: &mut Batch) -> Result<(Option<Tree>, BatchOutcome)> {
         if batch.is_empty() {
-            return Ok(None);
+            return Ok((None, BatchOutcome::default()));
         }
 
         let mid_index = batch.len() / 2;
-        let (mid_key, mid_op) = &batch[mid_index];
+        let (mid_key, mid_op) = &mut batch[mid_index];
+        let mut outcome = BatchOutcome::default();
         let mid_value = match mid_op {
             Delete => bail!("Tried to delete non-existent key {:?}", mid_key),
-            Put(value) => value
+            Put(value) | PutIfAbsent(value) => Some(mem::take(value)),
+            Merge(merge) => Some(merge(None)),
+            DeleteIfExists => None,
+            CompareAndSwap(_, _) => {
+                outcome.fail(mid_key, OpFailure::KeyNotFound);
+                None
+            }
+        };
+
+        let mid_value = match mid_value {
+            Some(value) => value,
+            None => {
+                // no node for the middle key, build from the left half then
+                // insert the right half into it
+                let (maybe_left, left_outcome) = Self::build(&mut batch[..mid_index])?;
+                outcome.append(left_outcome);
+                let maybe_left = maybe_left
+                    .map(|tree| Walker::new(tree, PanicSource {}));
+                let (maybe_tree, right_outcome) = Walker::apply_to(
+                    maybe_left,
+                    &mut batch[mid_index + 1..]
+                )?;
+                outcome.append(right_outcome);
+                return Ok((maybe_tree, outcome));
+            }
         };
 
-        // TODO: take from batch so we don't have to clone
-        let mid_tree = Tree::new(mid_key.to_vec(), mid_value.to_vec());
+        // the middle entry is excluded from the recursion below, so its key
+        // can be moved into the tree
+        let mid_tree = Tree::new(mem::take(mid_key), mid_value);
         let mid_walker = Walker::new(mid_tree, PanicSource {});
-        Ok(mid_walker
-            .recurse(batch, mid_index, true)?
-            .map(|w| w.into_inner()))
+        let (mid_walker, recurse_outcome) = mid_walker.recurse(batch, mid_index, true)?;
+        outcome.append(recurse_outcome);
+        Ok((mid_walker.map(|w| w.into_inner()), outcome))
     }
 
     /// Applies a batch of operations to an existing tree. This is similar to
     /// `Walker<S>::apply`_to, but requires a populated tree.
     ///
     /// Keys in batch must be sorted and unique.
-    fn apply(self, batch: &Batch) -> Result<Option<Self>> {
+    fn apply(
+        self,
+        batch: &mut Batch
+    ) -> Result<(Option<Self>, BatchOutcome)> {
         // binary search to see if this node's key is in the batch, and to split
         // into left and right batches
         let search = batch.binary_search_by(
             |(key, _op)| key.as_slice().cmp(self.tree().key())
         );
+        let mut outcome = BatchOutcome::default();
         let tree = if let Ok(index) = search {
             // a key matches this node's key, apply op to this node
-            match &batch[index].1 {
-                // TODO: take vec from batch so we don't need to clone
-                Put(value) => self.with_value(value.to_vec()),
-                Delete => {
+            match &mut batch[index].1 {
+                Put(value) => self.with_value(mem::take(value)),
+                PutIfAbsent(_) => {
+                    outcome.fail(self.tree().key(), OpFailure::KeyExists);
+                    self
+                },
+                CompareAndSwap(expected, new) => {
+                    if self.tree().value() == expected.as_slice() {
+                        self.with_value(mem::take(new))
+                    } else {
+                        outcome.fail(self.tree().key(), OpFailure::ValueMismatch);
+                        self
+                    }
+                },
+                Merge(merge) => {
+                    let value = merge(Some(self.tree().value()));
+                    self.with_value(value)
+                },
+                Delete | DeleteIfExists => {
                     // TODO: we shouldn't have to do this as 2 different calls to apply
                     let source = self.clone_source();
                     let wrap = |maybe_tree: Option<Tree>| {
                         maybe_tree.map(|tree| Self::new(tree, source.clone()))
                     };
+                    outcome.deleted_keys.push_back(self.tree().key().to_vec());
+
                     let maybe_tree = self.remove()?;
-                    let maybe_tree = wrap(Self::apply_to(maybe_tree, &batch[..index])?);
-                    let maybe_tree = wrap(Self::apply_to(maybe_tree, &batch[index + 1..])?);
-                    return Ok(maybe_tree);
+                    let (maybe_tree, left_outcome) =
+                        Self::apply_to(maybe_tree, &mut batch[..index])?;
+                    outcome.append(left_outcome);
+                    let (maybe_tree, right_outcome) =
+                        Self::apply_to(wrap(maybe_tree), &mut batch[index + 1..])?;
+                    outcome.append(right_outcome);
+
+                    return Ok((wrap(maybe_tree), outcome));
                 }
             }
         } else {
@@ -118,7 +346,9 @@ impl<S> Walker<S>
             Err(index) => (index, false)
         };
 
-        tree.recurse(batch, mid, exclusive)
+        let (maybe_tree, recurse_outcome) = tree.recurse(batch, mid, exclusive)?;
+        outcome.append(recurse_outcome);
+        Ok((maybe_tree, outcome))
     }
 
     /// Recursively applies operations to the tree's children (if there are any
@@ -128,36 +358,177 @@ impl<S> Walker<S>
     /// will be dispatched to workers in other threads.
     fn recurse(
         self,
-        batch: &Batch,
+        batch: &mut Batch,
         mid: usize,
         exclusive: bool
-    ) -> Result<Option<Self>> {
-        let left_batch = &batch[..mid];
+    ) -> Result<(Option<Self>, BatchOutcome)> {
+        let (left_batch, right_batch) = batch.split_at_mut(mid);
         let right_batch = if exclusive {
-            &batch[mid + 1..]
+            &mut right_batch[1..]
         } else {
-            &batch[mid..]
+            right_batch
         };
+
+        let mut outcome = BatchOutcome::default();
         
         let tree = if !left_batch.is_empty() {
-            self.walk(true, |maybe_left|
-                Self::apply_to(maybe_left, left_batch)
-            )?
+            self.walk(true, |maybe_left| {
+                let (maybe_left, left_outcome) =
+                    Self::apply_to(maybe_left, left_batch)?;
+                outcome.append(left_outcome);
+                Ok(maybe_left)
+            })?
         } else {
             self
         };
 
         let tree = if !right_batch.is_empty() {
-            tree.walk(false, |maybe_right|
-                Self::apply_to(maybe_right, right_batch)
-            )?
+            tree.walk(false, |maybe_right| {
+                let (maybe_right, right_outcome) =
+                    Self::apply_to(maybe_right, right_batch)?;
+                outcome.append(right_outcome);
+                Ok(maybe_right)
+            })?
         } else {
             tree
         };
 
         let tree = tree.maybe_balance()?;
 
-        Ok(Some(tree))
+        Ok((Some(tree), outcome))
+    }
+
+    /// Removes all keys in the range `[start, end)`, possibly returning `None`
+    /// if every key was removed.
+    ///
+    /// Subtrees which lie entirely within the range are dropped without being
+    /// fetched, so their keys are not known and are not returned - the caller
+    /// is responsible for removing their records from the backing store.
+    pub fn delete_range(
+        maybe_tree: Option<Self>,
+        start: &[u8],
+        end: &[u8]
+    ) -> Result<Option<Tree>> {
+        let maybe_walker = match maybe_tree {
+            Some(tree) if start < end => tree.remove_range(start, end)?,
+            maybe_tree => maybe_tree
+        };
+
+        Ok(maybe_walker.map(|walker| walker.into_inner()))
+    }
+
+    /// Removes all keys in the range `[start, end)` from a populated tree.
+    fn remove_range(self, start: &[u8], end: &[u8]) -> Result<Option<Self>> {
+        let key = self.tree().key();
+        let (below, above) = (key < start, key >= end);
+
+        let (tree, left) = unsafe { self.detach(true)? };
+        let (tree, right) = unsafe { tree.detach(false)? };
+
+        let maybe_tree = if below {
+            // range is entirely in right subtree
+            let right = Self::remove_range_to(right, start, end)?;
+            Some(Self::join(left, tree, right)?)
+        } else if above {
+            // range is entirely in left subtree
+            let left = Self::remove_range_to(left, start, end)?;
+            Some(Self::join(left, tree, right)?)
+        } else {
+            // this node is in the range, keep only the edges of the subtrees
+            // outside of the range
+            let left = Self::split(left, start, true)?;
+            let right = Self::split(right, end, false)?;
+            match (left, right) {
+                (None, right) => right,
+                (left, None) => left,
+                (Some(left), right) => {
+                    let (edge, left) = left.remove_edge(false)?;
+                    Some(Self::join(left, edge, right)?)
+                }
+            }
+        };
+
+        Ok(maybe_tree)
+    }
+
+    fn remove_range_to(
+        maybe_tree: Option<Self>,
+        start: &[u8],
+        end: &[u8]
+    ) -> Result<Option<Self>> {
+        match maybe_tree {
+            None => Ok(None),
+            Some(tree) => tree.remove_range(start, end)
+        }
+    }
+
+    /// Splits the tree at `bound`, returning the nodes with keys less than
+    /// `bound` if `left` is `true`, or with keys greater than or equal to
+    /// `bound` if `left` is `false`. The rest of the tree is dropped, and
+    /// subtrees which are dropped entirely are not fetched.
+    fn split(
+        maybe_tree: Option<Self>,
+        bound: &[u8],
+        left: bool
+    ) -> Result<Option<Self>> {
+        let tree = match maybe_tree {
+            None => return Ok(None),
+            Some(tree) => tree
+        };
+
+        let keep = (tree.tree().key() < bound) == left;
+        if keep {
+            // keep this node and its child on the kept side, split the other
+            let (tree, kept_child) = unsafe { tree.detach(left)? };
+            let (tree, split_child) = unsafe { tree.detach(!left)? };
+            let split_child = Self::split(split_child, bound, left)?;
+            let joined = if left {
+                Self::join(kept_child, tree, split_child)?
+            } else {
+                Self::join(split_child, tree, kept_child)?
+            };
+            Ok(Some(joined))
+        } else {
+            // drop this node and its child on the other side
+            let tree = tree.drop_child(!left);
+            let (_, child) = unsafe { tree.detach(left)? };
+            Self::split(child, bound, left)
+        }
+    }
+
+    /// Joins two trees using `mid` (a node with no children) as the root,
+    /// where all keys in `maybe_left` are less than `mid`'s key and all keys in
+    /// `maybe_right` are greater. The trees may have any heights - the
+    /// shorter tree is attached down the edge of the taller one, rebalancing
+    /// on the way back up.
+    fn join(
+        maybe_left: Option<Self>,
+        mid: Self,
+        maybe_right: Option<Self>
+    ) -> Result<Self> {
+        let height = |maybe_tree: &Option<Self>| {
+            maybe_tree.as_ref().map_or(0, |tree| tree.tree().height())
+        };
+        let left_height = height(&maybe_left);
+        let right_height = height(&maybe_right);
+
+        if left_height > right_height + 1 {
+            maybe_left.unwrap()
+                .walk(false, |maybe_child| {
+                    Ok(Some(Self::join(maybe_child, mid, maybe_right)?))
+                })?
+                .maybe_balance()
+        } else if right_height > left_height + 1 {
+            maybe_right.unwrap()
+                .walk(true, |maybe_child| {
+                    Ok(Some(Self::join(maybe_left, mid, maybe_child)?))
+                })?
+                .maybe_balance()
+        } else {
+            Ok(mid
+                .attach(true, maybe_left)
+                .attach(false, maybe_right))
+        }
     }
 
     /// Gets the wrapped tree's balance factor.
@@ -214,8 +585,6 @@ impl<S> Walker<S>
         let has_right = tree.link(false).is_some();
         let left = tree.child_height(true) > tree.child_height(false);
 
-        // TODO: propagate up deleted keys?
-
         let maybe_tree = unsafe {
             if has_left && has_right {
                 // two children, promote edge of taller child
@@ -267,18 +636,23 @@ impl<S> Walker<S>
 
 #[cfg(test)]
 mod test {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use super::*;
     use crate::tree::*;
     use crate::test_utils::{
         make_tree_seq,
+        make_batch_seq,
+        seq_key,
         del_entry,
         apply_memonly,
+        apply_to_memonly,
         assert_tree_invariants
     };
 
     #[test]
     fn simple_insert() {
-        let batch = [
+        let mut batch = [
             (
                 b"foo2".to_vec(),
                 Op::Put(b"bar2".to_vec())
@@ -286,8 +660,9 @@ mod test {
         ];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
         let walker = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&mut batch)
             .expect("apply errored")
+            .0
             .expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
         assert_eq!(walker.into_inner().child(false).unwrap().key(), b"foo2");
@@ -295,7 +670,7 @@ mod test {
 
     #[test]
     fn simple_update() {
-        let batch = [
+        let mut batch = [
             (
                 b"foo".to_vec(),
                 Op::Put(b"bar2".to_vec())
@@ -303,8 +678,9 @@ mod test {
         ];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
         let walker = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&mut batch)
             .expect("apply errored")
+            .0
             .expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
         assert_eq!(walker.tree().value(), b"bar2");
@@ -314,7 +690,7 @@ mod test {
 
     #[test]
     fn simple_delete() {
-        let batch = [
+        let mut batch = [
             (b"foo2".to_vec(), Op::Delete)
         ];
         let tree = Tree::from_fields(
@@ -328,8 +704,9 @@ mod test {
             })
         );
         let walker = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&mut batch)
             .expect("apply errored")
+            .0
             .expect("should be Some");
         assert_eq!(walker.tree().key(), b"foo");
         assert_eq!(walker.tree().value(), b"bar");
@@ -340,34 +717,36 @@ mod test {
     #[test]
     #[should_panic]
     fn delete_non_existent() {
-        let batch = [
+        let mut batch = [
             (b"foo2".to_vec(), Op::Delete)
         ];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
         Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&mut batch)
             .unwrap();
     }
 
     #[test]
     fn delete_only_node() {
-        let batch = [
+        let mut batch = [
             (b"foo".to_vec(), Op::Delete)
         ];
         let tree = Tree::new(b"foo".to_vec(), b"bar".to_vec());
-        let walker = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+        let (walker, outcome) = Walker::new(tree, PanicSource {})
+            .apply(&mut batch)
             .expect("apply errored");
         assert!(walker.is_none());
+        assert_eq!(outcome.deleted_keys.into_iter().collect::<Vec<_>>(), vec![ b"foo".to_vec() ]);
     }
 
     #[test]
     fn delete_deep() {
         let tree = make_tree_seq(50);
-        let batch = [ del_entry(5) ]; 
+        let mut batch = [ del_entry(5) ]; 
         Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&mut batch)
             .expect("apply errored")
+            .0
             .expect("should be Some");
         // TODO: assert set of keys are correct
     }
@@ -375,21 +754,26 @@ mod test {
     #[test]
     fn delete_recursive() {
         let tree = make_tree_seq(50);
-        let batch = [ del_entry(29), del_entry(34) ]; 
-        Walker::new(tree, PanicSource {})
-            .apply(&batch)
-            .expect("apply errored")
-            .expect("should be Some");
+        let mut batch = [ del_entry(29), del_entry(34) ]; 
+        let (walker, outcome) = Walker::new(tree, PanicSource {})
+            .apply(&mut batch)
+            .expect("apply errored");
+        walker.expect("should be Some");
         // TODO: assert set of keys are correct
+
+        let mut deleted_keys: Vec<_> = outcome.deleted_keys.into_iter().collect();
+        deleted_keys.sort();
+        assert_eq!(deleted_keys, vec![ seq_key(29), seq_key(34) ]);
     }
 
     #[test]
     fn delete_recursive_2() {
         let tree = make_tree_seq(10);
-        let batch = [ del_entry(7), del_entry(9) ]; 
+        let mut batch = [ del_entry(7), del_entry(9) ]; 
         let walker = Walker::new(tree, PanicSource {})
-            .apply(&batch)
+            .apply(&mut batch)
             .expect("apply errored")
+            .0
             .expect("should be Some");
         // TODO: assert set of keys are correct
 
@@ -398,16 +782,18 @@ mod test {
 
     #[test]
     fn apply_empty_none() {
-        let maybe_tree = Walker::<PanicSource>::apply_to(None, &vec![])
+        let (maybe_tree, outcome) = Walker::<PanicSource>::apply_to(None, &mut vec![])
             .expect("apply_to failed");
         assert!(maybe_tree.is_none());
+        assert!(outcome.deleted_keys.is_empty());
     }
 
     #[test]
     fn insert_empty_single() {
-        let batch = vec![ (vec![0], Op::Put(vec![1])) ];
-        let tree = Walker::<PanicSource>::apply_to(None, &batch)
+        let mut batch = vec![ (vec![0], Op::Put(vec![1])) ];
+        let tree = Walker::<PanicSource>::apply_to(None, &mut batch)
             .expect("apply_to failed")
+            .0
             .expect("expected tree");
         assert_eq!(tree.key(), &[0]);
         assert_eq!(tree.value(), &[1]);
@@ -417,7 +803,7 @@ mod test {
     #[test]
     fn insert_root_single() {
         let tree = Tree::new(vec![5], vec![123]);
-        let batch = vec![ (vec![6], Op::Put(vec![123])) ];
+        let mut batch = vec![ (vec![6], Op::Put(vec![123])) ];
         let tree = apply_memonly(tree, &batch);
         assert_eq!(tree.key(), &[5]);
         assert!(tree.child(true).is_none());
@@ -427,7 +813,7 @@ mod test {
     #[test]
     fn insert_root_double() {
         let tree = Tree::new(vec![5], vec![123]);
-        let batch = vec![
+        let mut batch = vec![
             (vec![4], Op::Put(vec![123])),
             (vec![6], Op::Put(vec![123]))
         ];
@@ -441,10 +827,10 @@ mod test {
     fn insert_rebalance() {
         let tree = Tree::new(vec![5], vec![123]);
 
-        let batch = vec![ (vec![6], Op::Put(vec![123])) ];
+        let mut batch = vec![ (vec![6], Op::Put(vec![123])) ];
         let tree = apply_memonly(tree, &batch);
 
-        let batch = vec![ (vec![7], Op::Put(vec![123])) ];
+        let mut batch = vec![ (vec![7], Op::Put(vec![123])) ];
         let tree = apply_memonly(tree, &batch);
 
         assert_eq!(tree.key(), &[6]);
@@ -457,7 +843,7 @@ mod test {
         let mut tree = Tree::new(vec![0], vec![123]);
 
         for i in 0..100 {
-            let batch = vec![ (vec![i + 1], Op::Put(vec![123])) ];
+            let mut batch = vec![ (vec![i + 1], Op::Put(vec![123])) ];
             tree = apply_memonly(tree, &batch);
         }
 
@@ -465,4 +851,224 @@ mod test {
         assert_eq!(tree.child(true).expect("expected child").key(), &[31]);
         assert_eq!(tree.child(false).expect("expected child").key(), &[79]);
     }
+
+    fn failures(outcome: BatchOutcome) -> Vec<(Vec<u8>, OpFailure)> {
+        let mut failures: Vec<_> = outcome.failures.into_iter().collect();
+        failures.sort_by(|a, b| a.0.cmp(&b.0));
+        failures
+    }
+
+    #[test]
+    fn conditional_existing() {
+        let tree = make_tree_seq(10);
+        let merge: MergeFn = Arc::new(|value| {
+            let mut value = value.expect("expected value").to_vec();
+            value.push(1);
+            value
+        });
+        let mut batch = vec![
+            (seq_key(1), Op::PutIfAbsent(vec![1])),
+            (seq_key(2), Op::DeleteIfExists),
+            (seq_key(3), Op::CompareAndSwap(vec![123; 60], vec![3])),
+            (seq_key(4), Op::CompareAndSwap(vec![4], vec![4])),
+            (seq_key(5), Op::Merge(merge))
+        ];
+        let (maybe_tree, outcome) = Walker::new(tree, PanicSource {})
+            .apply(&mut batch)
+            .expect("apply errored");
+        let tree = maybe_tree.expect("should be Some").into_inner();
+
+        assert_eq!(failures(outcome), vec![
+            (seq_key(1), OpFailure::KeyExists),
+            (seq_key(4), OpFailure::ValueMismatch)
+        ]);
+
+        let get = |n| tree.iter()
+            .find(|(key, _)| *key == seq_key(n))
+            .map(|(_, value)| value);
+        assert_eq!(get(1), Some(vec![123; 60]));
+        assert_eq!(get(2), None);
+        assert_eq!(get(3), Some(vec![3]));
+        assert_eq!(get(4), Some(vec![123; 60]));
+        assert_eq!(get(5).unwrap().len(), 61);
+    }
+
+    #[test]
+    fn conditional_missing() {
+        let tree = Tree::new(seq_key(0), vec![0]);
+        let merge: MergeFn = Arc::new(|value| {
+            assert!(value.is_none());
+            vec![5]
+        });
+        let mut batch = vec![
+            (seq_key(1), Op::PutIfAbsent(vec![1])),
+            (seq_key(2), Op::DeleteIfExists),
+            (seq_key(3), Op::CompareAndSwap(vec![3], vec![3])),
+            (seq_key(4), Op::DeleteIfExists),
+            (seq_key(5), Op::Merge(merge))
+        ];
+        let tree = Walker::new(tree, PanicSource {})
+            .apply(&mut batch)
+            .expect("apply errored")
+            .0
+            .expect("should be Some")
+            .into_inner();
+
+        let entries: Vec<_> = tree.iter().collect();
+        assert_eq!(entries, vec![
+            (seq_key(0), vec![0]),
+            (seq_key(1), vec![1]),
+            (seq_key(5), vec![5])
+        ]);
+    }
+
+    #[test]
+    fn build_conditional() {
+        let mut batch = vec![
+            (vec![1], Op::DeleteIfExists),
+            (vec![2], Op::CompareAndSwap(vec![2], vec![2])),
+            (vec![3], Op::DeleteIfExists)
+        ];
+        let (maybe_tree, outcome) = Walker::<PanicSource>::apply_to(None, &mut batch)
+            .expect("apply_to failed");
+        assert!(maybe_tree.is_none());
+        assert_eq!(failures(outcome), vec![ (vec![2], OpFailure::KeyNotFound) ]);
+
+        let mut batch = vec![
+            (vec![1], Op::PutIfAbsent(vec![1])),
+            (vec![2], Op::DeleteIfExists),
+            (vec![3], Op::PutIfAbsent(vec![3]))
+        ];
+        let tree = apply_to_memonly(None, &batch).expect("expected tree");
+        assert_eq!(tree.iter().count(), 2);
+    }
+
+    fn delete_range_memonly(tree: Tree, start: u64, end: u64) -> Option<Tree> {
+        let walker = Walker::new(tree, PanicSource {});
+        Walker::delete_range(Some(walker), &seq_key(start), &seq_key(end))
+            .expect("delete_range failed")
+            .map(|mut tree| {
+                tree.commit(&mut NoopCommit {}).expect("commit failed");
+                assert_tree_invariants(&tree);
+                tree
+            })
+    }
+
+    #[test]
+    fn delete_range() {
+        for &(start, end) in [
+            (0, 100), (10, 20), (0, 50), (50, 100), (1, 99),
+            (37, 38), (90, 200), (20, 20), (30, 10)
+        ].iter() {
+            let tree = apply_to_memonly(None, &make_batch_seq(0..100))
+                .expect("expected tree");
+            let maybe_tree = delete_range_memonly(tree, start, end);
+
+            let expected: Vec<_> = (0..100)
+                .filter(|n| *n < start || *n >= end)
+                .map(seq_key)
+                .collect();
+            let actual: Vec<_> = maybe_tree
+                .map_or(vec![], |tree| tree.iter().map(|(key, _)| key).collect());
+            assert_eq!(actual, expected, "range {}..{}", start, end);
+        }
+    }
+
+    #[derive(Clone)]
+    struct CountingSource {
+        nodes: Arc<BTreeMap<Vec<u8>, Vec<u8>>>,
+        fetches: Arc<AtomicUsize>
+    }
+
+    impl Fetch for CountingSource {
+        fn fetch(&self, link: &Link) -> Result<Tree> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            Tree::decode(link.key(), &self.nodes[link.key()])
+        }
+    }
+
+    struct PruneAll(BTreeMap<Vec<u8>, Vec<u8>>);
+
+    impl Commit for PruneAll {
+        fn write(&mut self, tree: &Tree) -> Result<()> {
+            let mut bytes = vec![];
+            tree.encode_into(&mut bytes);
+            self.0.insert(tree.key().to_vec(), bytes);
+            Ok(())
+        }
+
+        fn prune(&self, _tree: &Tree) -> (bool, bool) {
+            (true, true)
+        }
+    }
+
+    #[test]
+    fn delete_range_pruned() {
+        let tree = apply_to_memonly(None, &make_batch_seq(0..1000))
+            .expect("expected tree");
+        let expected_hash = delete_range_memonly(tree.clone(), 100, 900)
+            .expect("expected tree")
+            .hash();
+
+        let mut tree = tree;
+        let mut committer = PruneAll(BTreeMap::new());
+        // mark every node as modified so they are all written and pruned
+        let mut batch = make_batch_seq(0..1000);
+        let walker = Walker::new(tree, PanicSource {});
+        tree = Walker::apply_to(Some(walker), &mut batch)
+            .expect("apply failed")
+            .0
+            .expect("expected tree");
+        tree.commit(&mut committer).expect("commit failed");
+
+        let source = CountingSource {
+            nodes: Arc::new(committer.0),
+            fetches: Arc::new(AtomicUsize::new(0))
+        };
+        let walker = Walker::new(tree, source.clone());
+        let mut tree = Walker::delete_range(Some(walker), &seq_key(100), &seq_key(900))
+            .expect("delete_range failed")
+            .expect("expected tree");
+        tree.commit(&mut NoopCommit {}).expect("commit failed");
+
+        assert_eq!(tree.hash(), expected_hash);
+        // only nodes along the edges of the range should have been fetched
+        assert!(source.fetches.load(Ordering::SeqCst) < 100);
+    }
+
+    #[test]
+    fn sort_batch_last_write_wins() {
+        let batch = vec![
+            (vec![3], Op::Put(vec![1])),
+            (vec![1], Op::Put(vec![2])),
+            (vec![3], Op::Delete),
+            (vec![2], Op::Put(vec![3])),
+            (vec![1], Op::Put(vec![4]))
+        ];
+        let batch = sort_batch(batch, DuplicateKeys::LastWriteWins)
+            .expect("sort failed");
+        assert_eq!(format!("{:?}", batch), format!("{:?}", vec![
+            (vec![1], Op::Put(vec![4])),
+            (vec![2], Op::Put(vec![3])),
+            (vec![3], Op::Delete)
+        ]));
+    }
+
+    #[test]
+    fn sort_batch_duplicate_error() {
+        let batch = vec![
+            (vec![2], Op::Put(vec![1])),
+            (vec![1], Op::Put(vec![2]))
+        ];
+        let batch = sort_batch(batch, DuplicateKeys::Error).expect("sort failed");
+        assert_eq!(batch[0].0, vec![1]);
+        assert_eq!(batch[1].0, vec![2]);
+
+        let batch = vec![
+            (vec![2], Op::Put(vec![1])),
+            (vec![1], Op::Put(vec![2])),
+            (vec![2], Op::Delete)
+        ];
+        assert!(sort_batch(batch, DuplicateKeys::Error).is_err());
+    }
 }
@@ -122,6 +122,13 @@ impl<S> Walker<S>
         self
     }
 
+    /// Removes the child on the given side (if any) without fetching it,
+    /// dropping the entire subtree.
+    pub fn drop_child(mut self, left: bool) -> Self {
+        self.tree.own(|t| t.detach(left).0);
+        self
+    }
+
     /// Similar to `Tree#with_value`.
     pub fn with_value(mut self, value: Vec<u8>) -> Self {
         self.tree.own(|t| t.with_value(value));
@@ -204,6 +211,8 @@ mod test {
             Default::default(),
             Some(Link::Pruned {
                 hash: Default::default(),
+                sum: None,
+                count: None,
                 key: b"foo".to_vec(),
                 child_heights: (0, 0)
             }),
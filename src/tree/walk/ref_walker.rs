@@ -1,52 +1,107 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use crate::error::Result;
 use super::Fetch;
 use super::super::{Tree, Link};
 
 /// Allows read-only traversal of a `Tree`, fetching from the given source when
-/// traversing to a pruned node. The fetched nodes are then retained in memory
-/// until they (possibly) get pruned on the next commit.
+/// traversing to a pruned node. The tree is never modified, so fetched nodes
+/// are not retained in memory - they are only owned by the walker which
+/// traverses them, and are dropped along with it. This means many walkers can
+/// traverse the same tree at once.
 ///
 /// Only finalized trees may be walked (trees which have had `commit` called
 /// since the last update).
 pub struct RefWalker<'a, S>
-    where S: Fetch + Sized + Clone + Send
+    where S: Fetch + Sized + Clone
 {
-    tree: &'a mut Tree,
+    tree: Cow<'a, Tree>,
     source: S
 }
 
 impl<'a, S> RefWalker<'a, S>
-    where S: Fetch + Sized + Clone + Send
+    where S: Fetch + Sized + Clone
 {
     /// Creates a `RefWalker` with the given tree and source.
-    pub fn new(tree: &'a mut Tree, source: S) -> Self {
+    pub fn new(tree: &'a Tree, source: S) -> Self {
         // TODO: check if tree has modified links, panic if so
-        RefWalker { tree, source }
+        RefWalker { tree: Cow::Borrowed(tree), source }
     }
 
     /// Gets an immutable reference to the `Tree` wrapped by this `RefWalker`.
     pub fn tree(&self) -> &Tree {
-        self.tree
+        &self.tree
     }
 
     /// Traverses to the child on the given side (if any), fetching from the
-    /// source if pruned. When fetching, the link is upgraded from
-    /// `Link::Pruned` to `Link::Stored`.
-    pub fn walk<'b>(&'b mut self, left: bool) -> Result<Option<RefWalker<'b, S>>> {
+    /// source if pruned. A fetched child is owned by the returned walker rather
+    /// than being attached to the tree.
+    pub fn walk(&self, left: bool) -> Result<Option<RefWalker<'_, S>>> {
         let link = match self.tree.link(left) {
             None => return Ok(None),
             Some(link) => link
         };
 
-        match link {
+        let child = match link {
             Link::Modified { .. } => panic!("Cannot traverse Link::Modified"),
-            Link::Stored { .. } => {},
+            Link::Stored { tree, .. } => Cow::Borrowed(tree),
             Link::Pruned { .. } => {
-                self.tree.load(left, &self.source)?;
+                let tree = self.source.fetch(link)?;
+                debug_assert_eq!(tree.key(), link.key());
+                Cow::Owned(tree)
+            }
+        };
+
+        Ok(Some(RefWalker { tree: child, source: self.source.clone() }))
+    }
+
+    /// Returns the number of keys in the tree which are less than `key`
+    /// (whether or not `key` is in the tree). Fails if the tree does not
+    /// maintain counts.
+    pub fn rank(&self, key: &[u8]) -> Result<u64> {
+        let left_count = match self.tree().child_count(true) {
+            None => bail!("Tree does not maintain counts"),
+            Some(count) => count
+        };
+
+        match key.cmp(self.tree().key()) {
+            Ordering::Less => match self.walk(true)? {
+                None => Ok(0),
+                Some(child) => child.rank(key)
+            },
+            Ordering::Equal => Ok(left_count),
+            Ordering::Greater => {
+                let right_rank = match self.walk(false)? {
+                    None => 0,
+                    Some(child) => child.rank(key)?
+                };
+                Ok(left_count + 1 + right_rank)
             }
         }
+    }
+
+    /// Returns the key/value pair at the given index in key order, or `None`
+    /// if the index is out of bounds. Fails if the tree does not maintain
+    /// counts.
+    pub fn get_by_index(&self, index: u64) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let left_count = match self.tree().child_count(true) {
+            None => bail!("Tree does not maintain counts"),
+            Some(count) => count
+        };
 
-        let child = self.tree.child_mut(left).unwrap();
-        Ok(Some(RefWalker::new(child, self.source.clone())))
+        match index.cmp(&left_count) {
+            Ordering::Less => match self.walk(true)? {
+                None => Ok(None),
+                Some(child) => child.get_by_index(index)
+            },
+            Ordering::Equal => Ok(Some((
+                self.tree().key().to_vec(),
+                self.tree().value().to_vec()
+            ))),
+            Ordering::Greater => match self.walk(false)? {
+                None => Ok(None),
+                Some(child) => child.get_by_index(index - left_count - 1)
+            }
+        }
     }
 }